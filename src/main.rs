@@ -1,23 +1,31 @@
 use anyhow::Result;
 use env_logger::Env;
 use image::{load_from_memory_with_format, ImageFormat};
-use log::info;
+use log::{error, info};
 use std::{
-    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread::{self, JoinHandle},
     time::Duration,
 };
-use tao::event_loop::{ControlFlow, EventLoopBuilder};
+use tao::{
+    event::Event,
+    event_loop::{ControlFlow, EventLoop, EventLoopBuilder, EventLoopProxy},
+};
 use tray_icon::{
     menu::{AboutMetadata, Menu, MenuEvent, MenuItem, PredefinedMenuItem},
-    TrayIconBuilder, TrayIconEvent,
+    TrayIcon, TrayIconBuilder, TrayIconEvent,
 };
 use wooting_analog_midi_core::{
-    config::{Config, KeyConfig},
-    HIDCodes, MidiService, NoteID, REFRESH_RATE,
+    config::{Config, ConfigBuilder, EnableState},
+    HIDCodes, MidiService, REFRESH_RATE,
 };
 
+/// The first of these found in the working directory is loaded instead of the built-in mapping
+const CONFIG_PATHS: [&str; 2] = ["wooting-analog-midi.toml", "wooting-analog-midi.json"];
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
 struct Service {
     midi: MidiService,
     stop: bool,
@@ -32,10 +40,15 @@ impl Service {
     }
 }
 
-fn spawn_polling_loop(service: &Arc<Mutex<Service>>) -> JoinHandle<Result<()>> {
+/// Polls the service and reports enable state changes to the event loop, so the tray can show them
+fn spawn_polling_loop(
+    service: &Arc<Mutex<Service>>,
+    proxy: EventLoopProxy<EnableState>,
+) -> JoinHandle<Result<()>> {
     let service = service.clone();
     thread::spawn(move || {
         info!("Starting polling loop");
+        let mut enable_state = service.lock().unwrap().midi.enable_state();
 
         let duration = Duration::from_secs_f32(1.0 / REFRESH_RATE);
         let mut interval = spin_sleep_util::interval(duration)
@@ -52,16 +65,58 @@ fn spawn_polling_loop(service: &Arc<Mutex<Service>>) -> JoinHandle<Result<()>> {
                 return Ok(());
             }
             service.midi.poll()?;
+            if service.midi.enable_state() != enable_state {
+                enable_state = service.midi.enable_state();
+                // Only fails once the event loop is gone, while quitting
+                let _ = proxy.send_event(enable_state);
+            }
         }
     })
 }
 
-fn run_event_loop(service: Arc<Mutex<Service>>, handle: JoinHandle<Result<()>>) -> Result<()> {
-    let mut service = Some(service);
+/// Applies the config file again whenever it is saved, edits that fail to parse or validate are
+/// logged and leave the running config in place
+fn spawn_config_watcher(service: &Arc<Mutex<Service>>, path: PathBuf) {
+    let service = service.clone();
+    thread::spawn(move || {
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let mut last_modified = modified(&path);
+        loop {
+            thread::sleep(CONFIG_WATCH_INTERVAL);
+            if service.lock().unwrap().stop {
+                return;
+            }
+            let current = modified(&path);
+            if current.is_none() || current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            let result = Config::load_from_path(&path)
+                .and_then(|config| service.lock().unwrap().midi.set_config(config));
+            match result {
+                Ok(()) => info!("Reloaded config from {}", path.display()),
+                Err(err) => error!("Keeping the previous config: {err:?}"),
+            }
+        }
+    });
+}
 
-    let event_loop = EventLoopBuilder::new().build();
+fn run_event_loop(
+    event_loop: EventLoop<EnableState>,
+    service: Arc<Mutex<Service>>,
+    handle: JoinHandle<Result<()>>,
+) -> Result<()> {
+    let enable_state = service.lock().unwrap().midi.enable_state();
+    let mut service = Some(service);
 
     let tray_menu = Menu::new();
+    let octave_i = MenuItem::new(octave_text(0), false, None);
+    let octave_up_i = MenuItem::new("Octave +", true, None);
+    let octave_down_i = MenuItem::new("Octave −", true, None);
+    let reset_transpose_i = MenuItem::new("Reset transpose", true, None);
+    let panic_i = MenuItem::new("All notes off", true, None);
+    let resend_setup_i = MenuItem::new("Resend channel setup", true, None);
     let quit_i = MenuItem::new("Quit", true, None);
     tray_menu
         .append_items(&[
@@ -74,16 +129,23 @@ fn run_event_loop(service: Arc<Mutex<Service>>, handle: JoinHandle<Result<()>>)
                 }),
             ),
             &PredefinedMenuItem::separator(),
+            &octave_i,
+            &octave_up_i,
+            &octave_down_i,
+            &reset_transpose_i,
+            &PredefinedMenuItem::separator(),
+            &panic_i,
+            &resend_setup_i,
+            &PredefinedMenuItem::separator(),
             &quit_i,
         ])
         .expect("Failed to add item to tray menu");
 
-    let icon = load_icon();
     let mut tray_icon = Some(
         TrayIconBuilder::new()
             .with_menu(Box::new(tray_menu))
-            .with_tooltip("wooing-analog-midi")
-            .with_icon(icon)
+            .with_tooltip(tooltip_text(enable_state))
+            .with_icon(load_icon(enable_state))
             .build()
             .unwrap(),
     );
@@ -92,12 +154,44 @@ fn run_event_loop(service: Arc<Mutex<Service>>, handle: JoinHandle<Result<()>>)
     let menu_channel = MenuEvent::receiver();
 
     let mut handle = Some(handle);
-    event_loop.run(move |_event, _, control_flow| {
+    event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
 
+        if let (Event::UserEvent(enable_state), Some(tray_icon)) = (event, &tray_icon) {
+            show_enable_state(tray_icon, enable_state);
+        }
+
         if let Ok(event) = menu_channel.try_recv() {
             println!("{event:?}");
-            if event.id == quit_i.id() {
+            if event.id == octave_up_i.id()
+                || event.id == octave_down_i.id()
+                || event.id == reset_transpose_i.id()
+            {
+                if let Some(service) = &service {
+                    let midi = &mut service.lock().unwrap().midi;
+                    let transpose = if event.id == octave_up_i.id() {
+                        midi.transpose().saturating_add(12)
+                    } else if event.id == octave_down_i.id() {
+                        midi.transpose().saturating_sub(12)
+                    } else {
+                        0
+                    };
+                    midi.set_transpose(transpose);
+                    octave_i.set_text(octave_text(midi.transpose()));
+                }
+            } else if event.id == panic_i.id() {
+                if let Some(service) = &service {
+                    if let Err(err) = service.lock().unwrap().midi.all_notes_off() {
+                        error!("Failed to send all notes off: {err:?}");
+                    }
+                }
+            } else if event.id == resend_setup_i.id() {
+                if let Some(service) = &service {
+                    if let Err(err) = service.lock().unwrap().midi.send_channel_setup() {
+                        error!("Failed to resend channel setup: {err:?}");
+                    }
+                }
+            } else if event.id == quit_i.id() {
                 tray_icon.take();
                 service.take().unwrap().lock().unwrap().stop = true;
                 handle.take().unwrap().join().unwrap().unwrap();
@@ -111,67 +205,104 @@ fn run_event_loop(service: Arc<Mutex<Service>>, handle: JoinHandle<Result<()>>)
 fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
+    let config_path = CONFIG_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists());
     let service = Arc::new(Mutex::new(Service::new()));
     {
         let mut service = service.lock().unwrap();
         service.midi.init()?;
         service.midi.select_port(0)?;
         // info!("Ports: {:#?}", service.midi.port_options);
-        let config = create_config();
+        let config = match &config_path {
+            Some(path) => {
+                info!("Loading config from {}", path.display());
+                Config::load_from_path(path)?
+            }
+            None => create_config()?,
+        };
         service.midi.set_config(config)?;
     }
 
-    let handle = spawn_polling_loop(&service);
+    let event_loop = EventLoopBuilder::with_user_event().build();
+    let handle = spawn_polling_loop(&service, event_loop.create_proxy());
+    if let Some(path) = config_path {
+        spawn_config_watcher(&service, path);
+    }
 
-    run_event_loop(service, handle)
+    run_event_loop(event_loop, service, handle)
 }
 
-fn create_config() -> Config {
-    let mut key_configs = HashMap::default();
-    for (index, code) in [
-        HIDCodes::Q,
-        HIDCodes::N2,
-        HIDCodes::W,
-        HIDCodes::E,
-        HIDCodes::R,
-        HIDCodes::N5,
-        HIDCodes::T,
-        HIDCodes::N6,
-        HIDCodes::T,
-        HIDCodes::N7,
-        HIDCodes::U,
-        HIDCodes::I,
-        HIDCodes::N9,
-        HIDCodes::O,
-        HIDCodes::N0,
-        HIDCodes::P,
-    ]
-    .into_iter()
-    .enumerate()
-    {
-        key_configs.insert(
-            code,
-            KeyConfig {
-                note_id: 60 + index as NoteID,
-                ..Default::default()
-            },
-        );
-    }
+fn create_config() -> Result<Config> {
+    ConfigBuilder::new()
+        .chromatic_row(
+            &[
+                HIDCodes::Q,
+                HIDCodes::N2,
+                HIDCodes::W,
+                HIDCodes::E,
+                HIDCodes::R,
+                HIDCodes::N5,
+                HIDCodes::T,
+                HIDCodes::N6,
+                HIDCodes::T,
+                HIDCodes::N7,
+                HIDCodes::U,
+                HIDCodes::I,
+                HIDCodes::N9,
+                HIDCodes::O,
+                HIDCodes::N0,
+                HIDCodes::P,
+            ],
+            60,
+        )
+        .toggle_key(HIDCodes::F12)
+        .build()
+}
 
-    return Config {
-        key_configs,
-        toggle_keys: vec![HIDCodes::F12],
-        ..Default::default()
+fn octave_text(transpose: i8) -> String {
+    format!("Octave: {:+}", transpose / 12)
+}
+
+fn tooltip_text(enable_state: EnableState) -> String {
+    let state = match enable_state {
+        EnableState::Off => "off",
+        EnableState::Partial => "partially on",
+        EnableState::Full => "on",
     };
+    format!("wooting-analog-midi: {}", state)
 }
 
-fn load_icon() -> tray_icon::Icon {
+fn show_enable_state(tray_icon: &TrayIcon, enable_state: EnableState) {
+    if let Err(err) = tray_icon.set_tooltip(Some(tooltip_text(enable_state))) {
+        error!("Failed to update tray tooltip: {err:?}");
+    }
+    if let Err(err) = tray_icon.set_icon(Some(load_icon(enable_state))) {
+        error!("Failed to update tray icon: {err:?}");
+    }
+}
+
+/// The icon in color while fully on, in gray while partially on and faded out while off
+fn load_icon(enable_state: EnableState) -> tray_icon::Icon {
     let bytes = include_bytes!("icon.png");
 
     let (icon_rgba, icon_width, icon_height) = {
-        let image = load_from_memory_with_format(bytes, ImageFormat::Png)
+        let mut image = load_from_memory_with_format(bytes, ImageFormat::Png)
             .expect("Failed to load icon image")
             .into_rgba8();
+        if enable_state != EnableState::Full {
+            for pixel in image.pixels_mut() {
+                let [r, g, b, a] = pixel.0;
+                let gray = ((r as u16 * 77 + g as u16 * 150 + b as u16 * 29) >> 8) as u8;
+                let alpha = if enable_state == EnableState::Off {
+                    a / 2
+                } else {
+                    a
+                };
+                pixel.0 = [gray, gray, gray, alpha];
+            }
+        }
         let (width, height) = image.dimensions();
         let rgba = image.into_raw();
         (rgba, width, height)
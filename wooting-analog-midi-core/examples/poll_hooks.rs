@@ -0,0 +1,58 @@
+//! Publishes a snapshot of every tick from the post-poll hook, so a UI thread can always read the
+//! freshest state without ever blocking the polling loop.
+
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use wooting_analog_midi_core::config::EnableState;
+use wooting_analog_midi_core::{MidiService, REFRESH_RATE};
+
+#[derive(Clone)]
+struct Snapshot {
+    tick: u64,
+    enable_state: EnableState,
+    events_emitted: usize,
+}
+
+fn main() -> Result<()> {
+    let mut service = MidiService::new();
+    service.init()?;
+
+    let latest = Arc::new(Mutex::new(Snapshot {
+        tick: 0,
+        enable_state: EnableState::Off,
+        events_emitted: 0,
+    }));
+    let published = latest.clone();
+    service.set_post_poll_hook(Box::new(move |context| {
+        let snapshot = Snapshot {
+            tick: context.tick,
+            enable_state: context.enable_state,
+            events_emitted: context.events_emitted,
+        };
+        // While the UI is reading, this tick is skipped instead of waiting for it
+        if let Ok(mut latest) = published.try_lock() {
+            *latest = snapshot;
+        }
+    }));
+
+    let ui = thread::spawn(move || {
+        for _ in 0..20 {
+            // Cloned out so the lock is held as briefly as possible
+            let snapshot = latest.lock().unwrap().clone();
+            println!(
+                "Tick {}: {:?}, {} events",
+                snapshot.tick, snapshot.enable_state, snapshot.events_emitted
+            );
+            thread::sleep(Duration::from_millis(250));
+        }
+    });
+
+    let interval = Duration::from_secs_f32(1.0 / REFRESH_RATE);
+    while !ui.is_finished() {
+        service.poll()?;
+        thread::sleep(interval);
+    }
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use crate::config::{ArpMode, Config};
+use crate::note::NoteSink;
+use crate::{Channel, NoteID};
+use anyhow::Result;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct HeldNote {
+    note_id: NoteID,
+    velocity: f32,
+    channel: Channel,
+    high_resolution: bool,
+}
+
+#[derive(Debug)]
+struct SoundingNote {
+    note: HeldNote,
+    off_at: Instant,
+}
+
+/// Plays the held notes one after another instead of together
+#[derive(Debug, Default)]
+pub(crate) struct Arpeggiator {
+    // Ordered from oldest to newest
+    held: Vec<HeldNote>,
+    sounding: Option<SoundingNote>,
+    next_step: Option<Instant>,
+    step: usize,
+    rng: Option<SmallRng>,
+}
+
+impl Arpeggiator {
+    fn update(&mut self, config: &Config, now: Instant, sink: &mut impl NoteSink) -> Result<()> {
+        let Some(arp) = &config.arpeggiator else {
+            return Ok(());
+        };
+        if self
+            .sounding
+            .as_ref()
+            .is_some_and(|sounding| now >= sounding.off_at)
+        {
+            self.end_note(sink)?;
+        }
+        if self.held.is_empty() {
+            self.next_step = None;
+            self.step = 0;
+            return Ok(());
+        }
+
+        let due = *self.next_step.get_or_insert(now);
+        if now < due {
+            return Ok(());
+        }
+        self.end_note(sink)?;
+        let note = self.pick(arp.mode);
+        if note.high_resolution {
+            sink.note_on_high_resolution(note.note_id, note.velocity, note.channel)?;
+        } else {
+            sink.note_on(note.note_id, note.velocity, note.channel)?;
+        }
+
+        let bpm = arp.bpm.unwrap_or_else(|| config.tempo());
+        let interval = Duration::from_secs_f64(60.0 / (bpm as f64 * arp.steps_per_beat as f64));
+        self.sounding = Some(SoundingNote {
+            note,
+            off_at: now + interval.mul_f32(arp.gate.clamp(0.0, 1.0)),
+        });
+        self.step += 1;
+        // Steps follow absolute deadlines unless polling fell more than a step behind
+        self.next_step = Some(if due + interval > now {
+            due + interval
+        } else {
+            now + interval
+        });
+        Ok(())
+    }
+
+    fn pick(&mut self, mode: ArpMode) -> HeldNote {
+        let mut notes = self.held.clone();
+        notes.sort_by_key(|note| (note.note_id, note.channel));
+        let len = notes.len();
+        let index = match mode {
+            ArpMode::Up => self.step % len,
+            ArpMode::Down => len - 1 - self.step % len,
+            ArpMode::UpDown => {
+                // The top and bottom notes are not repeated at the turns
+                let period = (2 * len).saturating_sub(2).max(1);
+                let position = self.step % period;
+                if position < len {
+                    position
+                } else {
+                    period - position
+                }
+            }
+            ArpMode::Random => self
+                .rng
+                .get_or_insert_with(SmallRng::from_entropy)
+                .gen_range(0..len),
+        };
+        notes[index]
+    }
+
+    fn end_note(&mut self, sink: &mut impl NoteSink) -> Result<()> {
+        if let Some(SoundingNote { note, .. }) = self.sounding.take() {
+            sink.note_off(note.note_id, note.velocity, note.channel)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the sounding note and forgets the held ones
+    pub(crate) fn flush(&mut self, sink: &mut impl NoteSink) -> Result<()> {
+        self.end_note(sink)?;
+        self.clear();
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.held.clear();
+        self.sounding = None;
+        self.next_step = None;
+        self.step = 0;
+    }
+}
+
+/// Collects the notes of the keys while the arpeggiator is enabled instead of sending them,
+/// everything else is forwarded as is
+pub(crate) struct ArpSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    arpeggiator: &'a mut Arpeggiator,
+    config: &'a Config,
+}
+
+impl<'a, S: NoteSink> ArpSink<'a, S> {
+    pub(crate) fn new(
+        inner: &'a mut S,
+        arpeggiator: &'a mut Arpeggiator,
+        config: &'a Config,
+    ) -> Self {
+        Self {
+            inner,
+            arpeggiator,
+            config,
+        }
+    }
+
+    /// Plays the next step once it is due at `now`
+    pub(crate) fn arpeggiate(&mut self, now: Instant) -> Result<()> {
+        self.arpeggiator.update(self.config, now, self.inner)
+    }
+
+    /// Ends the sounding step and forgets the held notes
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.arpeggiator.flush(self.inner)
+    }
+
+    fn hold(&mut self, note: HeldNote) -> Result<()> {
+        if self.config.arpeggiator.is_none() {
+            return if note.high_resolution {
+                self.inner
+                    .note_on_high_resolution(note.note_id, note.velocity, note.channel)
+            } else {
+                self.inner
+                    .note_on(note.note_id, note.velocity, note.channel)
+            };
+        }
+        let held = &mut self.arpeggiator.held;
+        held.retain(|held| (held.note_id, held.channel) != (note.note_id, note.channel));
+        held.push(note);
+        Ok(())
+    }
+}
+
+impl<S: NoteSink> NoteSink for ArpSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.hold(HeldNote {
+            note_id,
+            velocity,
+            channel,
+            high_resolution: false,
+        })
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.hold(HeldNote {
+            note_id,
+            velocity,
+            channel,
+            high_resolution: true,
+        })
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        if self.config.arpeggiator.is_none() {
+            return self.inner.note_off(note_id, velocity, channel);
+        }
+        // Every note on since the arpeggiator was enabled has been collected, so nothing was sent
+        self.arpeggiator
+            .held
+            .retain(|held| (held.note_id, held.channel) != (note_id, channel));
+        Ok(())
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        if self.config.arpeggiator.is_some() {
+            let sounding = self
+                .arpeggiator
+                .sounding
+                .as_ref()
+                .map(|sounding| (sounding.note.note_id, sounding.note.channel));
+            if sounding != Some((note_id, channel)) {
+                return Ok(());
+            }
+        }
+        self.inner.polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.inner.sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ArpeggiatorConfig;
+
+    /// One step per second, sounding for the given portion of it
+    fn arp_config(mode: ArpMode, gate: f32) -> Config {
+        Config {
+            arpeggiator: Some(ArpeggiatorConfig {
+                mode,
+                bpm: Some(60.0),
+                steps_per_beat: 1,
+                gate,
+            }),
+            ..Config::default()
+        }
+    }
+
+    /// Holds the notes on channel 0, then polls at the given milliseconds and returns
+    /// (status, note) pairs
+    fn arpeggiate(config: &Config, notes: &[NoteID], polls_ms: &[u64]) -> Vec<(u8, u8)> {
+        let mut arpeggiator = Arpeggiator::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = ArpSink::new(&mut output, &mut arpeggiator, config);
+        for &note_id in notes {
+            sink.note_on(note_id, 1.0, 0).unwrap();
+        }
+        let start = Instant::now();
+        for &ms in polls_ms {
+            sink.arpeggiate(start + Duration::from_millis(ms)).unwrap();
+        }
+        output
+            .iter()
+            .map(|message| (message[0], message[1]))
+            .collect()
+    }
+
+    fn steps(mode: ArpMode, count: u64) -> Vec<NoteID> {
+        let polls: Vec<u64> = (0..count).map(|step| step * 1000).collect();
+        arpeggiate(&arp_config(mode, 0.5), &[64, 60, 67], &polls)
+            .into_iter()
+            .filter(|&(status, _)| status == 0x90)
+            .map(|(_, note)| note)
+            .collect()
+    }
+
+    #[test]
+    fn steps_follow_the_mode() {
+        assert_eq!(steps(ArpMode::Up, 4), [60, 64, 67, 60]);
+        assert_eq!(steps(ArpMode::Down, 4), [67, 64, 60, 67]);
+        assert_eq!(steps(ArpMode::UpDown, 6), [60, 64, 67, 64, 60, 64]);
+    }
+
+    #[test]
+    fn notes_sound_for_the_gate() {
+        let config = arp_config(ArpMode::Up, 0.25);
+        assert_eq!(
+            arpeggiate(&config, &[60, 64], &[0, 200, 250, 900, 1000]),
+            [(0x90, 60), (0x80, 60), (0x90, 64)]
+        );
+        // A full gate ends each note right before the next one starts
+        let config = arp_config(ArpMode::Up, 1.0);
+        assert_eq!(
+            arpeggiate(&config, &[60, 64], &[0, 999, 1000]),
+            [(0x90, 60), (0x80, 60), (0x90, 64)]
+        );
+    }
+
+    #[test]
+    fn released_notes_leave_the_pattern() {
+        let config = arp_config(ArpMode::Up, 0.5);
+        let mut arpeggiator = Arpeggiator::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = ArpSink::new(&mut output, &mut arpeggiator, &config);
+        sink.note_on(60, 1.0, 0).unwrap();
+        sink.note_on(64, 1.0, 0).unwrap();
+        let start = Instant::now();
+        sink.arpeggiate(start).unwrap();
+        sink.note_off(64, 0.0, 0).unwrap();
+        sink.arpeggiate(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            output,
+            [
+                vec![0x90, 60, 127],
+                vec![0x80, 60, 127],
+                vec![0x90, 60, 127]
+            ]
+        );
+    }
+
+    #[test]
+    fn flushing_ends_the_sounding_note() {
+        let config = arp_config(ArpMode::Up, 0.5);
+        let mut arpeggiator = Arpeggiator::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = ArpSink::new(&mut output, &mut arpeggiator, &config);
+        sink.note_on(60, 1.0, 0).unwrap();
+        let start = Instant::now();
+        sink.arpeggiate(start).unwrap();
+        sink.flush().unwrap();
+        // Nothing is held anymore, so the pattern does not continue
+        sink.arpeggiate(start + Duration::from_secs(1)).unwrap();
+        assert_eq!(output, [vec![0x90, 60, 127], vec![0x80, 60, 127]]);
+    }
+
+    #[test]
+    fn without_an_arpeggiator_notes_pass_through() {
+        let mut arpeggiator = Arpeggiator::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let config = Config::default();
+        let mut sink = ArpSink::new(&mut output, &mut arpeggiator, &config);
+        sink.note_on(60, 1.0, 2).unwrap();
+        sink.arpeggiate(Instant::now()).unwrap();
+        sink.note_off(60, 0.0, 2).unwrap();
+        assert_eq!(output, [vec![0x92, 60, 127], vec![0x82, 60, 0]]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::note::{NoteSink, CLOCK_START_MSG, CLOCK_STOP_MSG, CLOCK_TICK_MSG};
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+const PULSES_PER_QUARTER_NOTE: f64 = 24.0;
+// Ticks sent at once after a stall before the clock gives up and resynchronizes
+const CATCH_UP_MAX: u32 = 4;
+
+/// MIDI clock driven from the polling loop. Ticks are scheduled against absolute deadlines, so
+/// polling jitter never accumulates into tempo drift.
+#[derive(Debug)]
+pub(crate) struct MidiClock {
+    interval: Duration,
+    next_tick: Instant,
+}
+
+impl MidiClock {
+    /// Sends Start, the first tick is due at `now`
+    pub(crate) fn start(bpm: f32, now: Instant, sink: &mut impl NoteSink) -> Result<Self> {
+        sink.system_realtime(CLOCK_START_MSG)?;
+        let interval = interval(bpm);
+        Ok(Self {
+            interval,
+            next_tick: now,
+        })
+    }
+
+    pub(crate) fn stop(self, sink: &mut impl NoteSink) -> Result<()> {
+        sink.system_realtime(CLOCK_STOP_MSG)
+    }
+
+    pub(crate) fn set_bpm(&mut self, bpm: f32) {
+        self.interval = interval(bpm);
+    }
+
+    /// Sends the ticks due by `now`
+    pub(crate) fn update(&mut self, now: Instant, sink: &mut impl NoteSink) -> Result<()> {
+        let mut sent = 0;
+        while self.next_tick <= now {
+            sink.system_realtime(CLOCK_TICK_MSG)?;
+            self.next_tick += self.interval;
+            sent += 1;
+            if sent >= CATCH_UP_MAX {
+                self.next_tick = now + self.interval;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn interval(bpm: f32) -> Duration {
+    Duration::from_secs_f64(60.0 / (bpm as f64 * PULSES_PER_QUARTER_NOTE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Polls a 125 BPM clock, whose ticks are exactly 20ms apart, at the given milliseconds
+    /// after it started and returns the ticks sent by each poll
+    fn ticks(polls_ms: &[u64]) -> Vec<usize> {
+        let start = Instant::now();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut clock = MidiClock::start(125.0, start, &mut output).unwrap();
+        assert_eq!(output, [[CLOCK_START_MSG]]);
+        polls_ms
+            .iter()
+            .map(|&ms| {
+                output.clear();
+                clock
+                    .update(start + Duration::from_millis(ms), &mut output)
+                    .unwrap();
+                assert!(output.iter().all(|message| message == &[CLOCK_TICK_MSG]));
+                output.len()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ticks_follow_the_interval() {
+        assert_eq!(interval(125.0), Duration::from_millis(20));
+        assert_eq!(ticks(&[0, 10, 19, 20, 39, 41]), [1, 0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn polling_jitter_does_not_drift() {
+        // A second of polls at 1-9ms apart, landing anywhere relative to the ticks
+        let mut polls = Vec::new();
+        let mut ms = 0;
+        for step in 0.. {
+            ms += 1 + step * 7 % 9;
+            if ms > 1000 {
+                break;
+            }
+            polls.push(ms);
+        }
+        let sent: usize = ticks(&polls).iter().sum();
+        // Ticks at 0, 20, .. 1000ms, the last poll decides whether 1000ms was reached
+        let expected = *polls.last().unwrap() as usize / 20 + 1;
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn a_stall_catches_up_a_few_ticks_then_resynchronizes() {
+        // 200ms late would be 11 ticks, only 4 are sent and the grid restarts from the stall
+        assert_eq!(ticks(&[0, 200, 210, 220, 240]), [1, 4, 0, 1, 1]);
+    }
+
+    #[test]
+    fn tempo_changes_take_effect_from_the_next_tick() {
+        let start = Instant::now();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut clock = MidiClock::start(125.0, start, &mut output).unwrap();
+        clock.update(start, &mut output).unwrap();
+        clock.set_bpm(62.5);
+        output.clear();
+        for ms in [20, 40, 60] {
+            clock
+                .update(start + Duration::from_millis(ms), &mut output)
+                .unwrap();
+        }
+        // The tick at 20ms was already scheduled, the next one follows 40ms later
+        assert_eq!(output, [[CLOCK_TICK_MSG], [CLOCK_TICK_MSG]]);
+    }
+
+    #[test]
+    fn stopping_sends_stop() {
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let clock = MidiClock::start(120.0, Instant::now(), &mut output).unwrap();
+        clock.stop(&mut output).unwrap();
+        assert_eq!(output, [[CLOCK_START_MSG], [CLOCK_STOP_MSG]]);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::note::{CLOCK_CONTINUE_MSG, CLOCK_START_MSG, CLOCK_STOP_MSG, CLOCK_TICK_MSG};
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MIDI_INPUT_CLIENT_NAME: &str = "Wooting Analog MIDI Clock Input";
+const MIDI_INPUT_PORT_NAME: &str = "wooting-analog-midi-clock";
+// Weight of the newest tick interval in the tempo estimate, evens out USB jitter
+const INTERVAL_SMOOTHING: f32 = 0.1;
+// Missed ticks after which the clock counts as dropped, e.g. an unplugged cable without a Stop
+const TIMEOUT_TICKS: u32 = 12;
+
+#[derive(Debug, Default)]
+struct ClockState {
+    running: bool,
+    // Index of the last tick since Start, the first tick after Start is the downbeat
+    position: Option<u64>,
+    last_tick: Option<Instant>,
+    interval: Option<Duration>,
+}
+
+impl ClockState {
+    fn receive(&mut self, message: &[u8], now: Instant) {
+        match message.first() {
+            Some(&CLOCK_TICK_MSG) => {
+                if let Some(last_tick) = self.last_tick {
+                    let elapsed = now - last_tick;
+                    self.interval = Some(match self.interval {
+                        Some(interval) => {
+                            interval.mul_f32(1.0 - INTERVAL_SMOOTHING)
+                                + elapsed.mul_f32(INTERVAL_SMOOTHING)
+                        }
+                        None => elapsed,
+                    });
+                }
+                self.last_tick = Some(now);
+                self.position = Some(self.position.map_or(0, |position| position + 1));
+            }
+            Some(&CLOCK_START_MSG) => {
+                self.running = true;
+                self.position = None;
+            }
+            Some(&CLOCK_CONTINUE_MSG) => self.running = true,
+            Some(&CLOCK_STOP_MSG) => self.running = false,
+            _ => {}
+        }
+    }
+
+    fn next_grid_point(&self, grid_ticks: u8, now: Instant) -> Option<Instant> {
+        if !self.running {
+            return None;
+        }
+        let grid_ticks = grid_ticks.max(1) as u64;
+        let (position, last_tick, interval) = (self.position?, self.last_tick?, self.interval?);
+        let since_tick = now.saturating_duration_since(last_tick);
+        if since_tick > interval * TIMEOUT_TICKS {
+            return None;
+        }
+
+        let phase = position % grid_ticks;
+        // A press landing just after a grid tick still counts as on the grid
+        if phase == 0 && since_tick < interval / 2 {
+            return Some(last_tick);
+        }
+        Some(last_tick + interval * (grid_ticks - phase) as u32)
+    }
+}
+
+/// Follows the MIDI clock of an input port, e.g. a drum machine or DAW
+pub(crate) struct ClockInput {
+    port_name: String,
+    state: Arc<Mutex<ClockState>>,
+    _connection: MidiInputConnection<()>,
+}
+
+impl ClockInput {
+    /// Connects to the first input port whose name contains `port_name`
+    pub(crate) fn connect(port_name: &str) -> Result<Self> {
+        let mut midi_input = MidiInput::new(MIDI_INPUT_CLIENT_NAME)?;
+        midi_input.ignore(Ignore::SysexAndActiveSense);
+        let port = midi_input.ports().into_iter().find(|port| {
+            midi_input
+                .port_name(port)
+                .is_ok_and(|name| name.contains(port_name))
+        });
+        let Some(port) = port else {
+            bail!("No MIDI input port matches \"{}\"", port_name);
+        };
+        info!("Following the MIDI clock of \"{}\"", port_name);
+
+        let state = Arc::new(Mutex::new(ClockState::default()));
+        let callback_state = state.clone();
+        let connection = midi_input
+            .connect(
+                &port,
+                MIDI_INPUT_PORT_NAME,
+                move |_, message, _| {
+                    if let Ok(mut state) = callback_state.lock() {
+                        state.receive(message, Instant::now());
+                    }
+                },
+                (),
+            )
+            .map_err(|e| anyhow!("Error: {}", e))?;
+        Ok(Self {
+            port_name: port_name.to_owned(),
+            state,
+            _connection: connection,
+        })
+    }
+
+    pub(crate) fn port_name(&self) -> &str {
+        &self.port_name
+    }
+
+    /// Time of the next grid point `grid_ticks` apart after `now`, `None` while the clock is
+    /// stopped, has dropped out or its tempo is still unknown
+    pub(crate) fn next_grid_point(&self, grid_ticks: u8, now: Instant) -> Option<Instant> {
+        self.state.lock().ok()?.next_grid_point(grid_ticks, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(20);
+
+    /// A running clock that received Start and then `ticks` ticks 20ms apart from `start`
+    fn running(start: Instant, ticks: u32) -> ClockState {
+        let mut state = ClockState::default();
+        state.receive(&[CLOCK_START_MSG], start);
+        for tick in 0..ticks {
+            state.receive(&[CLOCK_TICK_MSG], start + TICK * tick);
+        }
+        state
+    }
+
+    /// The smoothed interval is off by float rounding, so times only match to the microsecond
+    fn assert_near(actual: Option<Duration>, expected: Duration) {
+        let actual = actual.unwrap();
+        assert!(
+            actual.abs_diff(expected) < Duration::from_micros(1),
+            "{:?} vs {:?}",
+            actual,
+            expected
+        );
+    }
+
+    fn grid_offset(state: &ClockState, start: Instant, now_ms: u64) -> Option<Duration> {
+        state
+            .next_grid_point(6, start + Duration::from_millis(now_ms))
+            .map(|grid_point| grid_point - start)
+    }
+
+    #[test]
+    fn grid_points_follow_the_clock() {
+        let start = Instant::now();
+        // Ticks 0 to 3, the next sixteenth is tick 6 at 120ms
+        let state = running(start, 4);
+        assert_near(state.interval, TICK);
+        assert_near(grid_offset(&state, start, 65), TICK * 6);
+        // Just after the downbeat a press still counts as on it
+        let state = running(start, 7);
+        assert_near(grid_offset(&state, start, 125), TICK * 6);
+        assert_near(grid_offset(&state, start, 131), TICK * 12);
+    }
+
+    #[test]
+    fn tempo_estimate_evens_out_jitter() {
+        let start = Instant::now();
+        let mut state = running(start, 2);
+        // One late tick only moves the estimate by a tenth of its error
+        state.receive(&[CLOCK_TICK_MSG], start + Duration::from_millis(50));
+        assert_near(state.interval, Duration::from_millis(21));
+        state.receive(&[CLOCK_TICK_MSG], start + Duration::from_millis(60));
+        assert_near(state.interval, Duration::from_micros(19_900));
+    }
+
+    #[test]
+    fn without_a_running_clock_notes_are_not_quantized() {
+        let start = Instant::now();
+        assert_eq!(grid_offset(&ClockState::default(), start, 65), None);
+        // A single tick gives no tempo yet
+        assert_eq!(grid_offset(&running(start, 1), start, 65), None);
+
+        let mut state = running(start, 4);
+        state.receive(&[CLOCK_STOP_MSG], start + Duration::from_millis(61));
+        assert_eq!(grid_offset(&state, start, 65), None);
+        // Continue resumes the position, Start restarts it at the downbeat
+        state.receive(&[CLOCK_CONTINUE_MSG], start + Duration::from_millis(62));
+        assert_near(grid_offset(&state, start, 65), TICK * 6);
+        state.receive(&[CLOCK_START_MSG], start + Duration::from_millis(63));
+        assert_eq!(state.position, None);
+    }
+
+    #[test]
+    fn a_silent_clock_counts_as_dropped() {
+        let start = Instant::now();
+        let state = running(start, 4);
+        let last_tick = start + TICK * 3;
+        let silent_for = |ticks: u32| state.next_grid_point(6, last_tick + TICK * ticks);
+        assert!(silent_for(TIMEOUT_TICKS - 1).is_some());
+        assert_eq!(silent_for(TIMEOUT_TICKS + 1), None);
+    }
+}
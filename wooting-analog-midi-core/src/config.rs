@@ -1,17 +1,110 @@
 use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use wooting_analog_wrapper::HIDCodes;
 
-use crate::{Channel, NoteID};
+use crate::config_file::{channel_map, key_list, key_map, note, note_and_depth, note_list};
+use crate::note::{quantize_velocity, MIDI_NOTE_MAX, MIDI_NOTE_MIN};
+use crate::notenames::MiddleC;
+use crate::tuning::Tuning;
 
-#[derive(Debug, Clone)]
+pub use crate::config_builder::{ConfigBuilder, KeyConfigBuilder};
+pub use crate::config_file::ConfigFormat;
+pub use crate::layouts::{
+    chromatic_strip, isomorphic_layout, piano_layout, IsomorphicLayout, KeyRow,
+};
+pub use crate::validation::ConfigError;
+use crate::{mpe, Channel, NoteID};
+
+/// Version of the config semantics, bumped whenever the meaning of an existing field changes
+pub const CONFIG_VERSION: u32 = 2;
+
+const DEFAULT_BPM: f32 = 120.0;
+const DEFAULT_RELEASE_HYSTERESIS: f32 = 0.05;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct KeyConfig {
+    /// Written as a number or a name like "C#4" in config files, see `notenames::parse`
+    #[serde(with = "note")]
     pub note_id: NoteID,
     pub channel: Channel,
+    /// Readings of the switch at rest and fully pressed, rescaled to 0.0 and 1.0 before
+    /// anything else looks at them
+    pub calibration_min: f32,
+    pub calibration_max: f32,
     pub actuation_point: f32,
     pub threshold: f32,
+    /// Depth a sounding note is released below, keeps sensor noise around `threshold` from
+    /// retriggering it. Defaults to slightly below the threshold.
+    pub release_threshold: Option<f32>,
+    /// Additional note and the deeper threshold it sounds past, shifted like the base note
+    #[serde(with = "note_and_depth")]
+    pub second_note: Option<(NoteID, f32)>,
+    /// Press speed in full key travels per second that maps to maximum velocity
     pub velocity_scale: f32,
+    /// Multiplies the velocity after `velocity_curve`, see `MidiService::start_velocity_calibration`
+    pub velocity_gain: f32,
+    pub velocity_estimation: VelocityEstimation,
+    /// Shapes the measured velocity before it becomes a note on, linear without it
+    pub velocity_curve: Option<VelocityCurve>,
+    /// Release speed in full key travels per second that maps to maximum note off velocity,
+    /// without it the note off repeats the press velocity
+    pub release_velocity_scale: Option<f32>,
+    /// Sends exactly this velocity on every note off, ahead of `release_velocity_scale`
+    pub fixed_release_velocity: Option<u8>,
+    /// Sends pressure while the key is held past its threshold
     pub aftertouch: bool,
+    /// Time constant of the low-pass filter applied to aftertouch, 0 disables it
+    pub aftertouch_smoothing_ms: f32,
+    pub aftertouch_response: AftertouchResponse,
+    /// Maps the travel between threshold and bottom out onto the full aftertouch range, without
+    /// it aftertouch is the raw key depth
+    pub aftertouch_curve: Option<AftertouchCurve>,
+    /// Continuously reports the depth past the actuation point, even before the note triggers
+    pub pre_touch: Option<PreTouch>,
     pub shift_amount: i8,
+    /// Moves a held note to its new pitch when the shift or transpose changes, instead of
+    /// keeping it until the key is released. Keys with a note pool keep their note.
+    pub retrigger_on_shift: bool,
+    pub note_pool: Option<NotePool>,
+    /// Semitone offsets from the base note that sound along with it as a chord
+    pub extra_notes: Vec<i8>,
+    pub strum: Option<StrumConfig>,
+    /// Snaps measured note on and note off velocities to the centers of this many equal layers,
+    /// as the last step before they are sent
+    pub velocity_steps: Option<u8>,
+    pub timing_offset_ms: i8,
+    pub velocity_trim: i8,
+    /// Sends exactly this velocity on every note on and skips measuring the press
+    pub fixed_velocity: Option<u8>,
+    /// Note on velocities the measured 0.0 to 1.0 velocity is rescaled into
+    pub velocity_min: u8,
+    pub velocity_max: u8,
+    /// Random variation of each note on velocity as a fraction, 0.1 varies it by up to 10%
+    pub humanize_velocity: f32,
+    /// Makes the variation reproducible, independently of the seed of `note_pool`
+    pub humanize_seed: Option<u64>,
+    pub early_release: Option<EarlyReleaseConfig>,
+    pub rapid_trigger: Option<RapidTriggerConfig>,
+    pub soft_hold: Option<SoftHoldConfig>,
+    pub note_repeat: Option<NoteRepeatConfig>,
+    pub vibrato: Option<VibratoConfig>,
+    /// Presses slower than this velocity do not trigger a note
+    pub min_trigger_velocity: Option<f32>,
+    /// Lets a gated press still trigger once it speeds up past `min_trigger_velocity`
+    pub retry_within_press: bool,
+    /// Holds back the note on until the key has stayed past the threshold this long, presses
+    /// that retreat earlier stay silent. Delays every note by as much.
+    pub min_press_ms: u16,
+    /// Depth at which a held back note on starts right away
+    pub min_press_depth: Option<f32>,
+    /// Presses this soon after a release stay silent, filters switch bounce
+    pub min_retrigger_ms: u16,
+    /// Holds off a note off until the note has sounded this long
+    pub min_note_ms: u16,
+    /// Toggles the note with each press, the note keeps sounding while the key is at rest
+    pub latch: bool,
 }
 
 impl Default for KeyConfig {
@@ -19,28 +112,1007 @@ impl Default for KeyConfig {
         Self {
             note_id: 60, // Middle C
             channel: 0,
+            calibration_min: 0.0,
+            calibration_max: 1.0,
             actuation_point: 0.0,
             threshold: 0.8,
-            velocity_scale: 5.0,
+            release_threshold: None,
+            second_note: None,
+            velocity_scale: 20.0,
+            velocity_gain: 1.0,
+            velocity_estimation: VelocityEstimation::default(),
+            velocity_curve: None,
+            release_velocity_scale: None,
+            fixed_release_velocity: None,
             aftertouch: true,
+            aftertouch_smoothing_ms: 0.0,
+            aftertouch_response: AftertouchResponse::default(),
+            aftertouch_curve: None,
+            pre_touch: None,
             shift_amount: 12,
+            retrigger_on_shift: false,
+            note_pool: None,
+            extra_notes: vec![],
+            strum: None,
+            velocity_steps: None,
+            timing_offset_ms: 0,
+            velocity_trim: 0,
+            fixed_velocity: None,
+            velocity_min: 0,
+            velocity_max: 127,
+            humanize_velocity: 0.0,
+            humanize_seed: None,
+            early_release: None,
+            rapid_trigger: None,
+            soft_hold: None,
+            note_repeat: None,
+            vibrato: None,
+            min_trigger_velocity: None,
+            retry_within_press: false,
+            min_press_ms: 0,
+            min_press_depth: None,
+            min_retrigger_ms: 0,
+            min_note_ms: 0,
+            latch: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PreTouch {
+    /// Sent for the sounding note, or the key's base note while nothing sounds yet. Replaces the
+    /// regular aftertouch of the key.
+    PolyAftertouch,
+    /// Sent as the given controller
+    ControlChange(u8),
+}
+
+const AFTERTOUCH_CURVE_STEEPNESS: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AftertouchCurve {
+    Linear,
+    /// Gentle at first, most of the range is in the deepest part of the travel
+    Exponential,
+    /// Inverse of `Exponential`, rises quickly right after the threshold
+    Logarithmic,
+    /// Raises the position to the given exponent
+    Gamma(f32),
+}
+
+impl AftertouchCurve {
+    /// Shapes a position in 0.0..=1.0
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        let k = AFTERTOUCH_CURVE_STEEPNESS;
+        match *self {
+            AftertouchCurve::Linear => x,
+            AftertouchCurve::Exponential => ((k * x).exp() - 1.0) / (k.exp() - 1.0),
+            AftertouchCurve::Logarithmic => (1.0 + (k.exp() - 1.0) * x).ln() / k,
+            AftertouchCurve::Gamma(gamma) => x.powf(gamma.max(0.0)),
+        }
+    }
+}
+
+/// Velocity curves, the parameter is the steepness and 0.0 is linear
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum VelocityCurve {
+    Linear,
+    /// Concave, it takes a hard press to get loud
+    Exponential(f32),
+    /// Convex, soft presses already come out fairly loud
+    Logarithmic(f32),
+    /// Flattens both ends so most presses land in the middle
+    SCurve(f32),
+}
+
+impl VelocityCurve {
+    /// Shapes a velocity in 0.0..=1.0, keeping 0.0 and 1.0 where they are
+    pub fn apply(&self, x: f32) -> f32 {
+        if x <= 0.0 {
+            return 0.0;
+        }
+        if x >= 1.0 {
+            return 1.0;
+        }
+        let shaped = match *self {
+            VelocityCurve::Linear => x,
+            VelocityCurve::Exponential(k) if k > 0.0 => ((k * x).exp() - 1.0) / (k.exp() - 1.0),
+            VelocityCurve::Logarithmic(k) if k > 0.0 => (1.0 + (k.exp() - 1.0) * x).ln() / k,
+            VelocityCurve::SCurve(k) if k > 0.0 => {
+                let sigmoid = |t: f32| 1.0 / (1.0 + (-k * (t - 0.5)).exp());
+                (sigmoid(x) - sigmoid(0.0)) / (sigmoid(1.0) - sigmoid(0.0))
+            }
+            _ => x,
+        };
+        shaped.clamp(0.0, 1.0)
+    }
+}
+
+/// Once a key has triggered, it releases as soon as it rises by `release_sensitivity` and
+/// triggers again as soon as it falls by `press_sensitivity`, wherever it is in its travel.
+/// Letting it back up to the actuation point returns to the regular thresholds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RapidTriggerConfig {
+    pub press_sensitivity: f32,
+    pub release_sensitivity: f32,
+}
+
+impl Default for RapidTriggerConfig {
+    fn default() -> Self {
+        Self {
+            press_sensitivity: 0.1,
+            release_sensitivity: 0.1,
+        }
+    }
+}
+
+/// Releases a note once the key has been rising for `ticks` consecutive polls, each by more
+/// than `min_slope`, even if it is still above the threshold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EarlyReleaseConfig {
+    pub ticks: u8,
+    pub min_slope: f32,
+}
+
+/// Plays an alternate `note` when the key is held past the actuation point, but never deeper
+/// than `max_depth`, for at least `hold_ms`. Reaching the threshold cancels it for that press.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoftHoldConfig {
+    pub max_depth: f32,
+    pub hold_ms: u16,
+    #[serde(with = "note")]
+    pub note: NoteID,
+    pub velocity: f32,
+}
+
+/// Spreads the notes of a chord out in time, starting from the base note
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StrumConfig {
+    /// Delay between successive notes
+    pub step_ms: u16,
+    /// Presses at least this fast strum from the top note down instead
+    pub reverse_above_velocity: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RepeatRate {
+    Eighth,
+    #[default]
+    Sixteenth,
+    SixteenthTriplet,
+    ThirtySecond,
+}
+
+impl RepeatRate {
+    pub fn per_beat(self) -> f32 {
+        match self {
+            RepeatRate::Eighth => 2.0,
+            RepeatRate::Sixteenth => 4.0,
+            RepeatRate::SixteenthTriplet => 6.0,
+            RepeatRate::ThirtySecond => 8.0,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Retriggers the note at `rate` while the key is held, louder the deeper it is pressed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoteRepeatConfig {
+    pub rate: RepeatRate,
+    /// Follows `Config::tempo` when unset
+    pub bpm: Option<f32>,
+}
+
+/// How the press speed is measured from the samples since the key started moving
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum VelocityEstimation {
+    /// Slope between the first and the latest sample
+    TwoPoint,
+    /// Least squares slope through the recent samples, less dependent on where the polls land
+    #[default]
+    LeastSquares,
+    /// Fastest movement between two consecutive samples, keeps the slow start of quick taps
+    /// from dragging their velocity down
+    PeakSpeed,
+    /// Time from passing the actuation point to passing the threshold, like the two contacts of
+    /// a digital piano key. `fastest_ms` and faster is full velocity, `slowest_ms` and slower
+    /// the softest one, ignoring `velocity_scale`.
+    ContactTime { fastest_ms: f32, slowest_ms: f32 },
+    /// How far past the threshold the key gets within `window_ms` of crossing it, for pads and
+    /// drums. Delays every note on by the window, a key released within it still plays.
+    Depth { window_ms: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum VibratoTarget {
+    /// Bends along with the wiggle, as a fraction of the bend range
+    #[default]
+    PitchBend,
+    /// Sends the wiggle strength on CC1, leaving the vibrato itself to the synth
+    ModWheel,
+}
+
+/// Turns rhythmically wiggling a held key into vibrato on its channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VibratoConfig {
+    pub target: VibratoTarget,
+    /// Wiggle amplitude in key depth below which nothing is sent, keeps regular aftertouch
+    /// motion from triggering vibrato
+    pub sensitivity: f32,
+    /// Output per key depth of amplitude above `sensitivity`
+    pub depth: f32,
+}
+
+impl Default for VibratoConfig {
+    fn default() -> Self {
+        Self {
+            target: VibratoTarget::default(),
+            sensitivity: 0.02,
+            depth: 4.0,
+        }
+    }
+}
+
+/// Picks a random note from `notes` on every trigger instead of `note_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotePool {
+    #[serde(with = "note_list")]
+    pub notes: Vec<NoteID>,
+    pub no_repeat: bool,
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ArpMode {
+    #[default]
+    Up,
+    Down,
+    UpDown,
+    Random,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArpeggiatorConfig {
+    pub mode: ArpMode,
+    /// Tempo of the steps, follows `Config::tempo` when unset
+    pub bpm: Option<f32>,
+    pub steps_per_beat: u8,
+    /// Portion of each step the note sounds for
+    pub gate: f32,
+}
+
+impl Default for ArpeggiatorConfig {
+    fn default() -> Self {
+        Self {
+            mode: ArpMode::default(),
+            bpm: None,
+            steps_per_beat: 4,
+            gate: 0.5,
+        }
+    }
+}
+
+/// Patch and controller state a channel is put into whenever a port is connected
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelSetup {
+    pub bank_msb: Option<u8>,
+    pub bank_lsb: Option<u8>,
+    pub program: Option<u8>,
+    pub cc_defaults: Vec<(u8, u8)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BendDirection {
+    Up,
+    Down,
+}
+
+/// Turns a key into a pitch bend paddle that bends proportionally to its depth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PitchBendConfig {
+    pub channel: Channel,
+    pub direction: BendDirection,
+}
+
+/// Sends the depth of a key as a continuous controller
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CcConfig {
+    pub controller: u8,
+    pub channel: Channel,
+    /// Also sends the LSB on `controller + 32` for 16384 steps, only for controllers 0 to 31
+    pub high_resolution: bool,
+}
+
+/// Sends a program change, optionally preceded by a bank select, when the key passes `threshold`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgramChangeConfig {
+    pub program: u8,
+    pub channel: Channel,
+    /// Bank select as (MSB, LSB)
+    pub bank: Option<(u8, u8)>,
+    pub threshold: f32,
+}
+
+/// Keys acting as a damper pedal. The pedal goes down once a key passes `threshold` and only
+/// comes back up below `threshold - hysteresis`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SustainConfig {
+    #[serde(with = "key_list")]
+    pub keys: Vec<HIDCodes>,
+    pub channel: Channel,
+    pub threshold: f32,
+    pub hysteresis: f32,
+}
+
+/// What happens to notes that a shift pushes outside the playable note range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ShiftOutOfRange {
+    #[default]
+    Drop,
+    /// Moves the note back by whole octaves, preserving its pitch class
+    Fold,
+    /// Pins the note to the nearest edge of the range
+    Clamp,
+}
+
+/// Filter for the readings of every key, trading a little trigger latency for less noise
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum InputSmoothing {
+    /// Exponential moving average weighting the newest reading by the coefficient in 0.0..=1.0.
+    /// A step needs about `1 / coefficient` polls to settle, so values around 0.5 and above keep
+    /// the added trigger latency to a poll or two.
+    Exponential(f32),
+    /// Median of the last three readings, removes single poll spikes and delays steps by one poll
+    MedianOf3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AftertouchResponse {
+    /// Follows the key up and down
+    #[default]
+    Continuous,
+    /// Holds the deepest pressure of the press until the key is released, for swells
+    PeakHold,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AftertouchMode {
+    #[default]
+    Polyphonic,
+    /// Sends the deepest held key of each channel as channel pressure, for synths without
+    /// polyphonic aftertouch
+    Channel,
+}
+
+/// How the depths of the held keys of a channel combine into its channel pressure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PressureAggregate {
+    #[default]
+    Max,
+    Average,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NotePriority {
+    #[default]
+    Last,
+    High,
+    Low,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MonoConfig {
+    pub priority: NotePriority,
+    /// Moves between held notes by overlapping them instead of retriggering
+    pub legato: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum StealPolicy {
+    #[default]
+    Oldest,
+    /// The note with the lowest velocity, the oldest of equally quiet ones
+    Quietest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolyphonyConfig {
+    pub max_voices: u8,
+    /// Sounding note ended to make room for a new one
+    pub steal: StealPolicy,
+}
+
+impl Default for PolyphonyConfig {
+    fn default() -> Self {
+        Self {
+            max_voices: 8,
+            steal: StealPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum QuantizedRelease {
+    /// The note never sounds
+    #[default]
+    Drop,
+    /// The note starts and ends right away
+    Blip,
+}
+
+/// Holds note ons back until the next grid point of an external MIDI clock. While the clock is
+/// stopped or silent, notes play immediately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuantizeConfig {
+    /// Part of the name of the input port sending the clock
+    pub input_port: String,
+    /// Grid spacing in clock ticks, at 24 per quarter note 6 is a sixteenth
+    pub grid_ticks: u8,
+    /// What happens to notes released before their grid point
+    pub early_release: QuantizedRelease,
+}
+
+impl Default for QuantizeConfig {
+    fn default() -> Self {
+        Self {
+            input_port: String::new(),
+            grid_ticks: 6,
+            early_release: QuantizedRelease::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ScaleKind {
+    #[default]
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    /// Pitch classes in semitones above the root
+    Custom(Vec<u8>),
+}
+
+impl ScaleKind {
+    pub fn intervals(&self) -> &[u8] {
+        match self {
+            ScaleKind::Major => &[0, 2, 4, 5, 7, 9, 11],
+            ScaleKind::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            ScaleKind::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            ScaleKind::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            ScaleKind::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            ScaleKind::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            ScaleKind::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            ScaleKind::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            ScaleKind::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            ScaleKind::MajorPentatonic => &[0, 2, 4, 7, 9],
+            ScaleKind::MinorPentatonic => &[0, 3, 5, 7, 10],
+            ScaleKind::Blues => &[0, 3, 5, 6, 7, 10],
+            ScaleKind::Custom(intervals) => intervals,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OutOfScale {
+    /// The note is not played
+    #[default]
+    Skip,
+    /// The nearest scale tone is played instead, the lower one on ties
+    Snap,
+}
+
+/// Limits the played notes to a scale, applied after shifting
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScaleConfig {
+    /// Pitch class of the root, 0 is C
+    pub root: u8,
+    pub kind: ScaleKind,
+    pub out_of_scale: OutOfScale,
+}
+
+impl ScaleConfig {
+    pub fn contains(&self, note: NoteID) -> bool {
+        let interval = (note as i16 - self.root as i16).rem_euclid(12) as u8;
+        self.kind
+            .intervals()
+            .iter()
+            .any(|&scale_interval| scale_interval % 12 == interval)
+    }
+
+    /// The note itself, its replacement or `None` if it is skipped
+    pub fn fit(&self, note: NoteID) -> Option<NoteID> {
+        if self.contains(note) {
+            return Some(note);
+        }
+        if self.out_of_scale == OutOfScale::Skip {
+            return None;
+        }
+        (1..12)
+            .flat_map(|distance| [note as i16 - distance, note as i16 + distance])
+            .filter(|&candidate| (MIDI_NOTE_MIN as i16..=MIDI_NOTE_MAX as i16).contains(&candidate))
+            .map(|candidate| candidate as NoteID)
+            .find(|&candidate| self.contains(candidate))
+    }
+}
+
+/// Real time message sent when output is turned on, turning it off always sends Stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransportStart {
+    Start,
+    Continue,
+}
+
+/// Key mappings that replace `key_configs` when activated, see `MidiService::activate_preset`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    #[serde(with = "key_map")]
+    pub key_configs: FxHashMap<HIDCodes, KeyConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EnableState {
+    Off,
+    Partial,
+    Full,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    /// Configs older than `CONFIG_VERSION` are migrated when applied. Files written before the
+    /// field existed have none and count as version 1.
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    #[serde(with = "key_list")]
     pub toggle_keys: Vec<HIDCodes>,
+    pub enable_cycle: Vec<EnableState>,
+    #[serde(with = "key_list")]
+    pub partial_scope: Vec<HIDCodes>,
+    pub reset_controllers_on_switch: bool,
+    /// Controller values sent after a reset, as (controller, value) pairs
+    pub controller_defaults: Vec<(u8, u8)>,
+    #[serde(with = "channel_map")]
+    pub channel_setup: FxHashMap<Channel, ChannelSetup>,
+    #[serde(with = "key_list")]
     pub modifier_keys: Vec<HIDCodes>,
+    pub sustain: Option<SustainConfig>,
+    pub shift_out_of_range: ShiftOutOfRange,
+    /// Semitones every note is moved by, on top of the modifier shift and the runtime transpose
+    pub transpose: i8,
+    pub input_smoothing: Option<InputSmoothing>,
+    pub aftertouch_mode: AftertouchMode,
+    /// Sends channel pressure from all held keys of a channel in addition to polyphonic
+    /// aftertouch, or picks how it is combined in the channel aftertouch mode
+    pub channel_pressure: Option<PressureAggregate>,
+    /// Maximum aftertouch messages per second and key, updates in between are skipped
+    pub aftertouch_max_rate: Option<f32>,
+    /// Prefixes every note on with a CC88 carrying 7 more bits of velocity
+    pub high_resolution_velocity: bool,
+    /// Gives every sounding note its own member channel of the lower MPE zone, ignoring the
+    /// channels of the key configs
+    pub mpe: bool,
+    /// Number of member channels, starting at the second MIDI channel
+    pub mpe_zone_size: u8,
+    /// Channels that sound only one of their held notes at a time
+    #[serde(with = "channel_map")]
+    pub mono_channels: FxHashMap<Channel, MonoConfig>,
+    /// Voices of synths that steal notes on their own, with MPE these are member channels
+    #[serde(with = "channel_map")]
+    pub polyphony: FxHashMap<Channel, PolyphonyConfig>,
+    pub scale: Option<ScaleConfig>,
+    /// Microtonal tuning, best combined with MPE so every note gets its own bend
+    pub tuning: Option<Tuning>,
+    /// Octave convention of note names in the log
+    pub middle_c: MiddleC,
+    /// Plays the held notes one at a time while set
+    pub arpeggiator: Option<ArpeggiatorConfig>,
+    #[serde(with = "key_map")]
+    pub pitch_bend_keys: FxHashMap<HIDCodes, PitchBendConfig>,
+    #[serde(with = "key_map")]
+    pub cc_mappings: FxHashMap<HIDCodes, CcConfig>,
+    #[serde(with = "key_map")]
+    pub program_change_keys: FxHashMap<HIDCodes, ProgramChangeConfig>,
+    /// Bend range in semitones the receiver is configured to on connect
+    pub pitch_bend_range_semitones: Option<u8>,
+    /// Complete SysEx messages, including 0xF0 and 0xF7, sent when output is turned on or off
+    pub on_enable_sysex: Option<Vec<u8>>,
+    pub on_disable_sysex: Option<Vec<u8>>,
+    /// Starts and stops the receiver's transport along with the output, independent of the clock
+    pub transport_on_toggle: Option<TransportStart>,
+    /// Sends MIDI clock at this tempo while set
+    pub clock_bpm: Option<f32>,
+    pub quantize: Option<QuantizeConfig>,
+    /// Chords that raise or lower the threshold of every key at runtime, all keys of a chord have
+    /// to be held
+    #[serde(with = "key_list")]
+    pub threshold_nudge_up_keys: Vec<HIDCodes>,
+    #[serde(with = "key_list")]
+    pub threshold_nudge_down_keys: Vec<HIDCodes>,
+    /// Keep the runtime threshold nudge when a new config is applied
+    pub threshold_nudge_sticky: bool,
+    #[serde(with = "key_map")]
     pub key_configs: FxHashMap<HIDCodes, KeyConfig>,
+    /// Named alternatives to `key_configs`, written as `[preset.<name>]` sections
+    #[serde(rename = "preset")]
+    pub presets: BTreeMap<String, Preset>,
+    /// Preset whose key configs are used instead of `key_configs` when the config is applied
+    pub default_preset: Option<String>,
+    /// Channel replacing the channels of all key configs and CC mappings while set
+    pub default_channel: Option<Channel>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             toggle_keys: vec![],
+            enable_cycle: vec![EnableState::Off, EnableState::Full],
+            partial_scope: vec![],
+            reset_controllers_on_switch: true,
+            controller_defaults: vec![(7, 100), (11, 127)], // Volume, Expression
+            channel_setup: FxHashMap::default(),
             modifier_keys: vec![HIDCodes::LeftShift, HIDCodes::RightShift],
+            sustain: None,
+            shift_out_of_range: ShiftOutOfRange::default(),
+            transpose: 0,
+            input_smoothing: None,
+            aftertouch_mode: AftertouchMode::default(),
+            channel_pressure: None,
+            aftertouch_max_rate: None,
+            high_resolution_velocity: false,
+            mpe: false,
+            mpe_zone_size: 15,
+            mono_channels: FxHashMap::default(),
+            polyphony: FxHashMap::default(),
+            arpeggiator: None,
+            scale: None,
+            tuning: None,
+            middle_c: MiddleC::default(),
+            pitch_bend_keys: FxHashMap::default(),
+            cc_mappings: FxHashMap::default(),
+            program_change_keys: FxHashMap::default(),
+            pitch_bend_range_semitones: None,
+            on_enable_sysex: None,
+            on_disable_sysex: None,
+            transport_on_toggle: None,
+            clock_bpm: None,
+            quantize: None,
+            threshold_nudge_up_keys: vec![],
+            threshold_nudge_down_keys: vec![],
+            threshold_nudge_sticky: false,
             key_configs: FxHashMap::default(),
+            presets: BTreeMap::new(),
+            default_preset: None,
+            default_channel: None,
+        }
+    }
+}
+
+impl KeyConfig {
+    /// Aftertouch for a key depth, see `aftertouch_curve`
+    pub fn shape_aftertouch(&self, depth: f32, threshold: f32) -> f32 {
+        match &self.aftertouch_curve {
+            Some(curve) if threshold < 1.0 => curve.apply((depth - threshold) / (1.0 - threshold)),
+            Some(_) => 1.0,
+            None => depth,
+        }
+    }
+
+    /// Rescales a raw reading by the calibration window, clamped to 0.0..=1.0
+    pub fn calibrate(&self, value: f32) -> f32 {
+        if self.calibration_min == 0.0 && self.calibration_max == 1.0 {
+            return value;
+        }
+        ((value - self.calibration_min) / (self.calibration_max - self.calibration_min))
+            .clamp(0.0, 1.0)
+    }
+
+    /// Threshold with a runtime nudge applied, kept above the actuation point
+    pub fn nudged_threshold(&self, delta: f32) -> f32 {
+        (self.threshold + delta).clamp((self.actuation_point + 0.01).min(1.0), 1.0)
+    }
+
+    /// Release threshold for a nudged threshold, it moves along with the nudge
+    pub fn nudged_release_threshold(&self, delta: f32) -> f32 {
+        let hysteresis = match self.release_threshold {
+            Some(release_threshold) => self.threshold - release_threshold,
+            None => DEFAULT_RELEASE_HYSTERESIS,
+        };
+        (self.nudged_threshold(delta) - hysteresis).max(self.actuation_point)
+    }
+
+    /// Snaps a velocity to the center of its layer when `velocity_steps` is set
+    pub fn snap_velocity(&self, velocity: f32) -> f32 {
+        match self.velocity_steps {
+            Some(steps) => quantize_velocity(velocity, steps),
+            None => velocity,
+        }
+    }
+
+    /// Maps a velocity into the `velocity_min` to `velocity_max` window
+    pub fn scale_velocity(&self, velocity: f32) -> f32 {
+        let (min, max) = (self.velocity_min as f32, self.velocity_max as f32);
+        (min + velocity.clamp(0.0, 1.0) * (max - min)) / 127.0
+    }
+}
+
+fn legacy_version() -> u32 {
+    1
+}
+
+impl Config {
+    /// Tempo of synced features without their own: the clock tempo, or 120 BPM without a clock
+    pub fn tempo(&self) -> f32 {
+        self.clock_bpm.unwrap_or(DEFAULT_BPM)
+    }
+
+    /// Converts fields of older config versions to the current semantics
+    pub fn migrate(&mut self) {
+        if self.version < 2 {
+            // velocity_scale used to be a multiplier in percent on the travel speed
+            let presets = self
+                .presets
+                .values_mut()
+                .flat_map(|preset| preset.key_configs.values_mut());
+            for key_config in self.key_configs.values_mut().chain(presets) {
+                if key_config.velocity_scale > 0.0 {
+                    key_config.velocity_scale = 100.0 / key_config.velocity_scale;
+                }
+            }
+        }
+        self.version = CONFIG_VERSION;
+    }
+
+    /// Every channel any mapping of this config sends on
+    pub fn channels(&self) -> Vec<Channel> {
+        let mut channels: Vec<Channel> = self
+            .key_configs
+            .values()
+            .map(|key_config| key_config.channel)
+            .chain(self.pitch_bend_keys.values().map(|bend| bend.channel))
+            .chain(self.cc_mappings.values().map(|cc| cc.channel))
+            .chain(
+                self.program_change_keys
+                    .values()
+                    .map(|program| program.channel),
+            )
+            .chain(self.sustain.iter().map(|sustain| sustain.channel))
+            .collect();
+        if self.mpe {
+            channels.extend(0..=mpe::member_channels(self));
         }
+        channels.sort_unstable();
+        channels.dedup();
+        channels
+    }
+
+    pub fn next_enable_state(&self, current: EnableState) -> EnableState {
+        match self.enable_cycle.iter().position(|&state| state == current) {
+            Some(index) => self.enable_cycle[(index + 1) % self.enable_cycle.len()],
+            None => self.enable_cycle.first().copied().unwrap_or(current),
+        }
+    }
+
+    pub fn is_key_active(&self, state: EnableState, hid_code: &HIDCodes) -> bool {
+        match state {
+            EnableState::Off => false,
+            EnableState::Partial => self.partial_scope.contains(hid_code),
+            EnableState::Full => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enable_states_follow_the_cycle() {
+        let config = Config {
+            enable_cycle: vec![EnableState::Off, EnableState::Partial, EnableState::Full],
+            ..Config::default()
+        };
+        assert_eq!(
+            config.next_enable_state(EnableState::Off),
+            EnableState::Partial
+        );
+        assert_eq!(
+            config.next_enable_state(EnableState::Partial),
+            EnableState::Full
+        );
+        assert_eq!(
+            config.next_enable_state(EnableState::Full),
+            EnableState::Off
+        );
+
+        // A state missing from the cycle restarts it
+        let config = Config::default();
+        assert_eq!(
+            config.next_enable_state(EnableState::Partial),
+            EnableState::Off
+        );
+        assert_eq!(
+            config.next_enable_state(EnableState::Off),
+            EnableState::Full
+        );
+    }
+
+    #[test]
+    fn partial_state_only_activates_its_scope() {
+        let config = Config {
+            partial_scope: vec![HIDCodes::Numpad1],
+            ..Config::default()
+        };
+        assert!(config.is_key_active(EnableState::Partial, &HIDCodes::Numpad1));
+        assert!(!config.is_key_active(EnableState::Partial, &HIDCodes::A));
+        assert!(config.is_key_active(EnableState::Full, &HIDCodes::A));
+        assert!(!config.is_key_active(EnableState::Off, &HIDCodes::Numpad1));
+    }
+
+    #[test]
+    fn version_1_velocity_scales_are_migrated() {
+        let old_key = KeyConfig {
+            velocity_scale: 5.0,
+            ..KeyConfig::default()
+        };
+        let mut config = Config {
+            version: 1,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, old_key.clone());
+        config.presets.insert(
+            "lead".to_owned(),
+            Preset {
+                key_configs: [(HIDCodes::A, old_key)].into_iter().collect(),
+            },
+        );
+        config.migrate();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.key_configs[&HIDCodes::A].velocity_scale, 20.0);
+        assert_eq!(
+            config.presets["lead"].key_configs[&HIDCodes::A].velocity_scale,
+            20.0
+        );
+
+        // Current configs are left alone
+        config.migrate();
+        assert_eq!(config.key_configs[&HIDCodes::A].velocity_scale, 20.0);
+    }
+
+    #[test]
+    fn configs_without_a_version_are_migrated() {
+        let mut config: Config = serde_json::from_str(
+            r#"{"key_configs": {"A": {"note_id": 60, "velocity_scale": 5.0}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.version, 1);
+        config.migrate();
+        assert_eq!(config.key_configs[&HIDCodes::A].velocity_scale, 20.0);
+
+        let current: Config = serde_json::from_str(&format!(
+            r#"{{"version": {}, "key_configs": {{"A": {{"velocity_scale": 5.0}}}}}}"#,
+            CONFIG_VERSION
+        ))
+        .unwrap();
+        assert_eq!(current.key_configs[&HIDCodes::A].velocity_scale, 5.0);
+        assert_eq!(Config::default().version, CONFIG_VERSION);
+    }
+
+    #[test]
+    fn aftertouch_curves_keep_their_ends() {
+        let curves = [
+            AftertouchCurve::Linear,
+            AftertouchCurve::Exponential,
+            AftertouchCurve::Logarithmic,
+            AftertouchCurve::Gamma(2.0),
+        ];
+        for curve in curves {
+            assert!(curve.apply(0.0).abs() < 1e-6, "{:?}", curve);
+            assert!((curve.apply(1.0) - 1.0).abs() < 1e-6, "{:?}", curve);
+            let values: Vec<f32> = (0..=20).map(|i| curve.apply(i as f32 / 20.0)).collect();
+            assert!(
+                values.windows(2).all(|pair| pair[0] < pair[1]),
+                "{:?}",
+                curve
+            );
+        }
+        assert!(AftertouchCurve::Exponential.apply(0.5) < 0.5);
+        assert!(AftertouchCurve::Logarithmic.apply(0.5) > 0.5);
+        assert_eq!(AftertouchCurve::Gamma(2.0).apply(0.5), 0.25);
+        assert_eq!(AftertouchCurve::Linear.apply(1.5), 1.0);
+    }
+
+    #[test]
+    fn aftertouch_curve_spans_threshold_to_bottom() {
+        let key_config = KeyConfig {
+            aftertouch_curve: Some(AftertouchCurve::Linear),
+            ..KeyConfig::default()
+        };
+        assert_eq!(key_config.shape_aftertouch(0.8, 0.8), 0.0);
+        assert!((key_config.shape_aftertouch(0.9, 0.8) - 0.5).abs() < 1e-6);
+        assert_eq!(key_config.shape_aftertouch(1.0, 0.8), 1.0);
+        assert_eq!(key_config.shape_aftertouch(1.0, 1.0), 1.0);
+        // Without a curve the raw depth is used
+        assert_eq!(KeyConfig::default().shape_aftertouch(0.9, 0.8), 0.9);
+    }
+
+    const CURVES: [VelocityCurve; 4] = [
+        VelocityCurve::Linear,
+        VelocityCurve::Exponential(3.0),
+        VelocityCurve::Logarithmic(3.0),
+        VelocityCurve::SCurve(8.0),
+    ];
+
+    #[test]
+    fn velocity_curves_keep_their_ends_and_rise() {
+        for curve in CURVES {
+            assert_eq!(curve.apply(0.0), 0.0);
+            assert_eq!(curve.apply(1.0), 1.0);
+            assert_eq!(curve.apply(-0.5), 0.0);
+            assert_eq!(curve.apply(1.5), 1.0);
+            let samples: Vec<f32> = (0..=100).map(|i| curve.apply(i as f32 / 100.0)).collect();
+            assert!(
+                samples.windows(2).all(|pair| pair[0] < pair[1]),
+                "{:?} is not rising",
+                curve
+            );
+        }
+    }
+
+    #[test]
+    fn velocity_curves_bend_the_right_way() {
+        assert!(VelocityCurve::Exponential(3.0).apply(0.5) < 0.5);
+        assert!(VelocityCurve::Logarithmic(3.0).apply(0.5) > 0.5);
+        let s_curve = VelocityCurve::SCurve(8.0);
+        assert!((s_curve.apply(0.5) - 0.5).abs() < 1e-6);
+        assert!(s_curve.apply(0.1) < 0.1);
+        assert!(s_curve.apply(0.9) > 0.9);
+    }
+
+    #[test]
+    fn flat_velocity_curves_are_linear() {
+        for curve in [
+            VelocityCurve::Exponential(0.0),
+            VelocityCurve::Logarithmic(-1.0),
+            VelocityCurve::SCurve(0.0),
+        ] {
+            assert_eq!(curve.apply(0.3), 0.3);
+        }
+    }
+
+    #[test]
+    fn calibration_window_is_stretched_to_the_full_range() {
+        let key_config = KeyConfig {
+            calibration_min: 0.1,
+            calibration_max: 0.9,
+            ..KeyConfig::default()
+        };
+        assert_eq!(key_config.calibrate(0.05), 0.0);
+        assert_eq!(key_config.calibrate(0.1), 0.0);
+        assert!((key_config.calibrate(0.5) - 0.5).abs() < 1e-6);
+        assert!((key_config.calibrate(0.7) - 0.75).abs() < 1e-6);
+        assert_eq!(key_config.calibrate(0.95), 1.0);
+        // Uncalibrated keys pass readings through untouched
+        assert_eq!(KeyConfig::default().calibrate(0.42), 0.42);
     }
 }
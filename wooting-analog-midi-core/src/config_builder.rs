@@ -0,0 +1,195 @@
+use crate::config::{Config, KeyConfig, VelocityCurve};
+use crate::validation;
+use crate::{Channel, NoteID};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+use wooting_analog_wrapper::HIDCodes;
+
+/// Builds a `Config` without spelling out every key config, e.g.
+/// `ConfigBuilder::new().chromatic_row(&[HIDCodes::Q, HIDCodes::W], 60).build()`
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps a key to a note with the default key config
+    pub fn key(self, hid_code: HIDCodes, note_id: NoteID) -> Self {
+        self.key_with(hid_code, |key| key.note(note_id))
+    }
+
+    /// Maps a key with a key config adjusted by `configure`, it starts out as the default one
+    pub fn key_with(
+        mut self,
+        hid_code: HIDCodes,
+        configure: impl FnOnce(KeyConfigBuilder) -> KeyConfigBuilder,
+    ) -> Self {
+        let key_config = configure(KeyConfigBuilder::default()).key_config;
+        self.config.key_configs.insert(hid_code, key_config);
+        self
+    }
+
+    /// Maps the keys to consecutive notes, starting with `starting_note`
+    pub fn chromatic_row(mut self, hid_codes: &[HIDCodes], starting_note: NoteID) -> Self {
+        for (hid_code, note_id) in hid_codes.iter().zip(starting_note..=NoteID::MAX) {
+            self = self.key(hid_code.clone(), note_id);
+        }
+        self
+    }
+
+    /// Adds the key configs of a layout, see `piano_layout` and the other generators
+    pub fn layout(mut self, key_configs: FxHashMap<HIDCodes, KeyConfig>) -> Self {
+        self.config.key_configs.extend(key_configs);
+        self
+    }
+
+    pub fn toggle_key(mut self, hid_code: HIDCodes) -> Self {
+        self.config.toggle_keys.push(hid_code);
+        self
+    }
+
+    /// Adjusts any other setting of the config
+    pub fn configure(mut self, configure: impl FnOnce(&mut Config)) -> Self {
+        configure(&mut self.config);
+        self
+    }
+
+    /// The config, if `Config::validate` finds no problems
+    pub fn build(self) -> Result<Config> {
+        validation::ensure_valid(&self.config)?;
+        Ok(self.config)
+    }
+}
+
+/// Adjusts a key config inside `ConfigBuilder::key_with`
+#[derive(Debug, Clone, Default)]
+pub struct KeyConfigBuilder {
+    key_config: KeyConfig,
+}
+
+impl KeyConfigBuilder {
+    pub fn note(mut self, note_id: NoteID) -> Self {
+        self.key_config.note_id = note_id;
+        self
+    }
+
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.key_config.channel = channel;
+        self
+    }
+
+    pub fn actuation_point(mut self, actuation_point: f32) -> Self {
+        self.key_config.actuation_point = actuation_point;
+        self
+    }
+
+    pub fn threshold(mut self, threshold: f32) -> Self {
+        self.key_config.threshold = threshold;
+        self
+    }
+
+    pub fn release_threshold(mut self, release_threshold: f32) -> Self {
+        self.key_config.release_threshold = Some(release_threshold);
+        self
+    }
+
+    pub fn velocity_scale(mut self, velocity_scale: f32) -> Self {
+        self.key_config.velocity_scale = velocity_scale;
+        self
+    }
+
+    pub fn velocity_curve(mut self, velocity_curve: VelocityCurve) -> Self {
+        self.key_config.velocity_curve = Some(velocity_curve);
+        self
+    }
+
+    pub fn fixed_velocity(mut self, velocity: u8) -> Self {
+        self.key_config.fixed_velocity = Some(velocity);
+        self
+    }
+
+    pub fn aftertouch(mut self, aftertouch: bool) -> Self {
+        self.key_config.aftertouch = aftertouch;
+        self
+    }
+
+    pub fn shift_amount(mut self, shift_amount: i8) -> Self {
+        self.key_config.shift_amount = shift_amount;
+        self
+    }
+
+    /// Semitone offsets sounding along with the note, see `KeyConfig::extra_notes`
+    pub fn chord(mut self, extra_notes: &[i8]) -> Self {
+        self.key_config.extra_notes = extra_notes.to_vec();
+        self
+    }
+
+    pub fn latch(mut self, latch: bool) -> Self {
+        self.key_config.latch = latch;
+        self
+    }
+
+    /// Adjusts any other field of the key config
+    pub fn configure(mut self, configure: impl FnOnce(&mut KeyConfig)) -> Self {
+        configure(&mut self.key_config);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chromatic_rows_count_up_from_the_starting_note() {
+        let config = ConfigBuilder::new()
+            .chromatic_row(&[HIDCodes::Q, HIDCodes::W, HIDCodes::E], 60)
+            .build()
+            .unwrap();
+        let notes: Vec<NoteID> = [HIDCodes::Q, HIDCodes::W, HIDCodes::E]
+            .iter()
+            .map(|hid_code| config.key_configs[hid_code].note_id)
+            .collect();
+        assert_eq!(notes, [60, 61, 62]);
+    }
+
+    #[test]
+    fn key_settings_start_from_the_default() {
+        let config = ConfigBuilder::new()
+            .key_with(HIDCodes::A, |key| {
+                key.note(64)
+                    .channel(2)
+                    .threshold(0.6)
+                    .chord(&[4, 7])
+                    .configure(|key_config| key_config.velocity_trim = 5)
+            })
+            .toggle_key(HIDCodes::F12)
+            .configure(|config| config.transpose = -12)
+            .build()
+            .unwrap();
+        let key_config = &config.key_configs[&HIDCodes::A];
+        assert_eq!((key_config.note_id, key_config.channel), (64, 2));
+        assert_eq!(key_config.threshold, 0.6);
+        assert_eq!(key_config.extra_notes, [4, 7]);
+        assert_eq!(key_config.velocity_trim, 5);
+        assert_eq!(
+            key_config.actuation_point,
+            KeyConfig::default().actuation_point
+        );
+        assert_eq!(config.toggle_keys, [HIDCodes::F12]);
+        assert_eq!(config.transpose, -12);
+    }
+
+    #[test]
+    fn invalid_configs_are_not_built() {
+        assert!(ConfigBuilder::new().build().is_err());
+        let inverted = ConfigBuilder::new().key_with(HIDCodes::A, |key| {
+            key.note(60).actuation_point(0.9).threshold(0.5)
+        });
+        assert!(inverted.build().is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use crate::config::Config;
+use anyhow::{bail, Context, Result};
+use log::warn;
+use std::path::Path;
+use wooting_analog_wrapper::HIDCodes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Format matching the extension of a path, `None` for anything but .toml and .json
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?;
+        if extension.eq_ignore_ascii_case("toml") {
+            Some(ConfigFormat::Toml)
+        } else if extension.eq_ignore_ascii_case("json") {
+            Some(ConfigFormat::Json)
+        } else {
+            None
+        }
+    }
+
+    fn detect(path: &Path) -> Result<Self> {
+        match Self::from_path(path) {
+            Some(format) => Ok(format),
+            None => bail!(
+                "Cannot tell the format of {}, expected a .toml or .json file",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Config {
+    /// Reads a config in the format of its extension, see `load_from_path_as`
+    pub fn load_from_path(path: &Path) -> Result<Self> {
+        Self::load_from_path_as(path, ConfigFormat::detect(path)?)
+    }
+
+    /// Reads a config, fields it does not know are skipped with a warning
+    pub fn load_from_path_as(path: &Path, format: ConfigFormat) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let warn_ignored = |field: serde_ignored::Path| {
+            warn!(
+                "Ignoring unknown config field {} in {}",
+                field,
+                path.display()
+            );
+        };
+        let config = match format {
+            ConfigFormat::Toml => {
+                serde_ignored::deserialize(toml::Deserializer::new(&source), warn_ignored)
+                    .map_err(anyhow::Error::from)
+            }
+            ConfigFormat::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(&source);
+                serde_ignored::deserialize(&mut deserializer, warn_ignored)
+                    .and_then(|config| deserializer.end().map(|_| config))
+                    .map_err(anyhow::Error::from)
+            }
+        };
+        config.with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Writes the config in the format of the extension, see `save_to_path_as`
+    pub fn save_to_path(&self, path: &Path) -> Result<()> {
+        self.save_to_path_as(path, ConfigFormat::detect(path)?)
+    }
+
+    pub fn save_to_path_as(&self, path: &Path, format: ConfigFormat) -> Result<()> {
+        let source = match format {
+            ConfigFormat::Toml => toml::to_string_pretty(self).map_err(anyhow::Error::from),
+            ConfigFormat::Json => serde_json::to_string_pretty(self).map_err(anyhow::Error::from),
+        }
+        .context("Failed to serialize config")?;
+        std::fs::write(path, source).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+fn parse_key<E: serde::de::Error>(name: &str) -> Result<HIDCodes, E> {
+    crate::keynames::parse(name).ok_or_else(|| E::custom(format!("unknown key \"{}\"", name)))
+}
+
+/// A single key written by its US-ANSI name, for output only
+pub(crate) mod key {
+    use crate::keynames::{self, NamingScheme};
+    use serde::Serializer;
+    use wooting_analog_wrapper::HIDCodes;
+
+    pub(crate) fn serialize<S: Serializer>(
+        key: &HIDCodes,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(keynames::name_of(key, NamingScheme::UsAnsi))
+    }
+}
+
+/// Keys written by their US-ANSI names, like "Q" or "F12"
+pub(crate) mod key_list {
+    use crate::keynames::{self, NamingScheme};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use wooting_analog_wrapper::HIDCodes;
+
+    pub(crate) fn serialize<S: Serializer>(
+        keys: &[HIDCodes],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            keys.iter()
+                .map(|key| keynames::name_of(key, NamingScheme::UsAnsi)),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<HIDCodes>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|name| super::parse_key(name))
+            .collect()
+    }
+}
+
+/// Tables keyed by key names, written in HID code order
+pub(crate) mod key_map {
+    use crate::keynames::{self, NamingScheme};
+    use rustc_hash::FxHashMap;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+    use wooting_analog_wrapper::{HIDCodes, ToPrimitive};
+
+    pub(crate) fn serialize<T: Serialize, S: Serializer>(
+        map: &FxHashMap<HIDCodes, T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort_by_key(|(key, _)| key.to_u16());
+        serializer.collect_map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (keynames::name_of(key, NamingScheme::UsAnsi), value)),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<FxHashMap<HIDCodes, T>, D::Error> {
+        let mut map = FxHashMap::default();
+        for (name, value) in BTreeMap::<String, T>::deserialize(deserializer)? {
+            let key = super::parse_key::<D::Error>(&name)?;
+            if map.insert(key, value).is_some() {
+                return Err(de::Error::custom(format!(
+                    "key \"{}\" is listed more than once",
+                    name
+                )));
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Tables keyed by channel numbers, TOML keys are always strings
+pub(crate) mod channel_map {
+    use crate::Channel;
+    use rustc_hash::FxHashMap;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub(crate) fn serialize<T: Serialize, S: Serializer>(
+        map: &FxHashMap<Channel, T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let entries: BTreeMap<_, _> = map.iter().collect();
+        serializer.collect_map(
+            entries
+                .into_iter()
+                .map(|(channel, value)| (channel.to_string(), value)),
+        )
+    }
+
+    pub(crate) fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<FxHashMap<Channel, T>, D::Error> {
+        BTreeMap::<String, T>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(channel, value)| match channel.trim().parse() {
+                Ok(channel) => Ok((channel, value)),
+                Err(_) => Err(de::Error::custom(format!(
+                    "\"{}\" is not a channel number",
+                    channel
+                ))),
+            })
+            .collect()
+    }
+}
+
+/// Scale degrees of a keyboard mapping, silent keys are written as "x" like in .kbm files
+pub(crate) mod degree_list {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Degree {
+        Degree(usize),
+        Silent(String),
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        degrees: &[Option<usize>],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(degrees.iter().map(|degree| match degree {
+            Some(degree) => Degree::Degree(*degree),
+            None => Degree::Silent("x".to_string()),
+        }))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Option<usize>>, D::Error> {
+        Vec::<Degree>::deserialize(deserializer)?
+            .into_iter()
+            .map(|degree| match degree {
+                Degree::Degree(degree) => Ok(Some(degree)),
+                Degree::Silent(silent) if silent.eq_ignore_ascii_case("x") => Ok(None),
+                Degree::Silent(other) => Err(de::Error::custom(format!(
+                    "\"{}\" is neither a scale degree nor x",
+                    other
+                ))),
+            })
+            .collect()
+    }
+}
+
+/// Notes written as numbers or as names like "C#4", names always count middle C as C4
+pub(crate) mod note {
+    use crate::notenames::{self, MiddleC};
+    use crate::NoteID;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt;
+
+    pub(crate) struct Note(pub(crate) NoteID);
+
+    impl<'de> Deserialize<'de> for Note {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(NoteVisitor)
+        }
+    }
+
+    struct NoteVisitor;
+
+    impl de::Visitor<'_> for NoteVisitor {
+        type Value = Note;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a note number or a note name like \"C#4\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Note, E> {
+            NoteID::try_from(value)
+                .map(Note)
+                .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Note, E> {
+            match u64::try_from(value) {
+                Ok(value) => self.visit_u64(value),
+                Err(_) => Err(E::invalid_value(de::Unexpected::Signed(value), &self)),
+            }
+        }
+
+        fn visit_str<E: de::Error>(self, name: &str) -> Result<Note, E> {
+            notenames::parse_in(name, MiddleC::C4)
+                .map(Note)
+                .ok_or_else(|| E::custom(format!("\"{}\" is not a note name", name)))
+        }
+    }
+
+    pub(crate) fn serialize<S: Serializer>(
+        note: &NoteID,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*note)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<NoteID, D::Error> {
+        Note::deserialize(deserializer).map(|note| note.0)
+    }
+}
+
+/// Lists of notes, each written as a number or a name
+pub(crate) mod note_list {
+    use super::note::Note;
+    use crate::NoteID;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        notes: &[NoteID],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(notes)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<NoteID>, D::Error> {
+        let notes = Vec::<Note>::deserialize(deserializer)?;
+        Ok(notes.into_iter().map(|note| note.0).collect())
+    }
+}
+
+/// An optional note paired with a depth, like `KeyConfig::second_note`
+pub(crate) mod note_and_depth {
+    use super::note::Note;
+    use crate::NoteID;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Option<(NoteID, f32)>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<(NoteID, f32)>, D::Error> {
+        let value = Option::<(Note, f32)>::deserialize(deserializer)?;
+        Ok(value.map(|(note, depth)| (note.0, depth)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MonoConfig, VelocityCurve};
+    use crate::layouts::piano_layout;
+
+    const NOTE_NAMES: &str = r#"
+        [key_configs.Q]
+        note_id = "C#4"
+        second_note = ["D4", 0.9]
+
+        [key_configs.Q.soft_hold]
+        max_depth = 0.5
+        hold_ms = 300
+        note = "Bb3"
+        velocity = 0.4
+
+        [key_configs.W]
+        note_id = 64
+
+        [key_configs.W.note_pool]
+        notes = ["C4", 62, "e4"]
+        no_repeat = true
+    "#;
+
+    #[test]
+    fn note_names_load_like_numbers() {
+        let config: Config = toml::from_str(NOTE_NAMES).unwrap();
+        let q = &config.key_configs[&HIDCodes::Q];
+        assert_eq!(q.note_id, 61);
+        assert_eq!(q.second_note, Some((62, 0.9)));
+        assert_eq!(q.soft_hold.as_ref().unwrap().note, 58);
+        let w = &config.key_configs[&HIDCodes::W];
+        assert_eq!(w.note_id, 64);
+        assert_eq!(w.note_pool.as_ref().unwrap().notes, vec![60, 62, 64]);
+
+        let json: Config = serde_json::from_str(
+            r#"{"key_configs": {"Q": {"note_id": "C#4", "second_note": ["D4", 0.9]}}}"#,
+        )
+        .unwrap();
+        assert_eq!(json.key_configs[&HIDCodes::Q].note_id, 61);
+        assert_eq!(json.key_configs[&HIDCodes::Q].second_note, Some((62, 0.9)));
+    }
+
+    #[test]
+    fn invalid_note_names_are_rejected() {
+        for note in [r#""H4""#, r#""G#9""#, "256", "-1"] {
+            let source = format!("[key_configs.Q]\nnote_id = {}", note);
+            assert!(toml::from_str::<Config>(&source).is_err(), "{}", note);
+        }
+    }
+
+    /// Config has no `PartialEq`, its JSON tree stands in for comparisons
+    fn as_value(config: &Config) -> serde_json::Value {
+        serde_json::to_value(config).unwrap()
+    }
+
+    #[test]
+    fn default_config_round_trips_through_toml() {
+        let config = Config::default();
+        let source = toml::to_string_pretty(&config).unwrap();
+        let loaded: Config = toml::from_str(&source).unwrap();
+        assert_eq!(as_value(&loaded), as_value(&config));
+    }
+
+    /// Config exercising the custom field formats, nested tables and optional values
+    fn populated_config() -> Config {
+        let mut config = Config {
+            key_configs: piano_layout(48),
+            toggle_keys: vec![HIDCodes::F12],
+            ..Config::default()
+        };
+        let key_config = config.key_configs.get_mut(&HIDCodes::A).unwrap();
+        key_config.velocity_curve = Some(VelocityCurve::SCurve(4.0));
+        key_config.second_note = Some((72, 0.95));
+        key_config.extra_notes = vec![4, 7];
+        config.mono_channels.insert(1, MonoConfig::default());
+        config
+    }
+
+    #[test]
+    fn populated_config_round_trips_through_toml() {
+        let config = populated_config();
+        let source = toml::to_string_pretty(&config).unwrap();
+        let loaded: Config = toml::from_str(&source).unwrap();
+        assert_eq!(as_value(&loaded), as_value(&config));
+    }
+
+    #[test]
+    fn saved_toml_files_load_back() {
+        let path = std::env::temp_dir().join(format!(
+            "wooting-analog-midi-round-trip-{}.toml",
+            std::process::id()
+        ));
+        let config = populated_config();
+        config.save_to_path(&path).unwrap();
+        let loaded = Config::load_from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(as_value(&loaded.unwrap()), as_value(&config));
+    }
+
+    #[test]
+    fn toml_and_json_files_load_the_same_config() {
+        let config = populated_config();
+        let loaded: Vec<serde_json::Value> = ["toml", "json"]
+            .iter()
+            .map(|extension| {
+                let path = std::env::temp_dir().join(format!(
+                    "wooting-analog-midi-formats-{}.{}",
+                    std::process::id(),
+                    extension
+                ));
+                config.save_to_path(&path).unwrap();
+                let loaded = Config::load_from_path(&path);
+                std::fs::remove_file(&path).unwrap();
+                as_value(&loaded.unwrap())
+            })
+            .collect();
+        assert_eq!(loaded[0], loaded[1]);
+        assert_eq!(loaded[0], as_value(&config));
+    }
+
+    #[test]
+    fn unknown_extensions_are_rejected() {
+        let path = Path::new("config.yaml");
+        assert_eq!(ConfigFormat::from_path(path), None);
+        assert!(Config::default().save_to_path(path).is_err());
+        assert_eq!(
+            ConfigFormat::from_path(Path::new("CONFIG.TOML")),
+            Some(ConfigFormat::Toml)
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::keynames::{self, NamingScheme};
+use crate::notenames::{self, MiddleC};
+use crate::NoteID;
+use log::{debug, info, log_enabled, warn, Level};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use wooting_analog_wrapper::HIDCodes;
+
+const EVENT_LOG_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RecordKind {
+    NoteOn,
+    NoteOff,
+}
+
+#[derive(Debug)]
+pub(crate) struct Record {
+    pub(crate) key: HIDCodes,
+    pub(crate) kind: RecordKind,
+    pub(crate) note: NoteID,
+    pub(crate) middle_c: MiddleC,
+    pub(crate) value: f32,
+    pub(crate) press_duration: Option<Duration>,
+    pub(crate) tick: u64,
+}
+
+/// Hands hot path log records to a background thread for formatting. Records are dropped, never
+/// blocking the polling thread, when the background thread falls behind.
+pub(crate) struct EventLog {
+    sender: SyncSender<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventLog {
+    pub(crate) fn new() -> Self {
+        Self::spawn(EVENT_LOG_CAPACITY, |record| format_record(&record))
+    }
+
+    /// Starts the background thread handing every record to `consume`
+    fn spawn(capacity: usize, mut consume: impl FnMut(Record) + Send + 'static) -> Self {
+        let (sender, receiver) = sync_channel::<Record>(capacity);
+        let spawn_result = thread::Builder::new()
+            .name("event-log".to_string())
+            .spawn(move || {
+                for record in receiver {
+                    consume(record);
+                }
+            });
+        if let Err(err) = spawn_result {
+            warn!("Failed to start event log thread, hot path logging is disabled: {err}");
+        }
+        Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub(crate) fn record(&self, record: Record) {
+        let level = match record.kind {
+            RecordKind::NoteOn => Level::Info,
+            RecordKind::NoteOff => Level::Debug,
+        };
+        if log_enabled!(level) {
+            self.send(record);
+        }
+    }
+
+    fn send(&self, record: Record) {
+        if self.sender.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn format_record(record: &Record) {
+    match record.kind {
+        RecordKind::NoteOn => info!("{}", describe(record)),
+        RecordKind::NoteOff => debug!("{}", describe(record)),
+    }
+}
+
+fn describe(record: &Record) -> String {
+    let key = keynames::name_of(&record.key, NamingScheme::default());
+    let note = notenames::name_of(record.note, record.middle_c);
+    match record.kind {
+        RecordKind::NoteOn => format!(
+            "[{}] {} triggered note {} with velocity {:.3} after {:?}",
+            record.tick, key, note, record.value, record.press_duration
+        ),
+        RecordKind::NoteOff => format!(
+            "[{}] {} released note {} with velocity {:.3}",
+            record.tick, key, note, record.value
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn note_on(tick: u64) -> Record {
+        Record {
+            key: HIDCodes::A,
+            kind: RecordKind::NoteOn,
+            note: 61,
+            middle_c: MiddleC::C4,
+            value: 0.5,
+            press_duration: Some(Duration::from_millis(8)),
+            tick,
+        }
+    }
+
+    #[test]
+    fn records_are_described() {
+        assert_eq!(
+            describe(&note_on(7)),
+            "[7] A triggered note C#4 with velocity 0.500 after Some(8ms)"
+        );
+        let note_off = Record {
+            kind: RecordKind::NoteOff,
+            middle_c: MiddleC::C3,
+            ..note_on(8)
+        };
+        assert_eq!(
+            describe(&note_off),
+            "[8] A released note C#3 with velocity 0.500"
+        );
+    }
+
+    #[test]
+    fn records_are_dropped_instead_of_blocking() {
+        let (received, receiver) = channel();
+        let (release, blocked) = channel::<()>();
+        let log = EventLog::spawn(1, move |record| {
+            let _ = blocked.recv();
+            received.send(record.tick).unwrap();
+        });
+        for tick in 0..4 {
+            log.send(note_on(tick));
+        }
+        let dropped = log.dropped();
+        assert!(dropped >= 2, "{} dropped", dropped);
+
+        drop(release);
+        drop(log);
+        let ticks: Vec<u64> = receiver.iter().collect();
+        assert_eq!(ticks.len() as u64 + dropped, 4);
+        assert_eq!(ticks[0], 0);
+    }
+}
@@ -0,0 +1,331 @@
+use wooting_analog_wrapper::{FromPrimitive, HIDCodes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamingScheme {
+    #[default]
+    UsAnsi,
+    IsoUk,
+    De,
+}
+
+const ALIASES: &[(&str, HIDCodes)] = &[
+    ("Esc", HIDCodes::Escape),
+    ("Return", HIDCodes::Enter),
+    ("Back", HIDCodes::Backspace),
+    ("Spacebar", HIDCodes::Space),
+    ("Caps", HIDCodes::CapsLock),
+    ("Grave", HIDCodes::Backquote),
+    ("Backtick", HIDCodes::Backquote),
+    ("Ins", HIDCodes::Insert),
+    ("Del", HIDCodes::Delete),
+    ("PgUp", HIDCodes::PageUp),
+    ("PgDn", HIDCodes::PageDown),
+    ("PageDn", HIDCodes::PageDown),
+    ("Left", HIDCodes::ArrowLeft),
+    ("Right", HIDCodes::ArrowRight),
+    ("Up", HIDCodes::ArrowUp),
+    ("Down", HIDCodes::ArrowDown),
+    ("PrtSc", HIDCodes::PrintScreen),
+    ("Print", HIDCodes::PrintScreen),
+    ("Pause", HIDCodes::PauseBreak),
+    ("Break", HIDCodes::PauseBreak),
+    ("Menu", HIDCodes::ContextMenu),
+    ("Apps", HIDCodes::ContextMenu),
+    ("Mute", HIDCodes::VolumeMute),
+    ("Ctrl", HIDCodes::LeftCtrl),
+    ("Control", HIDCodes::LeftCtrl),
+    ("Shift", HIDCodes::LeftShift),
+    ("Alt", HIDCodes::LeftAlt),
+    ("AltGr", HIDCodes::RightAlt),
+    ("Win", HIDCodes::LeftMeta),
+    ("Super", HIDCodes::LeftMeta),
+    ("Cmd", HIDCodes::LeftMeta),
+    ("Meta", HIDCodes::LeftMeta),
+    ("RightWin", HIDCodes::RightMeta),
+    ("RightSuper", HIDCodes::RightMeta),
+    ("IntlBackslash", HIDCodes::InternationalBackslash),
+    ("IntlRo", HIDCodes::InternationalRO),
+    ("IntlYen", HIDCodes::InternationalYen),
+];
+
+/// Iterates over every HID code known to the SDK
+pub fn all_codes() -> impl Iterator<Item = HIDCodes> {
+    (0..=u8::MAX as u16).filter_map(HIDCodes::from_u16)
+}
+
+pub fn name_of(code: &HIDCodes, scheme: NamingScheme) -> &'static str {
+    let regional = match scheme {
+        NamingScheme::UsAnsi => None,
+        NamingScheme::IsoUk => iso_uk_name(code),
+        NamingScheme::De => de_name(code),
+    };
+    regional.unwrap_or_else(|| us_ansi_name(code))
+}
+
+/// Parses a key name using the US-ANSI naming scheme
+pub fn parse(name: &str) -> Option<HIDCodes> {
+    parse_in(name, NamingScheme::UsAnsi)
+}
+
+/// Parses a key name, case-insensitive. Names of the given scheme take precedence, followed by
+/// the SDK variant names (e.g. "BracketLeft"), common aliases and finally the US-ANSI names.
+pub fn parse_in(name: &str, scheme: NamingScheme) -> Option<HIDCodes> {
+    let name = name.trim();
+    let matches = |candidate: &str| candidate.to_lowercase() == name.to_lowercase();
+
+    all_codes()
+        .find(|code| matches(name_of(code, scheme)))
+        .or_else(|| all_codes().find(|code| matches(&format!("{:?}", code))))
+        .or_else(|| {
+            ALIASES
+                .iter()
+                .find(|(alias, _)| matches(alias))
+                .map(|(_, code)| code.clone())
+        })
+        .or_else(|| all_codes().find(|code| matches(us_ansi_name(code))))
+}
+
+fn iso_uk_name(code: &HIDCodes) -> Option<&'static str> {
+    Some(match code {
+        HIDCodes::Backslash => "#",
+        HIDCodes::InternationalBackslash => "\\",
+        _ => return None,
+    })
+}
+
+fn de_name(code: &HIDCodes) -> Option<&'static str> {
+    Some(match code {
+        HIDCodes::Y => "Z",
+        HIDCodes::Z => "Y",
+        HIDCodes::Minus => "ß",
+        HIDCodes::Equal => "´",
+        HIDCodes::BracketLeft => "Ü",
+        HIDCodes::BracketRight => "+",
+        HIDCodes::Backslash => "#",
+        HIDCodes::Semicolon => "Ö",
+        HIDCodes::Quote => "Ä",
+        HIDCodes::Backquote => "^",
+        HIDCodes::Slash => "-",
+        HIDCodes::InternationalBackslash => "<",
+        _ => return None,
+    })
+}
+
+fn us_ansi_name(code: &HIDCodes) -> &'static str {
+    match code {
+        HIDCodes::A => "A",
+        HIDCodes::B => "B",
+        HIDCodes::C => "C",
+        HIDCodes::D => "D",
+        HIDCodes::E => "E",
+        HIDCodes::F => "F",
+        HIDCodes::G => "G",
+        HIDCodes::H => "H",
+        HIDCodes::I => "I",
+        HIDCodes::J => "J",
+        HIDCodes::K => "K",
+        HIDCodes::L => "L",
+        HIDCodes::M => "M",
+        HIDCodes::N => "N",
+        HIDCodes::O => "O",
+        HIDCodes::P => "P",
+        HIDCodes::Q => "Q",
+        HIDCodes::R => "R",
+        HIDCodes::S => "S",
+        HIDCodes::T => "T",
+        HIDCodes::U => "U",
+        HIDCodes::V => "V",
+        HIDCodes::W => "W",
+        HIDCodes::X => "X",
+        HIDCodes::Y => "Y",
+        HIDCodes::Z => "Z",
+        HIDCodes::N1 => "1",
+        HIDCodes::N2 => "2",
+        HIDCodes::N3 => "3",
+        HIDCodes::N4 => "4",
+        HIDCodes::N5 => "5",
+        HIDCodes::N6 => "6",
+        HIDCodes::N7 => "7",
+        HIDCodes::N8 => "8",
+        HIDCodes::N9 => "9",
+        HIDCodes::N0 => "0",
+        HIDCodes::Enter => "Enter",
+        HIDCodes::Escape => "Escape",
+        HIDCodes::Backspace => "Backspace",
+        HIDCodes::Tab => "Tab",
+        HIDCodes::Space => "Space",
+        HIDCodes::Minus => "-",
+        HIDCodes::Equal => "=",
+        HIDCodes::BracketLeft => "[",
+        HIDCodes::BracketRight => "]",
+        HIDCodes::Backslash => "\\",
+        HIDCodes::Semicolon => ";",
+        HIDCodes::Quote => "'",
+        HIDCodes::Backquote => "`",
+        HIDCodes::Comma => ",",
+        HIDCodes::Period => ".",
+        HIDCodes::Slash => "/",
+        HIDCodes::CapsLock => "CapsLock",
+        HIDCodes::F1 => "F1",
+        HIDCodes::F2 => "F2",
+        HIDCodes::F3 => "F3",
+        HIDCodes::F4 => "F4",
+        HIDCodes::F5 => "F5",
+        HIDCodes::F6 => "F6",
+        HIDCodes::F7 => "F7",
+        HIDCodes::F8 => "F8",
+        HIDCodes::F9 => "F9",
+        HIDCodes::F10 => "F10",
+        HIDCodes::F11 => "F11",
+        HIDCodes::F12 => "F12",
+        HIDCodes::PrintScreen => "PrintScreen",
+        HIDCodes::ScrollLock => "ScrollLock",
+        HIDCodes::PauseBreak => "PauseBreak",
+        HIDCodes::Insert => "Insert",
+        HIDCodes::Home => "Home",
+        HIDCodes::PageUp => "PageUp",
+        HIDCodes::Delete => "Delete",
+        HIDCodes::End => "End",
+        HIDCodes::PageDown => "PageDown",
+        HIDCodes::ArrowRight => "ArrowRight",
+        HIDCodes::ArrowLeft => "ArrowLeft",
+        HIDCodes::ArrowDown => "ArrowDown",
+        HIDCodes::ArrowUp => "ArrowUp",
+        HIDCodes::NumLock => "NumLock",
+        HIDCodes::NumpadDivide => "Numpad/",
+        HIDCodes::NumpadMultiply => "Numpad*",
+        HIDCodes::NumpadSubtract => "Numpad-",
+        HIDCodes::NumpadAdd => "Numpad+",
+        HIDCodes::NumpadEnter => "NumpadEnter",
+        HIDCodes::Numpad1 => "Numpad1",
+        HIDCodes::Numpad2 => "Numpad2",
+        HIDCodes::Numpad3 => "Numpad3",
+        HIDCodes::Numpad4 => "Numpad4",
+        HIDCodes::Numpad5 => "Numpad5",
+        HIDCodes::Numpad6 => "Numpad6",
+        HIDCodes::Numpad7 => "Numpad7",
+        HIDCodes::Numpad8 => "Numpad8",
+        HIDCodes::Numpad9 => "Numpad9",
+        HIDCodes::Numpad0 => "Numpad0",
+        HIDCodes::NumpadDecimal => "Numpad.",
+        HIDCodes::InternationalBackslash => "IntlBackslash",
+        HIDCodes::ContextMenu => "ContextMenu",
+        HIDCodes::Power => "Power",
+        HIDCodes::NumpadEqual => "Numpad=",
+        HIDCodes::F13 => "F13",
+        HIDCodes::F14 => "F14",
+        HIDCodes::F15 => "F15",
+        HIDCodes::F16 => "F16",
+        HIDCodes::F17 => "F17",
+        HIDCodes::F18 => "F18",
+        HIDCodes::F19 => "F19",
+        HIDCodes::F20 => "F20",
+        HIDCodes::F21 => "F21",
+        HIDCodes::F22 => "F22",
+        HIDCodes::F23 => "F23",
+        HIDCodes::F24 => "F24",
+        HIDCodes::Open => "Open",
+        HIDCodes::Help => "Help",
+        HIDCodes::Again => "Again",
+        HIDCodes::Undo => "Undo",
+        HIDCodes::Cut => "Cut",
+        HIDCodes::Copy => "Copy",
+        HIDCodes::Paste => "Paste",
+        HIDCodes::Find => "Find",
+        HIDCodes::VolumeMute => "VolumeMute",
+        HIDCodes::VolumeUp => "VolumeUp",
+        HIDCodes::VolumeDown => "VolumeDown",
+        HIDCodes::NumpadComma => "Numpad,",
+        HIDCodes::InternationalRO => "IntlRo",
+        HIDCodes::KanaMode => "KanaMode",
+        HIDCodes::InternationalYen => "IntlYen",
+        HIDCodes::Convert => "Convert",
+        HIDCodes::NonConvert => "NonConvert",
+        HIDCodes::Lang1 => "Lang1",
+        HIDCodes::Lang2 => "Lang2",
+        HIDCodes::Lang3 => "Lang3",
+        HIDCodes::Lang4 => "Lang4",
+        HIDCodes::LeftCtrl => "LeftCtrl",
+        HIDCodes::LeftShift => "LeftShift",
+        HIDCodes::LeftAlt => "LeftAlt",
+        HIDCodes::LeftMeta => "LeftMeta",
+        HIDCodes::RightCtrl => "RightCtrl",
+        HIDCodes::RightShift => "RightShift",
+        HIDCodes::RightAlt => "RightAlt",
+        HIDCodes::RightMeta => "RightMeta",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMES: [NamingScheme; 3] =
+        [NamingScheme::UsAnsi, NamingScheme::IsoUk, NamingScheme::De];
+
+    #[test]
+    fn every_name_parses_back() {
+        for scheme in SCHEMES {
+            for code in all_codes() {
+                let name = name_of(&code, scheme);
+                assert_eq!(
+                    parse_in(name, scheme),
+                    Some(code.clone()),
+                    "{:?} named {:?} in {:?}",
+                    code,
+                    name,
+                    scheme
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn aliases_parse() {
+        for (alias, code) in ALIASES {
+            assert_eq!(parse(alias), Some(code.clone()), "{}", alias);
+            assert_eq!(
+                parse(&alias.to_uppercase()),
+                Some(code.clone()),
+                "{}",
+                alias
+            );
+        }
+    }
+
+    #[test]
+    fn sdk_names_parse() {
+        for code in all_codes() {
+            let name = format!("{:?}", code);
+            assert_eq!(parse(&name), Some(code.clone()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parsing_is_lenient() {
+        assert_eq!(parse("  escape "), Some(HIDCodes::Escape));
+        assert_eq!(parse("numpadenter"), Some(HIDCodes::NumpadEnter));
+        assert_eq!(parse("altgr"), Some(HIDCodes::RightAlt));
+        assert_eq!(parse("NotAKey"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn scheme_names_take_precedence() {
+        assert_eq!(parse_in("Z", NamingScheme::De), Some(HIDCodes::Y));
+        assert_eq!(parse_in("-", NamingScheme::De), Some(HIDCodes::Slash));
+        assert_eq!(parse_in("ü", NamingScheme::De), Some(HIDCodes::BracketLeft));
+        assert_eq!(
+            parse_in("#", NamingScheme::IsoUk),
+            Some(HIDCodes::Backslash)
+        );
+        assert_eq!(
+            parse_in("\\", NamingScheme::IsoUk),
+            Some(HIDCodes::InternationalBackslash)
+        );
+        // Keys the scheme does not rename fall back to the US-ANSI names
+        assert_eq!(parse_in("[", NamingScheme::De), Some(HIDCodes::BracketLeft));
+        assert_eq!(parse_in("Esc", NamingScheme::De), Some(HIDCodes::Escape));
+        assert_eq!(parse_in("Z", NamingScheme::UsAnsi), Some(HIDCodes::Z));
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config::KeyConfig;
+use crate::NoteID;
+use rustc_hash::FxHashMap;
+use wooting_analog_wrapper::HIDCodes;
+
+/// Rows of the alphanumeric block of a US-ANSI keyboard, each including its punctuation keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRow {
+    Number,
+    Top,
+    Home,
+    Bottom,
+}
+
+impl KeyRow {
+    pub fn keys(self) -> &'static [HIDCodes] {
+        match self {
+            KeyRow::Number => &[
+                HIDCodes::N1,
+                HIDCodes::N2,
+                HIDCodes::N3,
+                HIDCodes::N4,
+                HIDCodes::N5,
+                HIDCodes::N6,
+                HIDCodes::N7,
+                HIDCodes::N8,
+                HIDCodes::N9,
+                HIDCodes::N0,
+                HIDCodes::Minus,
+                HIDCodes::Equal,
+            ],
+            KeyRow::Top => &[
+                HIDCodes::Q,
+                HIDCodes::W,
+                HIDCodes::E,
+                HIDCodes::R,
+                HIDCodes::T,
+                HIDCodes::Y,
+                HIDCodes::U,
+                HIDCodes::I,
+                HIDCodes::O,
+                HIDCodes::P,
+                HIDCodes::BracketLeft,
+                HIDCodes::BracketRight,
+            ],
+            KeyRow::Home => &[
+                HIDCodes::A,
+                HIDCodes::S,
+                HIDCodes::D,
+                HIDCodes::F,
+                HIDCodes::G,
+                HIDCodes::H,
+                HIDCodes::J,
+                HIDCodes::K,
+                HIDCodes::L,
+                HIDCodes::Semicolon,
+                HIDCodes::Quote,
+            ],
+            KeyRow::Bottom => &[
+                HIDCodes::Z,
+                HIDCodes::X,
+                HIDCodes::C,
+                HIDCodes::V,
+                HIDCodes::B,
+                HIDCodes::N,
+                HIDCodes::M,
+                HIDCodes::Comma,
+                HIDCodes::Period,
+                HIDCodes::Slash,
+            ],
+        }
+    }
+}
+
+/// Layouts where every interval has the same shape wherever it is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsomorphicLayout {
+    /// Whole tones along a row, fourths and fifths to the keys above
+    WickiHayden,
+    /// Major thirds along a row, minor thirds and fifths to the keys above
+    HarmonicTable,
+}
+
+impl IsomorphicLayout {
+    /// Semitones to the next key of a row and to the key up and to the left
+    fn steps(self) -> (i16, i16) {
+        match self {
+            IsomorphicLayout::WickiHayden => (2, 5),
+            IsomorphicLayout::HarmonicTable => (4, 3),
+        }
+    }
+}
+
+/// Keys of the home row play the white keys and keys of the top row the black keys in between,
+/// like a piano keyboard starting at the A key. `starting_note` is the note of the A key and
+/// should be a C for the colors to line up.
+pub fn piano_layout(starting_note: NoteID) -> FxHashMap<HIDCodes, KeyConfig> {
+    let keys = [
+        (HIDCodes::A, 0),
+        (HIDCodes::W, 1),
+        (HIDCodes::S, 2),
+        (HIDCodes::E, 3),
+        (HIDCodes::D, 4),
+        (HIDCodes::F, 5),
+        (HIDCodes::T, 6),
+        (HIDCodes::G, 7),
+        (HIDCodes::Y, 8),
+        (HIDCodes::H, 9),
+        (HIDCodes::U, 10),
+        (HIDCodes::J, 11),
+        (HIDCodes::K, 12),
+        (HIDCodes::O, 13),
+        (HIDCodes::L, 14),
+        (HIDCodes::P, 15),
+        (HIDCodes::Semicolon, 16),
+        (HIDCodes::Quote, 17),
+    ];
+    layout(
+        keys.into_iter()
+            .map(|(hid_code, offset)| (hid_code, starting_note as i16 + offset)),
+    )
+}
+
+/// Consecutive notes across a row from left to right
+pub fn chromatic_strip(row: KeyRow, starting_note: NoteID) -> FxHashMap<HIDCodes, KeyConfig> {
+    layout(
+        row.keys()
+            .iter()
+            .zip(starting_note as i16..)
+            .map(|(hid_code, note)| (hid_code.clone(), note)),
+    )
+}
+
+/// Isomorphic layout across all four rows of the alphanumeric block, with `root` on the Z key.
+/// Each row is staggered half a key to the left of the one below it.
+pub fn isomorphic_layout(kind: IsomorphicLayout, root: NoteID) -> FxHashMap<HIDCodes, KeyConfig> {
+    let (along_row, up_left) = kind.steps();
+    let rows = [KeyRow::Bottom, KeyRow::Home, KeyRow::Top, KeyRow::Number];
+    layout(rows.into_iter().zip(0..).flat_map(|(row, row_index)| {
+        row.keys().iter().zip(0..).map(move |(hid_code, column)| {
+            let note = root as i16 + row_index * up_left + column * along_row;
+            (hid_code.clone(), note)
+        })
+    }))
+}
+
+/// Key configs playing the given notes, keys whose note is outside the MIDI range are left out
+fn layout(notes: impl Iterator<Item = (HIDCodes, i16)>) -> FxHashMap<HIDCodes, KeyConfig> {
+    notes
+        .filter(|&(_, note)| (0..=127).contains(&note))
+        .map(|(hid_code, note)| {
+            let key_config = KeyConfig {
+                note_id: note as NoteID,
+                ..Default::default()
+            };
+            (hid_code, key_config)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(layout: &FxHashMap<HIDCodes, KeyConfig>, hid_code: HIDCodes) -> NoteID {
+        layout[&hid_code].note_id
+    }
+
+    #[test]
+    fn piano_layout_puts_black_keys_on_the_top_row() {
+        let layout = piano_layout(60);
+        assert_eq!(layout.len(), 18);
+        assert_eq!(note(&layout, HIDCodes::A), 60);
+        assert_eq!(note(&layout, HIDCodes::W), 61);
+        assert_eq!(note(&layout, HIDCodes::D), 64);
+        assert_eq!(note(&layout, HIDCodes::F), 65);
+        assert_eq!(note(&layout, HIDCodes::K), 72);
+        assert!(!layout.contains_key(&HIDCodes::R));
+    }
+
+    #[test]
+    fn chromatic_strips_leave_out_notes_past_the_range() {
+        let layout = chromatic_strip(KeyRow::Top, 48);
+        assert_eq!(layout.len(), 12);
+        assert_eq!(note(&layout, HIDCodes::Q), 48);
+        assert_eq!(note(&layout, HIDCodes::BracketRight), 59);
+
+        let layout = chromatic_strip(KeyRow::Top, 120);
+        assert_eq!(layout.len(), 8);
+        assert_eq!(note(&layout, HIDCodes::I), 127);
+        assert!(!layout.contains_key(&HIDCodes::O));
+    }
+
+    #[test]
+    fn isomorphic_layouts_step_by_their_intervals() {
+        let layout = isomorphic_layout(IsomorphicLayout::WickiHayden, 48);
+        assert_eq!(note(&layout, HIDCodes::Z), 48);
+        assert_eq!(note(&layout, HIDCodes::X), 50);
+        assert_eq!(note(&layout, HIDCodes::A), 53);
+        assert_eq!(note(&layout, HIDCodes::Q), 58);
+        assert_eq!(note(&layout, HIDCodes::N1), 63);
+
+        let layout = isomorphic_layout(IsomorphicLayout::HarmonicTable, 48);
+        assert_eq!(note(&layout, HIDCodes::X), 52);
+        assert_eq!(note(&layout, HIDCodes::A), 51);
+        assert_eq!(note(&layout, HIDCodes::S), 55);
+    }
+
+    #[test]
+    fn isomorphic_layouts_keep_interval_shapes() {
+        let layout = isomorphic_layout(IsomorphicLayout::WickiHayden, 36);
+        for row in [KeyRow::Bottom, KeyRow::Home, KeyRow::Top, KeyRow::Number] {
+            for pair in row.keys().windows(2) {
+                let step = note(&layout, pair[1].clone()) - note(&layout, pair[0].clone());
+                assert_eq!(step, 2, "{:?}", row);
+            }
+        }
+    }
+}
@@ -1,37 +1,188 @@
+mod arp;
+mod clock;
+mod clock_input;
 pub mod config;
+mod config_builder;
+mod config_file;
+mod event_log;
+pub mod keynames;
+mod layouts;
+mod mono;
+mod mpe;
 pub mod note;
+pub mod notenames;
+mod output;
+mod shared_notes;
+pub mod tuning;
+#[cfg(feature = "midi2")]
+pub mod ump;
+mod validation;
+mod voices;
 
 use anyhow::{anyhow, bail, Context, Result};
-use config::{Config, KeyConfig};
-use log::{info, trace};
-use midir::{MidiOutput, MidiOutputConnection, MidiOutputPort};
-use note::{NoteSink, MIDI_NOTE_MAX, MIDI_NOTE_MIN};
-use rustc_hash::FxHashMap;
+use arp::{ArpSink, Arpeggiator};
+use clock::MidiClock;
+use clock_input::ClockInput;
+use config::{
+    AftertouchMode, AftertouchResponse, BendDirection, ChannelSetup, Config, EnableState,
+    InputSmoothing, KeyConfig, NotePool, NoteRepeatConfig, PreTouch, PressureAggregate,
+    QuantizedRelease, RapidTriggerConfig, ShiftOutOfRange, TransportStart, VelocityEstimation,
+    VibratoConfig, VibratoTarget, CONFIG_VERSION,
+};
+use event_log::{EventLog, Record, RecordKind};
+use keynames::NamingScheme;
+use log::{info, trace, warn};
+use midir::{MidiOutput, MidiOutputPort};
+use mono::{MonoSink, MonoVoices};
+use mpe::{send_mpe_configuration, ChannelAllocator, MpeSink};
+use note::{
+    unit_to_byte, CountingSink, NoteSink, CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_BANK_SELECT_LSB,
+    CC_BANK_SELECT_MSB, CC_MOD_WHEEL, CC_SUSTAIN, CLOCK_CONTINUE_MSG, CLOCK_START_MSG,
+    CLOCK_STOP_MSG, MIDI_NOTE_MAX, MIDI_NOTE_MIN, RPN_PITCH_BEND_RANGE,
+};
+use output::{MidiBuffer, Output};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rustc_hash::{FxHashMap, FxHashSet};
 use sdk::SDKResult;
 pub use sdk::{DeviceInfo, FromPrimitive, HIDCodes, ToPrimitive, WootingAnalogResult};
-use std::collections::HashMap;
-use std::time::Instant;
+use serde::Serialize;
+use shared_notes::{SharedNoteSink, SharedNotes};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tuning::TuningSink;
+use voices::{VoiceLimitSink, VoiceLimiter};
 use wooting_analog_wrapper as sdk;
 
 pub const REFRESH_RATE: f32 = 200.0; //Hz
-const AFTERTOUCH: bool = true;
 
 const MIDI_CLIENT_NAME: &str = "Wooting Analog MIDI Output";
 const MIDI_PORT_NAME: &str = "wooting-analog-midi";
 
+pub(crate) const TRANSPOSE_MAX: i8 = 48;
+
+const THRESHOLD_NUDGE_STEP: f32 = 0.02;
+const THRESHOLD_NUDGE_MAX: f32 = 0.3;
+
+const POLL_HOOK_BUDGET: Duration = Duration::from_micros(500);
+
+// Travel a key has to cover during range calibration to count as fully pressed
+const CALIBRATION_MIN_TRAVEL: f32 = 0.5;
+
+// Samples of a press the least squares velocity is fitted to
+const VELOCITY_SAMPLES: usize = 12;
+
+// Recent depths the center of a wiggle is averaged over, longer than a slow vibrato period
+const VIBRATO_WINDOW: Duration = Duration::from_millis(250);
+// Time for the vibrato to fade to a third once the wiggling stops
+const VIBRATO_RELEASE_SECS: f32 = 0.2;
+
 const DEVICE_BUFFER_MAX: usize = 5;
 const ANALOG_BUFFER_READ_MAX: usize = 40;
 
 pub type NoteID = u8;
+/// Zero-based MIDI channel, 0-15 corresponds to channels 1-16 as shown by most DAWs
 pub type Channel = u8;
 
+/// Information about the current tick handed to poll hooks
+#[derive(Debug, Clone)]
+pub struct PollContext {
+    pub timestamp: Instant,
+    pub tick: u64,
+    pub enable_state: EnableState,
+    /// Number of MIDI messages sent during this tick, always 0 for pre-poll hooks
+    pub events_emitted: usize,
+}
+
+/// Copy of what the engine currently sees of a configured key, e.g. for visualizers
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyStateSnapshot {
+    #[serde(serialize_with = "config_file::key::serialize")]
+    pub hid_code: HIDCodes,
+    /// Calibrated depth of the last poll
+    pub value: f32,
+    pub pressed: bool,
+    /// Velocity of the last note on
+    pub velocity: f32,
+    /// Note sounding for the key, or latched by it
+    pub note: Option<NoteID>,
+}
+
+/// Hooks run on the polling thread and should return well within `POLL_HOOK_BUDGET`
+pub type PollHook = Box<dyn FnMut(&PollContext) + Send>;
+
+/// Service wide state a key update depends on
+#[derive(Debug, Clone, Copy)]
+struct KeyContext {
+    shifted_amount: i8,
+    threshold_delta: f32,
+    /// Next grid point of the external clock while note ons are quantized
+    quantize_to: Option<Instant>,
+    /// Time of the poll, every timestamp the update records or compares against
+    now: Instant,
+}
+
 #[derive(Debug)]
 struct KeyState {
     pressed: bool,
     shifted_amount: i8,
-    velocity: f32,
+    // Measured velocity before curve and gain, not clamped yet
+    raw_velocity: f32,
     current_value: f32,
+    // Previous two raw readings, newest first
+    raw_history: [f32; 2],
     lower_press: Option<(Instant, f32)>,
+    // Samples since `lower_press`, oldest first and at most `VELOCITY_SAMPLES`
+    press_samples: VecDeque<(Instant, f32)>,
+    // End of the depth velocity window and the deepest point within it
+    depth_window: Option<(Instant, f32)>,
+    // Time of the previous update, crossings are interpolated between it and the current one
+    updated_at: Option<Instant>,
+    // Last upward crossing of the actuation point
+    actuated_at: Option<Instant>,
+    // Fastest speed between consecutive samples since `lower_press`
+    peak_speed: Option<f32>,
+    release_start: Option<(Instant, f32)>,
+    release_velocity: Option<f32>,
+    sounding_note: Option<NoteID>,
+    second_sounding_note: Option<NoteID>,
+    latched_note: Option<NoteID>,
+    // Notes sounding along with the sounding or latched note
+    chord_notes: Vec<NoteID>,
+    // Chord notes that have started, the rest is still waiting on the strum
+    chord_sent: usize,
+    strum_start: Option<Instant>,
+    press_velocity: f32,
+    repeat_at: Option<Instant>,
+    pending_note_on: Option<(Instant, f32)>,
+    blip_if_cancelled: bool,
+    // A pending note on may only start once the press qualified by length or depth
+    unqualified_until: Option<Instant>,
+    aftertouch_value: f32,
+    last_aftertouch: Option<Instant>,
+    smoothed_pressure: f32,
+    pressure_updated: Option<Instant>,
+    pre_touch_value: f32,
+    // Depths of the held key within `VIBRATO_WINDOW`, oldest first
+    vibrato_history: VecDeque<(Instant, f32)>,
+    vibrato_amplitude: f32,
+    vibrato_value: f32,
+    rising_ticks: u8,
+    early_released: bool,
+    velocity_gated: bool,
+    // Deepest point while pressed or highest one while released, once rapid trigger is armed
+    rapid_extreme: Option<f32>,
+    // A bounce right after a release, silent until the key leaves the threshold
+    debounced: bool,
+    pressed_at: Option<Instant>,
+    released_at: Option<Instant>,
+    soft_hold_since: Option<Instant>,
+    soft_hold_cancelled: bool,
+    soft_hold_sounding: bool,
+    out_of_range_logged: bool,
+    threshold_delta: f32,
+    pool_rng: Option<SmallRng>,
+    humanize_rng: Option<SmallRng>,
+    last_pool_note: Option<NoteID>,
 }
 
 impl KeyState {
@@ -39,259 +190,4954 @@ impl KeyState {
         Self {
             pressed: false,
             shifted_amount: 0,
-            velocity: 0.0,
+            raw_velocity: 0.0,
             current_value: 0.0,
+            raw_history: [0.0; 2],
             lower_press: None,
+            press_samples: VecDeque::new(),
+            actuated_at: None,
+            depth_window: None,
+            updated_at: None,
+            peak_speed: None,
+            release_start: None,
+            release_velocity: None,
+            sounding_note: None,
+            second_sounding_note: None,
+            latched_note: None,
+            chord_notes: Vec::new(),
+            chord_sent: 0,
+            strum_start: None,
+            press_velocity: 0.0,
+            repeat_at: None,
+            pending_note_on: None,
+            blip_if_cancelled: false,
+            unqualified_until: None,
+            aftertouch_value: 0.0,
+            last_aftertouch: None,
+            smoothed_pressure: 0.0,
+            pressure_updated: None,
+            pre_touch_value: 0.0,
+            vibrato_history: VecDeque::new(),
+            vibrato_amplitude: 0.0,
+            vibrato_value: 0.0,
+            rising_ticks: 0,
+            early_released: false,
+            velocity_gated: false,
+            rapid_extreme: None,
+            debounced: false,
+            pressed_at: None,
+            released_at: None,
+            soft_hold_since: None,
+            soft_hold_cancelled: false,
+            soft_hold_sounding: false,
+            out_of_range_logged: false,
+            threshold_delta: 0.0,
+            pool_rng: None,
+            humanize_rng: None,
+            last_pool_note: None,
         }
     }
 
     fn update_value(
         &mut self,
+        config: &Config,
         key_config: &KeyConfig,
         new_value: f32,
         sink: &mut impl NoteSink,
-        shifted_amount: i8,
+        context: KeyContext,
     ) -> Result<()> {
-        if (self.current_value <= key_config.actuation_point
-            && new_value > key_config.actuation_point
-            && new_value < key_config.threshold)
-            || new_value <= key_config.actuation_point
-        {
-            self.lower_press = Some((Instant::now(), new_value));
-            self.velocity = 0.0;
-        } else if let Some((prev_time, prev_depth)) = self.lower_press {
-            let duration = prev_time.elapsed().as_secs_f32();
-            self.velocity = if new_value != prev_depth {
-                ((new_value - prev_depth) / duration * key_config.velocity_scale / 100.0)
-                    .clamp(0.0, 1.0)
-            } else {
-                0.0
-            };
-            if (prev_depth - new_value).abs() < 0.01 || new_value < self.current_value - 0.01 {
-                self.lower_press = Some((Instant::now(), new_value));
-            }
+        // Like shifts, nudges only take effect while the key is up so held notes are unaffected
+        if !self.pressed {
+            self.threshold_delta = context.threshold_delta;
         }
+        let threshold = key_config.nudged_threshold(self.threshold_delta);
+        let release_threshold = key_config.nudged_release_threshold(self.threshold_delta);
+        let now = context.now;
+        let previous_update = self.updated_at.replace(now);
 
-        if shifted_amount != self.shifted_amount && !self.pressed {
-            self.shifted_amount = shifted_amount;
+        self.update_velocity(key_config, new_value, threshold, previous_update, now);
+        if self.pressed {
+            self.update_release_velocity(key_config, new_value, now);
         }
 
-        if let Some(effective_note) = self.get_effective_note(key_config.note_id) {
-            if new_value > key_config.threshold {
-                if !self.pressed {
-                    info!(
-                        "Triggering with velocity {:.3}, prev {:?}, new_val {:?}, elapsed {:?}",
-                        self.velocity,
-                        self.lower_press,
-                        new_value,
-                        self.lower_press.unwrap().0.elapsed()
-                    );
-                    sink.note_on(effective_note, self.velocity, key_config.channel)?;
-                    self.pressed = true;
-                } else if AFTERTOUCH && new_value != self.current_value {
-                    sink.polyphonic_aftertouch(effective_note, new_value, key_config.channel)?;
-                }
+        if context.shifted_amount != self.shifted_amount {
+            if !self.pressed {
+                self.shifted_amount = context.shifted_amount;
+            } else if key_config.retrigger_on_shift && key_config.note_pool.is_none() {
+                self.shifted_amount = context.shifted_amount;
+                self.retrigger_shifted(config, key_config, new_value, threshold, now, sink)?;
+            }
+        }
+
+        self.send_pending_note_on(config, key_config, new_value, now, sink)?;
+        self.update_strum(config, key_config, now, sink)?;
+        self.update_soft_hold(config, key_config, new_value, threshold, now, sink)?;
+        self.update_early_release(key_config, new_value, threshold, now, sink)?;
+
+        if self.is_held(key_config, new_value, threshold, release_threshold, now) {
+            if !self.pressed && !self.early_released && !self.velocity_gated && !self.debounced {
+                self.press(config, key_config, new_value, threshold, context, sink)?;
             } else if self.pressed {
-                sink.note_off(effective_note, self.velocity, key_config.channel)?;
-                self.pressed = false;
+                self.repeat_note(config, key_config, new_value, threshold, now, sink)?;
+                self.update_aftertouch(config, key_config, new_value, threshold, now, sink)?;
+            }
+        } else {
+            self.velocity_gated = false;
+            self.debounced = false;
+            let min_note = Duration::from_millis(key_config.min_note_ms as u64);
+            if self.pressed
+                && self
+                    .pressed_at
+                    .is_none_or(|pressed_at| now - pressed_at >= min_note)
+            {
+                self.release(key_config, now, sink)?;
             }
         }
 
+        if self.pressed {
+            self.update_second_note(config, key_config, new_value, sink)?;
+        }
+
+        if let Some(vibrato) = &key_config.vibrato {
+            self.update_vibrato(vibrato, key_config.channel, new_value, now, sink)?;
+        }
+
+        if let Some(pre_touch) = key_config.pre_touch {
+            self.update_pre_touch(config, key_config, pre_touch, new_value, sink)?;
+        }
+
         self.current_value = new_value;
         Ok(())
     }
 
-    fn get_effective_note(&self, base_note: NoteID) -> Option<NoteID> {
-        let computed = base_note as i16 + self.shifted_amount as i16;
-        if computed >= MIDI_NOTE_MIN.into() && computed <= MIDI_NOTE_MAX.into() {
-            Some(computed as NoteID)
-        } else {
-            None
+    /// When the key passed `level` since the previous poll, assuming it moved at a constant speed
+    fn crossing_time(
+        &self,
+        level: f32,
+        new_value: f32,
+        previous_update: Option<Instant>,
+        now: Instant,
+    ) -> Instant {
+        match previous_update {
+            Some(previous) if new_value > self.current_value => {
+                let fraction = (level - self.current_value) / (new_value - self.current_value);
+                previous + (now - previous).mul_f32(fraction.clamp(0.0, 1.0))
+            }
+            _ => now,
         }
     }
-}
 
-pub struct MidiService {
-    port_options: Vec<PortOption>,
-    connection: Option<MidiOutputConnection>,
-    config: Config,
-    key_states: FxHashMap<HIDCodes, KeyState>,
-    enabled: bool,
-    enabled_key_state: bool,
-}
+    /// Tracks the press window and estimates the velocity of the press in progress
+    fn update_velocity(
+        &mut self,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        previous_update: Option<Instant>,
+        now: Instant,
+    ) {
+        // Retreating below the actuation point starts the contact time over
+        if new_value <= key_config.actuation_point {
+            self.actuated_at = None;
+        } else if self.current_value <= key_config.actuation_point {
+            self.actuated_at = Some(self.crossing_time(
+                key_config.actuation_point,
+                new_value,
+                previous_update,
+                now,
+            ));
+        }
 
-pub struct PortOption {
-    port: MidiOutputPort,
-    name: String,
-}
+        // A fixed velocity leaves nothing to measure
+        if key_config.fixed_velocity.is_some()
+            || (self.current_value <= key_config.actuation_point
+                && new_value > key_config.actuation_point
+                && new_value < threshold)
+            || new_value <= key_config.actuation_point
+        {
+            self.start_press_window(new_value, now);
+            self.raw_velocity = 0.0;
+        } else if self.current_value <= key_config.actuation_point && new_value > threshold {
+            // Rest to past the threshold between two polls, the press took at most one tick. The
+            // press window may be stale or missing here, e.g. after the key was inactive.
+            let tick = Duration::from_secs_f32(1.0 / REFRESH_RATE);
+            let elapsed = previous_update.map_or(tick, |previous| {
+                (now - previous).clamp(Duration::from_micros(1), tick)
+            });
+            self.raw_velocity = match key_config.velocity_estimation {
+                VelocityEstimation::ContactTime {
+                    fastest_ms,
+                    slowest_ms,
+                } => contact_time_velocity(Duration::ZERO, fastest_ms, slowest_ms),
+                _ => {
+                    (new_value - self.current_value)
+                        / elapsed.as_secs_f32()
+                        / key_config.velocity_scale
+                }
+            };
+            self.start_press_window(new_value, now);
+        } else if let Some((prev_time, prev_depth)) = self.lower_press {
+            // The sample crossing the threshold is measured at the threshold itself, so where the
+            // poll lands within the tick does not show up as velocity jitter
+            let (sample_time, sample_value) =
+                if self.current_value <= threshold && new_value > threshold {
+                    (
+                        self.crossing_time(threshold, new_value, previous_update, now),
+                        threshold,
+                    )
+                } else {
+                    (now, new_value)
+                };
+            if let Some(&(last_time, last_depth)) = self.press_samples.back() {
+                let elapsed = sample_time
+                    .saturating_duration_since(last_time)
+                    .as_secs_f32();
+                if elapsed > 0.0 {
+                    let speed = (sample_value - last_depth) / elapsed;
+                    self.peak_speed = Some(self.peak_speed.map_or(speed, |peak| peak.max(speed)));
+                }
+            }
+            self.press_samples.push_back((sample_time, sample_value));
+            if self.press_samples.len() > VELOCITY_SAMPLES {
+                self.press_samples.pop_front();
+            }
+            let slope = match key_config.velocity_estimation {
+                VelocityEstimation::LeastSquares => least_squares_slope(&self.press_samples),
+                VelocityEstimation::PeakSpeed => self.peak_speed,
+                VelocityEstimation::TwoPoint
+                | VelocityEstimation::ContactTime { .. }
+                | VelocityEstimation::Depth { .. } => None,
+            };
+            self.raw_velocity = match (key_config.velocity_estimation, slope) {
+                (
+                    VelocityEstimation::ContactTime {
+                        fastest_ms,
+                        slowest_ms,
+                    },
+                    _,
+                ) => self.actuated_at.map_or(0.0, |actuated_at| {
+                    let elapsed = sample_time.saturating_duration_since(actuated_at);
+                    contact_time_velocity(elapsed, fastest_ms, slowest_ms)
+                }),
+                (_, Some(slope)) => slope / key_config.velocity_scale,
+                (_, None) if sample_value != prev_depth && sample_time > prev_time => {
+                    let duration = (sample_time - prev_time).as_secs_f32();
+                    (sample_value - prev_depth) / duration / key_config.velocity_scale
+                }
+                (_, None) => 0.0,
+            };
+            if (prev_depth - new_value).abs() < 0.01 || new_value < self.current_value - 0.01 {
+                self.start_press_window(new_value, now);
+            }
+        }
+    }
 
-impl MidiService {
-    pub fn new() -> Self {
-        MidiService {
-            port_options: Vec::new(),
-            connection: None,
-            config: Config::default(),
-            key_states: FxHashMap::default(),
-            enabled: false,
-            enabled_key_state: false,
+    /// Measures how fast a held key is coming back up, for the note off velocity
+    fn update_release_velocity(&mut self, key_config: &KeyConfig, new_value: f32, now: Instant) {
+        if new_value < self.current_value {
+            self.release_start.get_or_insert((now, self.current_value));
+        } else if new_value > self.current_value {
+            self.release_start = None;
         }
+        self.release_velocity = match (key_config.release_velocity_scale, self.release_start) {
+            (Some(scale), Some((since, depth))) => {
+                // The key started falling somewhere within the tick before it was noticed
+                let duration = (now - since).as_secs_f32() + 1.0 / REFRESH_RATE;
+                Some(((depth - new_value) / duration / scale).clamp(0.0, 1.0))
+            }
+            _ => None,
+        };
     }
 
-    pub fn set_config(&mut self, config: Config) -> Result<()> {
-        // Clean up existing notes if needed
-        if let Some(sink) = &mut self.connection {
-            for (hid_code, state) in &mut self.key_states {
-                if state.pressed {
-                    if let Some(key_config) = self.config.key_configs.get(hid_code) {
-                        if let Some(effective_note) = state.get_effective_note(key_config.note_id) {
-                            sink.note_off(effective_note, state.velocity, key_config.channel)?;
-                        }
-                    }
+    /// Starts a delayed note once it is due and the press has qualified by time or depth
+    fn send_pending_note_on(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        if self.unqualified_until.is_some_and(|until| now >= until)
+            || key_config
+                .min_press_depth
+                .is_some_and(|depth| new_value >= depth)
+        {
+            self.unqualified_until = None;
+        }
+        if let Some((due, velocity)) = self.pending_note_on {
+            if now >= due && self.unqualified_until.is_none() {
+                if let Some(effective_note) = self.sounding_note.or(self.latched_note) {
+                    send_note_on(config, sink, effective_note, velocity, key_config.channel)?;
+                    self.start_chord(config, key_config, now, sink)?;
                 }
+                self.pending_note_on = None;
             }
         }
+        Ok(())
+    }
 
-        self.config = config;
-        self.key_states.clear();
-
-        // Initialize states for all configured keys
-        for hid_code in self.config.key_configs.keys() {
-            self.key_states.insert(hid_code.clone(), KeyState::new());
+    /// Sounds the soft hold note once the key has rested above the actuation point long enough
+    fn update_soft_hold(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let Some(soft_hold) = &key_config.soft_hold else {
+            return Ok(());
+        };
+        if new_value <= key_config.actuation_point {
+            self.soft_hold_since = None;
+            self.soft_hold_cancelled = false;
+            self.end_soft_hold(key_config, sink)?;
+        } else if new_value > threshold {
+            self.soft_hold_since = None;
+            self.soft_hold_cancelled = true;
+        } else if new_value > soft_hold.max_depth {
+            self.soft_hold_since = None;
+        } else if !self.soft_hold_cancelled && !self.soft_hold_sounding {
+            let since = *self.soft_hold_since.get_or_insert(now);
+            if now - since >= Duration::from_millis(soft_hold.hold_ms as u64) {
+                send_note_on(
+                    config,
+                    sink,
+                    soft_hold.note,
+                    soft_hold.velocity,
+                    key_config.channel,
+                )?;
+                self.soft_hold_sounding = true;
+            }
         }
-
         Ok(())
     }
 
-    pub fn poll(&mut self) -> Result<()> {
-        let connection = self
-            .connection
-            .as_mut()
-            .ok_or_else(|| anyhow!("No MIDI connection!"))?;
+    /// Releases a held key that has been coming back up for enough consecutive polls
+    fn update_early_release(
+        &mut self,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let Some(early_release) = &key_config.early_release else {
+            return Ok(());
+        };
+        if new_value < self.current_value - early_release.min_slope {
+            self.rising_ticks = self.rising_ticks.saturating_add(1);
+        } else {
+            self.rising_ticks = 0;
+        }
+        // Re-arm once the key is pushed down again or has left the trigger zone
+        if self.early_released
+            && (new_value <= threshold || new_value > self.current_value + early_release.min_slope)
+        {
+            self.early_released = false;
+        }
+        if self.pressed && self.rising_ticks >= early_release.ticks.max(1) {
+            self.release(key_config, now, sink)?;
+            self.early_released = true;
+            self.rising_ticks = 0;
+        }
+        Ok(())
+    }
 
-        let read_result: SDKResult<HashMap<u16, f32>> =
-            sdk::read_full_buffer(ANALOG_BUFFER_READ_MAX);
-        let analog_data = read_result.0.context("Failed to read buffer")?;
+    /// Whether the key counts as held, by rapid trigger or the thresholds and the depth window
+    fn is_held(
+        &mut self,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        release_threshold: f32,
+        now: Instant,
+    ) -> bool {
+        let held = match &key_config.rapid_trigger {
+            Some(rapid) => {
+                self.rapid_trigger_held(rapid, key_config, new_value, threshold, release_threshold)
+            }
+            None => new_value > threshold || (self.pressed && new_value > release_threshold),
+        };
+        match key_config.velocity_estimation {
+            VelocityEstimation::Depth { window_ms } => {
+                self.depth_window_held(window_ms, held, new_value, threshold, now)
+            }
+            _ => held,
+        }
+    }
 
-        let toggle_pressed = self.config.toggle_keys.iter().any(|code| {
-            analog_data
-                .get(&code.to_u16().unwrap())
-                .map_or(false, |&v| v > 0.0)
-        });
-        if toggle_pressed != self.enabled_key_state {
-            self.enabled_key_state = toggle_pressed;
-            if toggle_pressed {
-                self.enabled = !self.enabled;
-                if self.enabled {
-                    info!("Enabled keyboard");
-                } else {
-                    info!("Disabled keyboard");
+    /// Starts the note of a new press, unless debouncing, a latch or the velocity gate holds it
+    fn press(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        context: KeyContext,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let now = context.now;
+        let velocity = (self.output_velocity(key_config) + key_config.velocity_trim as f32 / 127.0)
+            .clamp(0.0, 1.0);
+        let min_retrigger = Duration::from_millis(key_config.min_retrigger_ms as u64);
+        if self
+            .released_at
+            .is_some_and(|released_at| now - released_at < min_retrigger)
+        {
+            self.debounced = true;
+        } else if let Some(latched_note) = self.latched_note.take() {
+            // The releasing press sets the note off velocity
+            if self.pending_note_on.take().is_none() {
+                let velocity = key_config.snap_velocity(velocity);
+                sink.note_off(latched_note, velocity, key_config.channel)?;
+                self.end_chord(velocity, key_config.channel, sink)?;
+            }
+            self.chord_notes.clear();
+            self.pressed = true;
+            self.pressed_at = Some(now);
+        } else if key_config
+            .min_trigger_velocity
+            .is_some_and(|min_velocity| velocity < min_velocity)
+        {
+            // Without retries the press stays silent until the key leaves the threshold
+            self.velocity_gated = !key_config.retry_within_press;
+        } else if let Some((mut effective_note, mut chord_notes)) =
+            self.next_notes(config, key_config)
+        {
+            let reverse_strum = key_config
+                .strum
+                .as_ref()
+                .and_then(|strum| strum.reverse_above_velocity)
+                .is_some_and(|min_velocity| velocity >= min_velocity);
+            if reverse_strum && !chord_notes.is_empty() {
+                chord_notes.insert(0, effective_note);
+                chord_notes.reverse();
+                effective_note = chord_notes.remove(0);
+            }
+            let velocity = match key_config.fixed_velocity {
+                Some(fixed_velocity) => fixed_velocity as f32 / 127.0,
+                None => {
+                    let velocity = key_config.scale_velocity(velocity);
+                    key_config.snap_velocity(self.humanize(key_config, velocity))
                 }
+            };
+            self.sounding_note = Some(effective_note);
+            self.chord_notes = chord_notes;
+            self.press_velocity = velocity;
+            let offset_due = (key_config.timing_offset_ms > 0)
+                .then(|| now + Duration::from_millis(key_config.timing_offset_ms as u64));
+            let qualified = key_config.min_press_ms == 0
+                || key_config
+                    .min_press_depth
+                    .is_some_and(|depth| new_value >= depth);
+            self.unqualified_until =
+                (!qualified).then(|| now + Duration::from_millis(key_config.min_press_ms as u64));
+            let due = offset_due.max(context.quantize_to).filter(|&due| due > now);
+            if due.is_some() || self.unqualified_until.is_some() {
+                // Carries the velocity measured now, however late the note starts
+                self.pending_note_on = Some((due.unwrap_or(now), velocity));
+                self.blip_if_cancelled = context.quantize_to.is_some()
+                    && config
+                        .quantize
+                        .as_ref()
+                        .is_some_and(|quantize| quantize.early_release == QuantizedRelease::Blip);
+            } else {
+                send_note_on(config, sink, effective_note, velocity, key_config.channel)?;
+                self.start_chord(config, key_config, now, sink)?;
             }
+            self.repeat_at = key_config
+                .note_repeat
+                .as_ref()
+                .map(|repeat| now + repeat_interval(config, repeat));
+            // The filter starts fresh so the first aftertouch isn't dragged down by history
+            self.smoothed_pressure = key_config.shape_aftertouch(new_value, threshold);
+            self.aftertouch_value = self.smoothed_pressure;
+            self.pressure_updated = Some(now);
+            self.pressed = true;
+            self.pressed_at = Some(now);
         }
-        if !self.enabled {
-            return Ok(());
-        }
-
-        let modifier_pressed = self.config.modifier_keys.iter().any(|code| {
-            analog_data
-                .get(&code.to_u16().unwrap())
-                .map_or(false, |&v| v > 0.0)
-        });
-
-        for (hid_code, state) in &mut self.key_states {
-            if let Some(key_config) = self.config.key_configs.get(hid_code) {
-                let new_value = analog_data
-                    .get(&hid_code.to_u16().unwrap())
-                    .copied()
-                    .unwrap_or(0.0);
-
-                let shifted_amount = modifier_pressed as i8 * key_config.shift_amount;
+        Ok(())
+    }
 
-                state.update_value(key_config, new_value, connection, shifted_amount)?;
+    /// Sends polyphonic aftertouch for the held notes once the pressure changed
+    fn update_aftertouch(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let pressure = self.smooth_pressure(
+            key_config,
+            key_config.shape_aftertouch(new_value, threshold),
+            now,
+        );
+        if key_config.aftertouch
+            && config.aftertouch_mode == AftertouchMode::Polyphonic
+            && key_config.pre_touch != Some(PreTouch::PolyAftertouch)
+            && pressure != self.aftertouch_value
+            && self.pending_note_on.is_none()
+            && self.aftertouch_due(config, now)
+        {
+            if let Some(effective_note) = self.sounding_note {
+                sink.polyphonic_aftertouch(effective_note, pressure, key_config.channel)?;
+                for &note in &self.chord_notes[..self.chord_sent] {
+                    sink.polyphonic_aftertouch(note, pressure, key_config.channel)?;
+                }
+                self.aftertouch_value = pressure;
+                self.last_aftertouch = Some(now);
             }
         }
-
         Ok(())
     }
 
-    pub fn init(&mut self) -> Result<u32> {
-        info!("Starting Wooting Analog SDK!");
-        let init_result: SDKResult<u32> = sdk::initialise();
-        let device_num = init_result
-            .0
-            .context("Wooting Analog SDK Failed to initialise")?;
-
-        info!("Analog SDK Successfully initialised with {device_num} devices");
-        let devices = sdk::get_connected_devices_info(DEVICE_BUFFER_MAX).0?;
-        for (i, device) in devices.iter().enumerate() {
-            info!("Device {} is {:?}", i, device);
+    /// Replaces the held note with the one of the current shift, at a velocity from the depth
+    fn retrigger_shifted(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        // A delayed note has not started yet and keeps its original pitch
+        if self.pending_note_on.is_some() {
+            return Ok(());
         }
-
-        self.refresh_port_options();
-
-        if !self.port_options.is_empty() {
-            info!("Opening connection");
-            self.select_port(0)?;
-        } else {
-            info!("No output ports available!");
+        let Some(old_note) = self.sounding_note else {
+            return Ok(());
+        };
+        // Shifted out of range, the held note keeps sounding
+        let Some((new_note, chord_notes)) = self.next_notes(config, key_config) else {
+            return Ok(());
+        };
+        if new_note == old_note {
+            return Ok(());
         }
 
-        Ok(device_num)
+        let depth = if threshold < 1.0 {
+            ((new_value - threshold) / (1.0 - threshold)).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        let velocity = key_config.snap_velocity(key_config.scale_velocity(depth));
+        let channel = key_config.channel;
+        sink.note_off(old_note, self.press_velocity, channel)?;
+        self.end_chord(self.press_velocity, channel, sink)?;
+        self.sounding_note = Some(new_note);
+        self.chord_notes = chord_notes;
+        self.press_velocity = velocity;
+        send_note_on(config, sink, new_note, velocity, channel)?;
+        self.start_chord(config, key_config, now, sink)
     }
 
-    pub fn refresh_port_options(&mut self) {
-        let midi_output = MidiOutput::new(MIDI_CLIENT_NAME).unwrap();
-        self.port_options = midi_output
-            .ports()
-            .into_iter()
-            .map(|port| {
-                let name = midi_output.port_name(&port).unwrap();
-                PortOption { port, name }
-            })
-            .collect();
-        info!(
-            "We have {} ports available! ({:?})",
-            self.port_options.len(),
-            self.port_options
-                .iter()
-                .map(|port| &port.name)
-                .collect::<Vec<_>>()
-        );
+    /// Defers the trigger until the depth window has passed, measuring the velocity from the
+    /// deepest point within it. The key counts as held until then, even if it was released.
+    fn depth_window_held(
+        &mut self,
+        window_ms: u16,
+        held: bool,
+        new_value: f32,
+        threshold: f32,
+        now: Instant,
+    ) -> bool {
+        if self.pressed {
+            return held;
+        }
+        if held
+            && self.depth_window.is_none()
+            && !self.early_released
+            && !self.velocity_gated
+            && !self.debounced
+        {
+            self.depth_window = Some((now + Duration::from_millis(window_ms as u64), new_value));
+        }
+        let Some((until, peak)) = &mut self.depth_window else {
+            return held;
+        };
+        *peak = peak.max(new_value);
+        self.raw_velocity = if threshold < 1.0 {
+            ((*peak - threshold) / (1.0 - threshold)).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        if now < *until {
+            return false;
+        }
+        self.depth_window = None;
+        true
     }
 
-    pub fn select_port(&mut self, option: usize) -> Result<()> {
-        if option >= self.port_options.len() {
-            bail!("Port option out of range!");
+    /// Whether the key counts as held, following its direction instead of the thresholds once
+    /// it has triggered. Directions are judged by `pressed`, so they always match what was sent.
+    fn rapid_trigger_held(
+        &mut self,
+        rapid: &RapidTriggerConfig,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        release_threshold: f32,
+    ) -> bool {
+        if new_value <= key_config.actuation_point {
+            self.rapid_extreme = None;
+            return false;
         }
+        let Some(extreme) = self.rapid_extreme else {
+            let held = new_value > threshold || (self.pressed && new_value > release_threshold);
+            if held {
+                self.rapid_extreme = Some(new_value);
+            }
+            return held;
+        };
 
-        drop(self.connection.take());
-
-        let selection = &self.port_options[option];
-        info!("Connecting to Port {}: \"{}\"!", option, selection.name);
+        let (held, extreme) = if self.pressed {
+            let released = new_value < extreme - rapid.release_sensitivity;
+            (
+                !released,
+                if released {
+                    new_value
+                } else {
+                    extreme.max(new_value)
+                },
+            )
+        } else {
+            let pressed = new_value > extreme + rapid.press_sensitivity;
+            (
+                pressed,
+                if pressed {
+                    new_value
+                } else {
+                    extreme.min(new_value)
+                },
+            )
+        };
+        self.rapid_extreme = Some(extreme);
+        held
+    }
 
-        let midi_output = MidiOutput::new(MIDI_CLIENT_NAME).unwrap();
-        self.connection = Some(
-            midi_output
-                .connect(&selection.port, MIDI_PORT_NAME)
-                .map_err(|e| anyhow!("Error: {}", e))?,
-        );
+    /// Filters a raw reading before it is handed to `update_value`
+    fn smooth_input(&mut self, smoothing: Option<InputSmoothing>, raw: f32) -> f32 {
+        let [previous, before] = self.raw_history;
+        self.raw_history = [raw, previous];
+        match smoothing {
+            None => raw,
+            Some(InputSmoothing::Exponential(coefficient)) => {
+                self.current_value + (raw - self.current_value) * coefficient.clamp(0.0, 1.0)
+            }
+            Some(InputSmoothing::MedianOf3) => raw.max(previous).min(raw.min(previous).max(before)),
+        }
+    }
 
-        Ok(())
+    /// Measures the press speed from here on
+    fn start_press_window(&mut self, value: f32, now: Instant) {
+        self.lower_press = Some((now, value));
+        self.press_samples.clear();
+        self.press_samples.push_back((now, value));
+        self.peak_speed = None;
     }
 
-    pub fn uninit(&mut self) {
-        info!("Uninitialising MidiService");
-        sdk::uninitialise();
-        trace!("Sdk uninit done");
-        if let Some(output) = self.connection.take() {
-            output.close();
+    fn repeat_note(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        threshold: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let Some(repeat) = &key_config.note_repeat else {
+            return Ok(());
+        };
+        if self.pending_note_on.is_some() || self.repeat_at.is_none_or(|due| now < due) {
+            return Ok(());
+        }
+        let Some(effective_note) = self.sounding_note else {
+            return Ok(());
+        };
+
+        let depth = if threshold < 1.0 {
+            (new_value - threshold) / (1.0 - threshold)
+        } else {
+            1.0
+        };
+        let velocity = key_config.snap_velocity(key_config.scale_velocity(depth));
+        let channel = key_config.channel;
+        sink.note_off(effective_note, self.press_velocity, channel)?;
+        self.end_chord(self.press_velocity, channel, sink)?;
+        self.press_velocity = velocity;
+        send_note_on(config, sink, effective_note, velocity, channel)?;
+        self.start_chord(config, key_config, now, sink)?;
+        self.repeat_at = Some(now + repeat_interval(config, repeat));
+        Ok(())
+    }
+
+    /// Starts the chord notes after the first note, all at once or one by one when strummed
+    fn start_chord(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        self.chord_sent = 0;
+        self.strum_start = Some(now);
+        self.update_strum(config, key_config, now, sink)
+    }
+
+    fn update_strum(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let Some(start) = self.strum_start else {
+            return Ok(());
+        };
+        let step = key_config.strum.as_ref().map_or(Duration::ZERO, |strum| {
+            Duration::from_millis(strum.step_ms as u64)
+        });
+        while self.chord_sent < self.chord_notes.len()
+            && now - start >= step * (self.chord_sent as u32 + 1)
+        {
+            let note = self.chord_notes[self.chord_sent];
+            send_note_on(config, sink, note, self.press_velocity, key_config.channel)?;
+            self.chord_sent += 1;
+        }
+        if self.chord_sent == self.chord_notes.len() {
+            self.strum_start = None;
+        }
+        Ok(())
+    }
+
+    /// Ends the chord notes that have started, the ones a strum has not reached are dropped
+    fn end_chord(
+        &mut self,
+        velocity: f32,
+        channel: Channel,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        for &note in &self.chord_notes[..self.chord_sent] {
+            sink.note_off(note, velocity, channel)?;
+        }
+        self.chord_sent = 0;
+        self.strum_start = None;
+        Ok(())
+    }
+
+    fn update_second_note(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        new_value: f32,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let Some((note_id, deep_threshold)) = key_config.second_note else {
+            return Ok(());
+        };
+        if new_value > deep_threshold {
+            // Waits for a delayed base note so the second one never sounds first
+            if self.second_sounding_note.is_none() && self.pending_note_on.is_none() {
+                if let Some(effective_note) = self.get_effective_note(config, note_id) {
+                    send_note_on(
+                        config,
+                        sink,
+                        effective_note,
+                        self.press_velocity,
+                        key_config.channel,
+                    )?;
+                    self.second_sounding_note = Some(effective_note);
+                }
+            }
+        } else {
+            self.end_second_note(key_config, sink)?;
+        }
+        Ok(())
+    }
+
+    fn end_second_note(&mut self, key_config: &KeyConfig, sink: &mut impl NoteSink) -> Result<()> {
+        if let Some(effective_note) = self.second_sounding_note.take() {
+            let velocity = self.note_off_velocity(key_config);
+            sink.note_off(effective_note, velocity, key_config.channel)?;
+        }
+        Ok(())
+    }
+
+    fn update_pre_touch(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+        pre_touch: PreTouch,
+        new_value: f32,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let span = 1.0 - key_config.actuation_point;
+        let depth = if span > 0.0 {
+            ((new_value - key_config.actuation_point) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        // Only 7-bit steps are reported, so a key at rest stops after a single 0
+        let value = (depth * 127.0).round() / 127.0;
+        if value == self.pre_touch_value {
+            return Ok(());
+        }
+
+        match pre_touch {
+            PreTouch::PolyAftertouch => {
+                let note = match self.sounding_note {
+                    Some(note) => Some(note),
+                    None => self.get_effective_note(config, key_config.note_id),
+                };
+                if let Some(note) = note {
+                    sink.polyphonic_aftertouch(note, value, key_config.channel)?;
+                }
+            }
+            PreTouch::ControlChange(controller) => {
+                let value = (value * 127.0).round() as u8;
+                sink.control_change(controller, value, key_config.channel)?;
+            }
+        }
+        self.pre_touch_value = value;
+        Ok(())
+    }
+
+    /// High passes the depth by subtracting its recent average and follows the amplitude of what
+    /// remains, so only oscillation counts and slow pressure changes do not
+    fn update_vibrato(
+        &mut self,
+        vibrato: &VibratoConfig,
+        channel: Channel,
+        new_value: f32,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        let elapsed = self
+            .vibrato_history
+            .back()
+            .map_or(0.0, |&(time, _)| (now - time).as_secs_f32());
+        let mut value = 0.0;
+        if self.pressed {
+            self.vibrato_history.push_back((now, new_value));
+            while self
+                .vibrato_history
+                .front()
+                .is_some_and(|&(time, _)| now - time > VIBRATO_WINDOW)
+            {
+                self.vibrato_history.pop_front();
+            }
+            let center = self.vibrato_history.iter().map(|&(_, v)| v).sum::<f32>()
+                / self.vibrato_history.len() as f32;
+            let deviation = new_value - center;
+            self.vibrato_amplitude = deviation
+                .abs()
+                .max(self.vibrato_amplitude * (-elapsed / VIBRATO_RELEASE_SECS).exp());
+
+            // The press itself looks like a wiggle until the window has settled on its depth
+            let settled = self
+                .vibrato_history
+                .front()
+                .is_some_and(|&(time, _)| now - time >= VIBRATO_WINDOW / 2);
+            let strength =
+                ((self.vibrato_amplitude - vibrato.sensitivity) * vibrato.depth).clamp(0.0, 1.0);
+            if settled && strength > 0.0 {
+                value = match vibrato.target {
+                    VibratoTarget::PitchBend => {
+                        let phase = (deviation / self.vibrato_amplitude).clamp(-1.0, 1.0);
+                        (strength * phase * 8191.0).round() / 8191.0
+                    }
+                    VibratoTarget::ModWheel => (strength * 127.0).round() / 127.0,
+                };
+            }
+        } else {
+            self.vibrato_history.clear();
+            self.vibrato_amplitude = 0.0;
+        }
+
+        // Only changes are sent, so a released key ends with a single return to rest
+        if value != self.vibrato_value {
+            match vibrato.target {
+                VibratoTarget::PitchBend => sink.pitch_bend(value, channel)?,
+                VibratoTarget::ModWheel => {
+                    let value = (value * 127.0).round() as u8;
+                    sink.control_change(CC_MOD_WHEEL, value, channel)?;
+                }
+            }
+            self.vibrato_value = value;
+        }
+        Ok(())
+    }
+
+    fn release(
+        &mut self,
+        key_config: &KeyConfig,
+        now: Instant,
+        sink: &mut impl NoteSink,
+    ) -> Result<()> {
+        if self.pressed {
+            // The deeper zone is left first, even when both are crossed within one tick
+            self.end_second_note(key_config, sink)?;
+            // A delayed note that has not started yet is simply cancelled
+            if let Some(effective_note) = self.sounding_note.take() {
+                if key_config.latch {
+                    self.latched_note = Some(effective_note);
+                } else {
+                    match self.pending_note_on.take() {
+                        None => {
+                            let velocity = self.note_off_velocity(key_config);
+                            sink.note_off(effective_note, velocity, key_config.channel)?;
+                            self.end_chord(velocity, key_config.channel, sink)?;
+                        }
+                        // Brushes that never qualified stay silent
+                        Some((_, velocity))
+                            if self.blip_if_cancelled && self.unqualified_until.is_none() =>
+                        {
+                            sink.note_on(effective_note, velocity, key_config.channel)?;
+                            sink.note_off(effective_note, velocity, key_config.channel)?;
+                        }
+                        Some(_) => {}
+                    }
+                }
+                if !key_config.latch {
+                    self.chord_notes.clear();
+                }
+            }
+            self.pressed = false;
+            self.released_at = Some(now);
+            self.release_start = None;
+            self.release_velocity = None;
+        }
+        Ok(())
+    }
+
+    /// Releases everything the key is sounding, including articulations that normally only end
+    /// once the key is back at rest
+    fn release_all(&mut self, key_config: &KeyConfig, sink: &mut impl NoteSink) -> Result<()> {
+        self.release(key_config, Instant::now(), sink)?;
+        if let Some(latched_note) = self.latched_note.take() {
+            if self.pending_note_on.take().is_none() {
+                sink.note_off(latched_note, self.press_velocity, key_config.channel)?;
+                self.end_chord(self.press_velocity, key_config.channel, sink)?;
+            }
+            self.chord_notes.clear();
+        }
+        self.end_soft_hold(key_config, sink)
+    }
+
+    fn end_soft_hold(&mut self, key_config: &KeyConfig, sink: &mut impl NoteSink) -> Result<()> {
+        if self.soft_hold_sounding {
+            if let Some(soft_hold) = &key_config.soft_hold {
+                sink.note_off(soft_hold.note, soft_hold.velocity, key_config.channel)?;
+            }
+            self.soft_hold_sounding = false;
+        }
+        Ok(())
+    }
+
+    /// Exponential moving average of the key depth, using the real time between updates. With
+    /// peak hold it never falls, the next press starts the filter over.
+    fn smooth_pressure(&mut self, key_config: &KeyConfig, value: f32, now: Instant) -> f32 {
+        let previous = self.smoothed_pressure;
+        let elapsed = self
+            .pressure_updated
+            .map_or(0.0, |updated| (now - updated).as_secs_f32());
+        self.pressure_updated = Some(now);
+
+        if key_config.aftertouch_smoothing_ms <= 0.0 {
+            self.smoothed_pressure = value;
+        } else {
+            let alpha = 1.0 - (-elapsed * 1000.0 / key_config.aftertouch_smoothing_ms).exp();
+            self.smoothed_pressure += (value - self.smoothed_pressure) * alpha;
+        }
+        if key_config.aftertouch_response == AftertouchResponse::PeakHold {
+            self.smoothed_pressure = self.smoothed_pressure.max(previous);
+        }
+        self.smoothed_pressure
+    }
+
+    /// Skipped updates are not lost, the latest value goes out once the interval has passed
+    fn aftertouch_due(&self, config: &Config, now: Instant) -> bool {
+        match (config.aftertouch_max_rate, self.last_aftertouch) {
+            (Some(rate), Some(last)) if rate > 0.0 => {
+                now - last >= Duration::from_secs_f32(1.0 / rate)
+            }
+            _ => true,
+        }
+    }
+
+    fn output_velocity(&self, key_config: &KeyConfig) -> f32 {
+        if let Some(fixed_velocity) = key_config.fixed_velocity {
+            return fixed_velocity as f32 / 127.0;
+        }
+        (self.curved_velocity(key_config) * key_config.velocity_gain).clamp(0.0, 1.0)
+    }
+
+    /// Measured velocity after the curve, before the gain
+    fn curved_velocity(&self, key_config: &KeyConfig) -> f32 {
+        match &key_config.velocity_curve {
+            Some(curve) => curve.apply(self.raw_velocity),
+            None => self.raw_velocity,
+        }
+    }
+
+    /// Velocity of a released note, the press velocity unless the release is measured or fixed
+    fn note_off_velocity(&self, key_config: &KeyConfig) -> f32 {
+        if let Some(fixed_velocity) = key_config.fixed_release_velocity {
+            return fixed_velocity as f32 / 127.0;
+        }
+        let velocity = self
+            .release_velocity
+            .unwrap_or_else(|| self.output_velocity(key_config));
+        key_config.snap_velocity(velocity)
+    }
+
+    /// The first note that is in range and the rest of the chord, out of range notes are
+    /// skipped individually
+    fn next_notes(
+        &mut self,
+        config: &Config,
+        key_config: &KeyConfig,
+    ) -> Option<(NoteID, Vec<NoteID>)> {
+        let base_note = match &key_config.note_pool {
+            Some(pool) => self.pick_pool_note(pool)?,
+            None => key_config.note_id,
+        };
+        let mut notes: Vec<NoteID> = Vec::with_capacity(1 + key_config.extra_notes.len());
+        for offset in std::iter::once(0).chain(key_config.extra_notes.iter().copied()) {
+            let note = base_note as i16 + offset as i16;
+            if !(0..=127).contains(&note) {
+                continue;
+            }
+            if let Some(effective_note) = self.get_effective_note(config, note as NoteID) {
+                // Folding or clamping can land several chord notes on the same key
+                if !notes.contains(&effective_note) {
+                    notes.push(effective_note);
+                }
+            }
+        }
+        if notes.is_empty() {
+            return None;
+        }
+        let first = notes.remove(0);
+        Some((first, notes))
+    }
+
+    fn pick_pool_note(&mut self, pool: &NotePool) -> Option<NoteID> {
+        let candidates: Vec<NoteID> = pool
+            .notes
+            .iter()
+            .copied()
+            .filter(|&note| !pool.no_repeat || Some(note) != self.last_pool_note)
+            .collect();
+        let candidates = if candidates.is_empty() {
+            &pool.notes
+        } else {
+            &candidates
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let rng = self.pool_rng.get_or_insert_with(|| new_rng(pool.seed));
+        let note = candidates[rng.gen_range(0..candidates.len())];
+        self.last_pool_note = Some(note);
+        Some(note)
+    }
+
+    fn humanize(&mut self, key_config: &KeyConfig, velocity: f32) -> f32 {
+        let spread = key_config.humanize_velocity;
+        if spread <= 0.0 {
+            return velocity;
+        }
+        let rng = self
+            .humanize_rng
+            .get_or_insert_with(|| new_rng(key_config.humanize_seed));
+        let varied = velocity * (1.0 + rng.gen_range(-spread..=spread));
+        // Stays within the velocity window and never reaches 0, which would be a note off
+        varied
+            .max(key_config.velocity_min as f32 / 127.0)
+            .min(key_config.velocity_max as f32 / 127.0)
+            .max(1.0 / 127.0)
+    }
+
+    fn get_effective_note(&mut self, config: &Config, base_note: NoteID) -> Option<NoteID> {
+        let note = self.shifted_note(config, base_note)?;
+        match &config.scale {
+            Some(scale) => scale.fit(note),
+            None => Some(note),
+        }
+    }
+
+    fn shifted_note(&mut self, config: &Config, base_note: NoteID) -> Option<NoteID> {
+        let computed = base_note as i16 + self.shifted_amount as i16;
+        let (min, max) = (MIDI_NOTE_MIN as i16, MIDI_NOTE_MAX as i16);
+        if (min..=max).contains(&computed) {
+            return Some(computed as NoteID);
+        }
+
+        match config.shift_out_of_range {
+            ShiftOutOfRange::Drop => {
+                if !self.out_of_range_logged {
+                    warn!(
+                        "Note {} shifted by {} is out of range, dropping it",
+                        base_note, self.shifted_amount
+                    );
+                    self.out_of_range_logged = true;
+                }
+                None
+            }
+            ShiftOutOfRange::Fold => {
+                let mut note = computed;
+                while note > max {
+                    note -= 12;
+                }
+                while note < min {
+                    note += 12;
+                }
+                Some(note as NoteID)
+            }
+            ShiftOutOfRange::Clamp => Some(computed.clamp(min, max) as NoteID),
         }
-        trace!("MidiService uninit complete");
     }
 }
 
-impl Drop for MidiService {
-    fn drop(&mut self) {
-        self.uninit();
+pub struct MidiService {
+    port_options: Vec<PortOption>,
+    connection: Option<Output>,
+    config: Config,
+    /// Config as passed to `set_config`, before the preset and channel override were applied
+    source_config: Config,
+    key_states: FxHashMap<HIDCodes, KeyState>,
+    enable_state: EnableState,
+    enabled_key_state: bool,
+    sustain_down: bool,
+    threshold_delta: f32,
+    threshold_nudge_key_state: (bool, bool),
+    transpose: i8,
+    // Last sent 7-bit channel pressure
+    channel_pressure: FxHashMap<Channel, u8>,
+    pitch_bend: FxHashMap<Channel, f32>,
+    // Last sent value, 14-bit for high resolution mappings
+    cc_values: FxHashMap<HIDCodes, u16>,
+    held_program_keys: FxHashSet<HIDCodes>,
+    mpe_channels: ChannelAllocator,
+    mono_voices: MonoVoices,
+    shared_notes: SharedNotes,
+    voice_limiter: VoiceLimiter,
+    arpeggiator: Arpeggiator,
+    clock: Option<MidiClock>,
+    clock_input: Option<ClockInput>,
+    midi_buffer: MidiBuffer,
+    velocity_calibration: Option<FxHashMap<HIDCodes, Vec<f32>>>,
+    // Lowest and highest raw reading of every configured key
+    range_calibration: Option<FxHashMap<HIDCodes, (f32, f32)>>,
+    tick: u64,
+    pre_poll_hook: Option<PollHook>,
+    post_poll_hook: Option<PollHook>,
+    event_log: EventLog,
+}
+
+pub struct PortOption {
+    port: MidiOutputPort,
+    name: String,
+}
+
+impl MidiService {
+    pub fn new() -> Self {
+        MidiService {
+            port_options: Vec::new(),
+            connection: None,
+            config: Config::default(),
+            source_config: Config::default(),
+            key_states: FxHashMap::default(),
+            enable_state: EnableState::Off,
+            enabled_key_state: false,
+            sustain_down: false,
+            threshold_delta: 0.0,
+            threshold_nudge_key_state: (false, false),
+            transpose: 0,
+            channel_pressure: FxHashMap::default(),
+            pitch_bend: FxHashMap::default(),
+            cc_values: FxHashMap::default(),
+            held_program_keys: FxHashSet::default(),
+            mpe_channels: ChannelAllocator::default(),
+            mono_voices: MonoVoices::default(),
+            shared_notes: SharedNotes::default(),
+            voice_limiter: VoiceLimiter::default(),
+            arpeggiator: Arpeggiator::default(),
+            clock: None,
+            clock_input: None,
+            midi_buffer: MidiBuffer::default(),
+            velocity_calibration: None,
+            range_calibration: None,
+            tick: 0,
+            pre_poll_hook: None,
+            post_poll_hook: None,
+            event_log: EventLog::new(),
+        }
+    }
+
+    pub fn set_config(&mut self, mut config: Config) -> Result<()> {
+        validation::ensure_valid(&config)?;
+        let source_config = config.clone();
+        if let Some(name) = &config.default_preset {
+            config.key_configs = config.presets[name].key_configs.clone();
+        }
+        if let Some(channel) = config.default_channel {
+            for key_config in config.key_configs.values_mut() {
+                key_config.channel = channel;
+            }
+            for cc_config in config.cc_mappings.values_mut() {
+                cc_config.channel = channel;
+            }
+        }
+        if let Some(tuning) = &config.tuning {
+            let range = tuning::bend_range(&config) as f64 * 100.0;
+            let clamped = (0..=127)
+                .filter(|&note| {
+                    tuning
+                        .retune(note)
+                        .is_some_and(|(_, cents)| cents.abs() > range)
+                })
+                .count();
+            if clamped > 0 {
+                warn!(
+                    "{} notes of the tuning are further off than the bend range of {} cents, \
+                     their bends are clamped",
+                    clamped, range
+                );
+            }
+            if !config.mpe {
+                warn!("Tuning without MPE bends every note of a channel together");
+            }
+        }
+        match &config.quantize {
+            Some(quantize)
+                if self
+                    .clock_input
+                    .as_ref()
+                    .is_none_or(|input| input.port_name() != quantize.input_port) =>
+            {
+                self.clock_input = Some(ClockInput::connect(&quantize.input_port)?);
+            }
+            Some(_) => {}
+            None => self.clock_input = None,
+        }
+        if config.version < CONFIG_VERSION {
+            info!(
+                "Migrating config from version {} to {}",
+                config.version, CONFIG_VERSION
+            );
+            config.migrate();
+        }
+
+        // Clean up existing notes if needed
+        if let Some(connection) = &mut self.connection {
+            let mut tuning_sink = TuningSink::new(connection, &self.config);
+            let mut voice_sink =
+                VoiceLimitSink::new(&mut tuning_sink, &mut self.voice_limiter, &self.config);
+            let mut sink = MpeSink::new(&mut voice_sink, &mut self.mpe_channels, &self.config);
+            self.mono_voices.release(&mut sink)?;
+            let mut mono_sink = MonoSink::new(&mut sink, &mut self.mono_voices, &self.config);
+            self.arpeggiator.flush(&mut mono_sink)?;
+            let mut arp_sink = ArpSink::new(&mut mono_sink, &mut self.arpeggiator, &self.config);
+            for (hid_code, state) in &mut self.key_states {
+                if let Some(key_config) = self.config.key_configs.get(hid_code) {
+                    let key = hid_code.to_u16().unwrap();
+                    let mut sink = SharedNoteSink::new(&mut arp_sink, &mut self.shared_notes, key);
+                    state.release_all(key_config, &mut sink)?;
+                }
+            }
+            if self.sustain_down {
+                if let Some(sustain) = &self.config.sustain {
+                    sink.control_change(CC_SUSTAIN, 0, sustain.channel)?;
+                }
+            }
+            for (channel, _) in self.channel_pressure.drain() {
+                sink.channel_pressure(0.0, channel)?;
+            }
+            for (channel, _) in self.pitch_bend.drain() {
+                sink.pitch_bend(0.0, channel)?;
+            }
+            if self.config.tuning.is_some() {
+                for channel in self.config.channels() {
+                    sink.pitch_bend(0.0, channel)?;
+                }
+            }
+            for (hid_code, value) in self.cc_values.drain() {
+                if let Some(cc_config) = self.config.cc_mappings.get(&hid_code) {
+                    if value != 0 && cc_config.high_resolution {
+                        sink.control_change_14bit(
+                            cc_config.controller,
+                            0,
+                            None,
+                            cc_config.channel,
+                        )?;
+                    } else if value != 0 {
+                        sink.control_change(cc_config.controller, 0, cc_config.channel)?;
+                    }
+                }
+            }
+
+            if config.reset_controllers_on_switch {
+                let mut channels = self.config.channels();
+                channels.extend(config.channels());
+                channels.sort_unstable();
+                channels.dedup();
+                for channel in channels {
+                    sink.reset_controllers(channel, &config.controller_defaults)?;
+                }
+            }
+            if config.mpe || self.config.mpe {
+                send_mpe_configuration(&mut sink, &config)?;
+            }
+
+            match (self.clock.take(), config.clock_bpm) {
+                (Some(mut clock), Some(bpm)) => {
+                    clock.set_bpm(bpm);
+                    self.clock = Some(clock);
+                }
+                (Some(clock), None) => clock.stop(&mut sink)?,
+                (None, Some(bpm)) => {
+                    self.clock = Some(MidiClock::start(bpm, Instant::now(), &mut sink)?)
+                }
+                (None, None) => {}
+            }
+        }
+        self.mpe_channels.clear();
+        self.mono_voices.clear();
+        self.shared_notes.clear();
+        self.voice_limiter.clear();
+        self.arpeggiator.clear();
+
+        if !config.threshold_nudge_sticky && self.threshold_delta != 0.0 {
+            info!("Resetting threshold nudge of {:+.2}", self.threshold_delta);
+            self.threshold_delta = 0.0;
+        }
+
+        self.config = config;
+        self.source_config = source_config;
+        self.sustain_down = false;
+        self.key_states.clear();
+
+        for (hid_code, key_config) in &mut self.config.key_configs {
+            if key_config.timing_offset_ms < 0 {
+                warn!(
+                    "Negative timing offset of {}ms on {} is not supported, using 0ms",
+                    key_config.timing_offset_ms,
+                    keynames::name_of(hid_code, NamingScheme::default())
+                );
+                key_config.timing_offset_ms = 0;
+            }
+        }
+
+        // Initialize states for all configured keys
+        for hid_code in self.config.key_configs.keys() {
+            self.key_states.insert(hid_code.clone(), KeyState::new());
+        }
+
+        Ok(())
+    }
+
+    pub fn set_pre_poll_hook(&mut self, hook: PollHook) {
+        self.pre_poll_hook = Some(hook);
+    }
+
+    pub fn set_post_poll_hook(&mut self, hook: PollHook) {
+        self.post_poll_hook = Some(hook);
+    }
+
+    pub fn clear_poll_hooks(&mut self) {
+        self.pre_poll_hook = None;
+        self.post_poll_hook = None;
+    }
+
+    pub fn poll(&mut self) -> Result<()> {
+        self.poll_with(Self::poll_keys)
+    }
+
+    /// Runs the hooks around `poll_keys`, which returns the number of messages it sent
+    fn poll_with(&mut self, poll_keys: impl FnOnce(&mut Self) -> Result<usize>) -> Result<()> {
+        let mut context = PollContext {
+            timestamp: Instant::now(),
+            tick: self.tick,
+            enable_state: self.enable_state,
+            events_emitted: 0,
+        };
+        if let Some(hook) = &mut self.pre_poll_hook {
+            run_poll_hook(hook, &context, "Pre-poll");
+        }
+
+        let events_emitted = poll_keys(self)?;
+        self.tick += 1;
+
+        context.enable_state = self.enable_state;
+        context.events_emitted = events_emitted;
+        if let Some(hook) = &mut self.post_poll_hook {
+            run_poll_hook(hook, &context, "Post-poll");
+        }
+        Ok(())
+    }
+
+    fn poll_keys(&mut self) -> Result<usize> {
+        let mut output = self
+            .connection
+            .take()
+            .ok_or_else(|| anyhow!("No MIDI connection!"))?;
+        let result = match &mut output {
+            Output::Port(connection) => {
+                let mut buffer = std::mem::take(&mut self.midi_buffer);
+                let result = buffer.batch(connection, |buffer| self.process_keys(buffer));
+                self.midi_buffer = buffer;
+                result
+            }
+            #[cfg(feature = "midi2")]
+            Output::Ump(sink) => self.process_keys(sink),
+        };
+        self.connection = Some(output);
+        result
+    }
+
+    fn process_keys(&mut self, output: &mut impl NoteSink) -> Result<usize> {
+        self.process_analog(output, || {
+            let read_result: SDKResult<HashMap<u16, f32>> =
+                sdk::read_full_buffer(ANALOG_BUFFER_READ_MAX);
+            read_result.0.context("Failed to read buffer")
+        })
+    }
+
+    /// Processes one tick of readings from `read_analog`, returning the number of messages sent
+    fn process_analog(
+        &mut self,
+        output: &mut impl NoteSink,
+        read_analog: impl FnOnce() -> Result<HashMap<u16, f32>>,
+    ) -> Result<usize> {
+        let now = Instant::now();
+        let mut counting_sink = CountingSink::new(output);
+        let mut tuning_sink = TuningSink::new(&mut counting_sink, &self.config);
+        let mut voice_sink =
+            VoiceLimitSink::new(&mut tuning_sink, &mut self.voice_limiter, &self.config);
+        let mut mpe_sink = MpeSink::new(&mut voice_sink, &mut self.mpe_channels, &self.config);
+        let mut mono_sink = MonoSink::new(&mut mpe_sink, &mut self.mono_voices, &self.config);
+        let mut sink = ArpSink::new(&mut mono_sink, &mut self.arpeggiator, &self.config);
+
+        if let Some(clock) = &mut self.clock {
+            clock.update(now, &mut sink)?;
+        }
+        sink.arpeggiate(now)?;
+
+        let analog_data = read_analog()?;
+
+        if let Some(ranges) = &mut self.range_calibration {
+            for hid_code in self.config.key_configs.keys() {
+                let value = analog_data
+                    .get(&hid_code.to_u16().unwrap())
+                    .copied()
+                    .unwrap_or(0.0);
+                let (min, max) = ranges.entry(hid_code.clone()).or_insert((value, value));
+                *min = min.min(value);
+                *max = max.max(value);
+            }
+            return Ok(counting_sink.count);
+        }
+
+        let is_down = |code: &HIDCodes| {
+            analog_data
+                .get(&code.to_u16().unwrap())
+                .map_or(false, |&v| v > 0.0)
+        };
+        let is_chord_down = |codes: &[HIDCodes]| !codes.is_empty() && codes.iter().all(is_down);
+
+        let toggle_pressed = self.config.toggle_keys.iter().any(is_down);
+        if toggle_pressed != self.enabled_key_state {
+            self.enabled_key_state = toggle_pressed;
+            if toggle_pressed {
+                let was_enabled = self.enable_state != EnableState::Off;
+                self.enable_state = self.config.next_enable_state(self.enable_state);
+                info!("Switched keyboard to {:?}", self.enable_state);
+
+                let enabled = self.enable_state != EnableState::Off;
+                if enabled != was_enabled {
+                    let sysex = if enabled {
+                        &self.config.on_enable_sysex
+                    } else {
+                        &self.config.on_disable_sysex
+                    };
+                    if let Some(sysex) = sysex {
+                        sink.sysex(sysex)?;
+                    }
+                    if let Some(transport_start) = self.config.transport_on_toggle {
+                        let status = match (enabled, transport_start) {
+                            (true, TransportStart::Start) => CLOCK_START_MSG,
+                            (true, TransportStart::Continue) => CLOCK_CONTINUE_MSG,
+                            (false, _) => CLOCK_STOP_MSG,
+                        };
+                        sink.system_realtime(status)?;
+                    }
+                }
+
+                // Release notes of keys that just left the active scope
+                for (hid_code, state) in &mut self.key_states {
+                    if let Some(key_config) = self.config.key_configs.get(hid_code) {
+                        if !self.config.is_key_active(self.enable_state, hid_code) {
+                            let key = hid_code.to_u16().unwrap();
+                            let mut sink =
+                                SharedNoteSink::new(&mut sink, &mut self.shared_notes, key);
+                            state.release_all(key_config, &mut sink)?;
+                        }
+                    }
+                }
+                if was_enabled && !enabled {
+                    // Receivers that missed a note off are silenced as well
+                    sink.flush()?;
+                    for channel in self.config.channels() {
+                        sink.control_change(CC_ALL_NOTES_OFF, 0, channel)?;
+                    }
+                }
+            }
+        }
+
+        if let Some(sustain) = &self.config.sustain {
+            let depth = sustain
+                .keys
+                .iter()
+                .filter(|code| self.config.is_key_active(self.enable_state, code))
+                .filter_map(|code| analog_data.get(&code.to_u16().unwrap()).copied())
+                .fold(0.0, f32::max);
+            let sustain_down = if self.sustain_down {
+                depth > sustain.threshold - sustain.hysteresis
+            } else {
+                depth > sustain.threshold
+            };
+            if sustain_down != self.sustain_down {
+                self.sustain_down = sustain_down;
+                let value = if sustain_down { 127 } else { 0 };
+                sink.control_change(CC_SUSTAIN, value, sustain.channel)?;
+            }
+        }
+
+        let nudge_state = (
+            is_chord_down(&self.config.threshold_nudge_up_keys),
+            is_chord_down(&self.config.threshold_nudge_down_keys),
+        );
+        if nudge_state != self.threshold_nudge_key_state {
+            let (up, down) = nudge_state;
+            let (was_up, was_down) = self.threshold_nudge_key_state;
+            self.threshold_nudge_key_state = nudge_state;
+            let step = match (up && !was_up, down && !was_down) {
+                (true, false) => THRESHOLD_NUDGE_STEP,
+                (false, true) => -THRESHOLD_NUDGE_STEP,
+                _ => 0.0,
+            };
+            if step != 0.0 {
+                self.threshold_delta =
+                    (self.threshold_delta + step).clamp(-THRESHOLD_NUDGE_MAX, THRESHOLD_NUDGE_MAX);
+                info!("Threshold nudged to {:+.2}", self.threshold_delta);
+            }
+        }
+
+        update_pitch_bend(
+            &self.config,
+            self.enable_state,
+            &analog_data,
+            &mut self.pitch_bend,
+            &mut sink,
+        )?;
+
+        for (hid_code, cc_config) in &self.config.cc_mappings {
+            let depth = if self.config.is_key_active(self.enable_state, hid_code) {
+                analog_data
+                    .get(&hid_code.to_u16().unwrap())
+                    .copied()
+                    .unwrap_or(0.0)
+            } else {
+                0.0
+            };
+            let max = if cc_config.high_resolution {
+                16383.0
+            } else {
+                127.0
+            };
+            let value = (depth.clamp(0.0, 1.0) * max).round() as u16;
+            // Only changes are sent, a key at rest ends with a final 0
+            let previous = self.cc_values.insert(hid_code.clone(), value);
+            if previous.unwrap_or(0) == value {
+                continue;
+            }
+            if cc_config.high_resolution {
+                sink.control_change_14bit(
+                    cc_config.controller,
+                    value,
+                    previous,
+                    cc_config.channel,
+                )?;
+            } else {
+                sink.control_change(cc_config.controller, value as u8, cc_config.channel)?;
+            }
+        }
+
+        for (hid_code, program_config) in &self.config.program_change_keys {
+            let pressed = self.config.is_key_active(self.enable_state, hid_code)
+                && analog_data
+                    .get(&hid_code.to_u16().unwrap())
+                    .is_some_and(|&v| v > program_config.threshold);
+            if !pressed {
+                self.held_program_keys.remove(hid_code);
+            } else if self.held_program_keys.insert(hid_code.clone()) {
+                if let Some((bank_msb, bank_lsb)) = program_config.bank {
+                    sink.control_change(CC_BANK_SELECT_MSB, bank_msb, program_config.channel)?;
+                    sink.control_change(CC_BANK_SELECT_LSB, bank_lsb, program_config.channel)?;
+                }
+                sink.program_change(program_config.program, program_config.channel)?;
+            }
+        }
+
+        if self.enable_state == EnableState::Off {
+            update_channel_pressure(
+                &self.config,
+                &self.key_states,
+                &mut self.channel_pressure,
+                &mut sink,
+            )?;
+            return Ok(counting_sink.count);
+        }
+
+        let modifier_pressed = self.config.modifier_keys.iter().any(is_down);
+        let quantize_to = match (&self.config.quantize, &self.clock_input) {
+            (Some(quantize), Some(clock_input)) => {
+                clock_input.next_grid_point(quantize.grid_ticks, now)
+            }
+            _ => None,
+        };
+
+        for (hid_code, state) in &mut self.key_states {
+            if !self.config.is_key_active(self.enable_state, hid_code) {
+                continue;
+            }
+            if let Some(key_config) = self.config.key_configs.get(hid_code) {
+                let new_value = state.smooth_input(
+                    self.config.input_smoothing,
+                    key_config.calibrate(
+                        analog_data
+                            .get(&hid_code.to_u16().unwrap())
+                            .copied()
+                            .unwrap_or(0.0),
+                    ),
+                );
+
+                let shifted_amount = (modifier_pressed as i8 * key_config.shift_amount)
+                    .saturating_add(self.config.transpose)
+                    .saturating_add(self.transpose);
+
+                let was_pressed = state.pressed;
+                let previous_note = state.sounding_note;
+                let context = KeyContext {
+                    shifted_amount,
+                    threshold_delta: self.threshold_delta,
+                    quantize_to,
+                    now,
+                };
+                let key = hid_code.to_u16().unwrap();
+                let mut sink = SharedNoteSink::new(&mut sink, &mut self.shared_notes, key);
+                state.update_value(&self.config, key_config, new_value, &mut sink, context)?;
+
+                let transition = match (was_pressed, state.pressed) {
+                    (false, true) => state.sounding_note.map(|note| (RecordKind::NoteOn, note)),
+                    (true, false) => previous_note.map(|note| (RecordKind::NoteOff, note)),
+                    _ => None,
+                };
+                if let Some((kind, note)) = transition {
+                    self.event_log.record(Record {
+                        key: hid_code.clone(),
+                        kind,
+                        note,
+                        middle_c: self.config.middle_c,
+                        value: state.press_velocity,
+                        press_duration: state.lower_press.map(|(time, _)| time.elapsed()),
+                        tick: self.tick,
+                    });
+                }
+
+                if let Some(samples) = &mut self.velocity_calibration {
+                    if state.pressed && !was_pressed {
+                        samples
+                            .entry(hid_code.clone())
+                            .or_default()
+                            .push(state.curved_velocity(key_config));
+                    }
+                }
+            }
+        }
+
+        update_channel_pressure(
+            &self.config,
+            &self.key_states,
+            &mut self.channel_pressure,
+            &mut sink,
+        )?;
+        Ok(counting_sink.count)
+    }
+
+    /// Silences everything: sends note offs for all held keys, then all notes off and all sound
+    /// off on every configured channel. Keys still held afterwards trigger again.
+    pub fn all_notes_off(&mut self) -> Result<()> {
+        let Some(connection) = &mut self.connection else {
+            return Ok(());
+        };
+        info!("Sending all notes off");
+
+        let mut tuning_sink = TuningSink::new(connection, &self.config);
+        let mut voice_sink =
+            VoiceLimitSink::new(&mut tuning_sink, &mut self.voice_limiter, &self.config);
+        let mut sink = MpeSink::new(&mut voice_sink, &mut self.mpe_channels, &self.config);
+        self.mono_voices.release(&mut sink)?;
+        let mut mono_sink = MonoSink::new(&mut sink, &mut self.mono_voices, &self.config);
+        self.arpeggiator.flush(&mut mono_sink)?;
+        let mut arp_sink = ArpSink::new(&mut mono_sink, &mut self.arpeggiator, &self.config);
+        for (hid_code, state) in &mut self.key_states {
+            if let Some(key_config) = self.config.key_configs.get(hid_code) {
+                // Keys sharing a note end it once, with the last of them
+                let key = hid_code.to_u16().unwrap();
+                let mut sink = SharedNoteSink::new(&mut arp_sink, &mut self.shared_notes, key);
+                state.release_all(key_config, &mut sink)?;
+            }
+            *state = KeyState::new();
+        }
+        if self.sustain_down {
+            if let Some(sustain) = &self.config.sustain {
+                sink.control_change(CC_SUSTAIN, 0, sustain.channel)?;
+            }
+            self.sustain_down = false;
+        }
+        for channel in self.config.channels() {
+            sink.control_change(CC_ALL_NOTES_OFF, 0, channel)?;
+            sink.control_change(CC_ALL_SOUND_OFF, 0, channel)?;
+        }
+        self.mpe_channels.clear();
+        self.mono_voices.clear();
+        self.shared_notes.clear();
+        self.voice_limiter.clear();
+        self.arpeggiator.clear();
+        Ok(())
+    }
+
+    /// Number of hot path log records dropped because the logging thread fell behind
+    pub fn dropped_log_records(&self) -> u64 {
+        self.event_log.dropped()
+    }
+
+    /// Config in effect, with the active preset and the channel override applied
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Config as it was set, with presets and the channel override kept apart. This is the one
+    /// to save, calibration results and applied nudges are written back into it.
+    pub fn source_config(&self) -> &Config {
+        &self.source_config
+    }
+
+    /// Names of the presets of the current config, in alphabetical order
+    pub fn preset_names(&self) -> Vec<&str> {
+        self.config.presets.keys().map(String::as_str).collect()
+    }
+
+    pub fn active_preset(&self) -> Option<&str> {
+        self.config.default_preset.as_deref()
+    }
+
+    /// Swaps in the key configs of a preset, releasing held notes but keeping the connection
+    pub fn activate_preset(&mut self, name: &str) -> Result<()> {
+        if !self.config.presets.contains_key(name) {
+            bail!("There is no preset named \"{}\"", name);
+        }
+        let mut config = self.source_config.clone();
+        config.default_preset = Some(name.to_owned());
+        self.set_config(config)?;
+        info!("Activated preset {}", name);
+        Ok(())
+    }
+
+    /// Moves all keys to one channel, or back to their own channels with `None`. Held notes are
+    /// released on the channel they started on.
+    pub fn set_default_channel(&mut self, channel: Option<Channel>) -> Result<()> {
+        let mut config = self.source_config.clone();
+        config.default_channel = channel;
+        self.set_config(config)
+    }
+
+    /// Snapshots of all configured keys, ordered by HID code
+    pub fn key_states(&self) -> Vec<KeyStateSnapshot> {
+        let mut snapshots: Vec<KeyStateSnapshot> = self
+            .key_states
+            .iter()
+            .map(|(hid_code, state)| KeyStateSnapshot {
+                hid_code: hid_code.clone(),
+                value: state.current_value,
+                pressed: state.pressed,
+                velocity: state.press_velocity,
+                note: state.sounding_note.or(state.latched_note),
+            })
+            .collect();
+        snapshots.sort_by_key(|snapshot| snapshot.hid_code.to_u16());
+        snapshots
+    }
+
+    /// Starts collecting the velocities of every triggered note, play each key a few times at
+    /// normal strength before finishing
+    pub fn start_velocity_calibration(&mut self) {
+        info!("Starting velocity calibration");
+        self.velocity_calibration = Some(FxHashMap::default());
+    }
+
+    /// Computes per-key gains that equalize the median velocities of all played keys and
+    /// applies them to the config
+    pub fn finish_velocity_calibration(&mut self) -> HashMap<HIDCodes, f32> {
+        let samples = self.velocity_calibration.take().unwrap_or_default();
+        let medians: HashMap<HIDCodes, f32> = samples
+            .into_iter()
+            .filter_map(|(hid_code, velocities)| Some((hid_code, median(velocities)?)))
+            .filter(|(_, median)| *median > 0.0)
+            .collect();
+        let Some(target) = median(medians.values().copied().collect()) else {
+            info!("Velocity calibration finished without any samples");
+            return HashMap::new();
+        };
+
+        let gains: HashMap<HIDCodes, f32> = medians
+            .into_iter()
+            .map(|(hid_code, median)| (hid_code, target / median))
+            .collect();
+        for (hid_code, &gain) in &gains {
+            self.update_key_config(hid_code, |key_config| key_config.velocity_gain = gain);
+        }
+        info!("Velocity calibration finished for {} keys", gains.len());
+        gains
+    }
+
+    /// Silences everything and starts learning the travel range of every configured key
+    /// instead of playing them, press each key all the way down once before finishing
+    pub fn start_range_calibration(&mut self) -> Result<()> {
+        self.all_notes_off()?;
+        info!("Starting range calibration");
+        self.range_calibration = Some(FxHashMap::default());
+        Ok(())
+    }
+
+    /// Configured keys that have not been pressed far enough yet, `None` outside of range
+    /// calibration
+    pub fn range_calibration_pending(&self) -> Option<Vec<HIDCodes>> {
+        let ranges = self.range_calibration.as_ref()?;
+        Some(
+            self.config
+                .key_configs
+                .keys()
+                .filter(|hid_code| !ranges.get(*hid_code).is_some_and(is_calibrated_range))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Applies the learned ranges of all fully pressed keys to the config as their calibration
+    /// window and returns them
+    pub fn finish_range_calibration(&mut self) -> HashMap<HIDCodes, (f32, f32)> {
+        let ranges: HashMap<HIDCodes, (f32, f32)> = self
+            .range_calibration
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, range)| is_calibrated_range(range))
+            .collect();
+        for (hid_code, &(min, max)) in &ranges {
+            self.update_key_config(hid_code, |key_config| {
+                key_config.calibration_min = min;
+                key_config.calibration_max = max;
+            });
+        }
+        info!("Range calibration finished for {} keys", ranges.len());
+        ranges
+    }
+
+    /// Changes a key in the active config and in the source config, in the entry of the active
+    /// preset if there is one, so the change survives switching presets or channels
+    fn update_key_config(&mut self, hid_code: &HIDCodes, update: impl Fn(&mut KeyConfig)) {
+        if let Some(key_config) = self.config.key_configs.get_mut(hid_code) {
+            update(key_config);
+        }
+        let source = &mut self.source_config;
+        let key_configs = match &source.default_preset {
+            Some(name) => match source.presets.get_mut(name) {
+                Some(preset) => &mut preset.key_configs,
+                None => return,
+            },
+            None => &mut source.key_configs,
+        };
+        if let Some(key_config) = key_configs.get_mut(hid_code) {
+            update(key_config);
+        }
+    }
+
+    pub fn enable_state(&self) -> EnableState {
+        self.enable_state
+    }
+
+    /// Global transpose in semitones, applied on top of the modifier shift
+    pub fn transpose(&self) -> i8 {
+        self.transpose
+    }
+
+    /// Sets the global transpose, held notes keep their pitch until released
+    pub fn set_transpose(&mut self, transpose: i8) {
+        self.transpose = transpose.clamp(-TRANSPOSE_MAX, TRANSPOSE_MAX);
+        info!("Transpose set to {:+}", self.transpose);
+    }
+
+    /// Runtime offset currently applied to every key's threshold
+    pub fn threshold_delta(&self) -> f32 {
+        self.threshold_delta
+    }
+
+    /// Writes the runtime threshold nudge into the config, so it is kept when the config is saved
+    pub fn apply_threshold_nudge(&mut self) {
+        let delta = self.threshold_delta;
+        let hid_codes: Vec<HIDCodes> = self.config.key_configs.keys().cloned().collect();
+        for hid_code in &hid_codes {
+            self.update_key_config(hid_code, |key_config| {
+                key_config.threshold = key_config.nudged_threshold(delta);
+            });
+        }
+        self.threshold_delta = 0.0;
+        for state in self.key_states.values_mut() {
+            state.threshold_delta = 0.0;
+        }
+    }
+
+    pub fn init(&mut self) -> Result<u32> {
+        info!("Starting Wooting Analog SDK!");
+        let init_result: SDKResult<u32> = sdk::initialise();
+        let device_num = init_result
+            .0
+            .context("Wooting Analog SDK Failed to initialise")?;
+
+        info!("Analog SDK Successfully initialised with {device_num} devices");
+        let devices = sdk::get_connected_devices_info(DEVICE_BUFFER_MAX).0?;
+        for (i, device) in devices.iter().enumerate() {
+            info!("Device {} is {:?}", i, device);
+        }
+
+        self.refresh_port_options();
+
+        if !self.port_options.is_empty() {
+            info!("Opening connection");
+            self.select_port(0)?;
+        } else {
+            info!("No output ports available!");
+        }
+
+        Ok(device_num)
+    }
+
+    pub fn refresh_port_options(&mut self) {
+        let midi_output = MidiOutput::new(MIDI_CLIENT_NAME).unwrap();
+        self.port_options = midi_output
+            .ports()
+            .into_iter()
+            .map(|port| {
+                let name = midi_output.port_name(&port).unwrap();
+                PortOption { port, name }
+            })
+            .collect();
+        info!(
+            "We have {} ports available! ({:?})",
+            self.port_options.len(),
+            self.port_options
+                .iter()
+                .map(|port| &port.name)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    pub fn select_port(&mut self, option: usize) -> Result<()> {
+        if option >= self.port_options.len() {
+            bail!("Port option out of range!");
+        }
+
+        drop(self.connection.take());
+        self.clock = None;
+
+        let selection = &self.port_options[option];
+        info!("Connecting to Port {}: \"{}\"!", option, selection.name);
+
+        let midi_output = MidiOutput::new(MIDI_CLIENT_NAME).unwrap();
+        let connection = midi_output
+            .connect(&selection.port, MIDI_PORT_NAME)
+            .map_err(|e| anyhow!("Error: {}", e))?;
+
+        self.open_output(Output::Port(connection))
+    }
+
+    /// Sends everything to a UMP stream instead of a port, e.g. a virtual UMP endpoint, file or
+    /// pipe. Selecting a port switches back to regular MIDI 1.0 output.
+    #[cfg(feature = "midi2")]
+    pub fn select_ump_output(
+        &mut self,
+        writer: Box<dyn std::io::Write + Send>,
+        protocol: ump::Protocol,
+    ) -> Result<()> {
+        if let Some(output) = self.connection.take() {
+            output.close();
+        }
+        self.clock = None;
+        info!("Switching to UMP output using {:?}", protocol);
+
+        self.open_output(Output::Ump(ump::UmpSink::new(writer, protocol)))
+    }
+
+    fn open_output(&mut self, mut connection: Output) -> Result<()> {
+        if self.config.reset_controllers_on_switch {
+            for channel in self.config.channels() {
+                connection.reset_controllers(channel, &self.config.controller_defaults)?;
+            }
+        }
+        if self.config.mpe {
+            send_mpe_configuration(&mut connection, &self.config)?;
+        }
+        if let Some(range) = self.config.pitch_bend_range_semitones {
+            for channel in self.config.channels() {
+                connection.registered_parameter(RPN_PITCH_BEND_RANGE, (range, 0), channel)?;
+            }
+        }
+        send_channel_setup(&mut connection, &self.config.channel_setup)?;
+        if let Some(bpm) = self.config.clock_bpm {
+            self.clock = Some(MidiClock::start(bpm, Instant::now(), &mut connection)?);
+        }
+        self.mpe_channels.clear();
+        self.mono_voices.clear();
+        self.shared_notes.clear();
+        self.voice_limiter.clear();
+        self.arpeggiator.clear();
+        self.connection = Some(connection);
+
+        Ok(())
+    }
+
+    pub fn send_channel_setup(&mut self) -> Result<()> {
+        if let Some(connection) = &mut self.connection {
+            send_channel_setup(connection, &self.config.channel_setup)?;
+        }
+        Ok(())
+    }
+
+    pub fn uninit(&mut self) {
+        info!("Uninitialising MidiService");
+        sdk::uninitialise();
+        trace!("Sdk uninit done");
+        self.clock_input = None;
+        if let Some(mut output) = self.connection.take() {
+            if let (true, Some(sustain)) = (self.sustain_down, &self.config.sustain) {
+                if let Err(err) = output.control_change(CC_SUSTAIN, 0, sustain.channel) {
+                    warn!("Failed to release sustain: {err:?}");
+                }
+                self.sustain_down = false;
+            }
+            if let Some(clock) = self.clock.take() {
+                if let Err(err) = clock.stop(&mut output) {
+                    warn!("Failed to stop clock: {err:?}");
+                }
+            }
+            output.close();
+        }
+        trace!("MidiService uninit complete");
+    }
+}
+
+/// Generators are separate per use, so one of them drawing never shifts the sequence of another
+fn new_rng(seed: Option<u64>) -> SmallRng {
+    match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    }
+}
+
+fn repeat_interval(config: &Config, repeat: &NoteRepeatConfig) -> Duration {
+    let bpm = repeat.bpm.unwrap_or_else(|| config.tempo());
+    Duration::from_secs_f32(60.0 / (bpm * repeat.rate.per_beat()))
+}
+
+fn send_note_on(
+    config: &Config,
+    sink: &mut impl NoteSink,
+    note_id: NoteID,
+    velocity: f32,
+    channel: Channel,
+) -> Result<()> {
+    if config.high_resolution_velocity {
+        sink.note_on_high_resolution(note_id, velocity, channel)
+    } else {
+        sink.note_on(note_id, velocity, channel)
+    }
+}
+
+/// Maps the time between the two contact points linearly onto velocities from 1.0 down to the
+/// softest one of 1/127
+fn contact_time_velocity(elapsed: Duration, fastest_ms: f32, slowest_ms: f32) -> f32 {
+    let ms = elapsed.as_secs_f32() * 1000.0;
+    let position = ((ms - fastest_ms) / (slowest_ms - fastest_ms)).clamp(0.0, 1.0);
+    1.0 - position * (1.0 - 1.0 / 127.0)
+}
+
+/// Depth change per second fitted through the samples, `None` without two distinct times
+fn least_squares_slope(samples: &VecDeque<(Instant, f32)>) -> Option<f32> {
+    let &(start, _) = samples.front()?;
+    let n = samples.len() as f32;
+    let times = || {
+        samples
+            .iter()
+            .map(|&(time, _)| (time - start).as_secs_f32())
+    };
+    let mean_time = times().sum::<f32>() / n;
+    let mean_depth = samples.iter().map(|&(_, depth)| depth).sum::<f32>() / n;
+    let (covariance, variance) = samples.iter().zip(times()).fold(
+        (0.0, 0.0),
+        |(covariance, variance), (&(_, depth), time)| {
+            let dt = time - mean_time;
+            (covariance + dt * (depth - mean_depth), variance + dt * dt)
+        },
+    );
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+/// Whether a learned range spans enough travel to be a full press
+fn is_calibrated_range(&(min, max): &(f32, f32)) -> bool {
+    max - min >= CALIBRATION_MIN_TRAVEL
+}
+
+fn send_channel_setup(
+    sink: &mut impl NoteSink,
+    channel_setup: &FxHashMap<Channel, ChannelSetup>,
+) -> Result<()> {
+    let mut channels: Vec<&Channel> = channel_setup.keys().collect();
+    channels.sort_unstable();
+    for channel in channels {
+        let setup = &channel_setup[channel];
+        if let Some(bank_msb) = setup.bank_msb {
+            sink.control_change(CC_BANK_SELECT_MSB, bank_msb, *channel)?;
+        }
+        if let Some(bank_lsb) = setup.bank_lsb {
+            sink.control_change(CC_BANK_SELECT_LSB, bank_lsb, *channel)?;
+        }
+        if let Some(program) = setup.program {
+            sink.program_change(program, *channel)?;
+        }
+        for &(controller, value) in &setup.cc_defaults {
+            sink.control_change(controller, value, *channel)?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends the deepest or average sounding key of every channel as channel pressure whenever its
+/// 7-bit value changes, falling back to zero once a channel has no held keys left
+fn update_channel_pressure(
+    config: &Config,
+    key_states: &FxHashMap<HIDCodes, KeyState>,
+    sent_pressure: &mut FxHashMap<Channel, u8>,
+    sink: &mut impl NoteSink,
+) -> Result<()> {
+    let aggregate = match (config.aftertouch_mode, config.channel_pressure) {
+        (_, Some(aggregate)) => aggregate,
+        (AftertouchMode::Channel, None) => PressureAggregate::Max,
+        (AftertouchMode::Polyphonic, None) => return Ok(()),
+    };
+
+    // Sum or maximum and the number of keys of each channel
+    let mut pressures: FxHashMap<Channel, (f32, u32)> = FxHashMap::default();
+    for (hid_code, state) in key_states {
+        if !state.pressed || state.pending_note_on.is_some() {
+            continue;
+        }
+        if let Some(key_config) = config
+            .key_configs
+            .get(hid_code)
+            .filter(|key_config| key_config.aftertouch)
+        {
+            let (pressure, count) = pressures.entry(key_config.channel).or_default();
+            *pressure = match aggregate {
+                PressureAggregate::Max => pressure.max(state.smoothed_pressure),
+                PressureAggregate::Average => *pressure + state.smoothed_pressure,
+            };
+            *count += 1;
+        }
+    }
+
+    for (&channel, &(pressure, count)) in &pressures {
+        let pressure = match aggregate {
+            PressureAggregate::Max => pressure,
+            PressureAggregate::Average => pressure / count as f32,
+        };
+        let value = unit_to_byte(pressure);
+        if sent_pressure.get(&channel) != Some(&value) {
+            sink.channel_pressure(pressure, channel)?;
+            sent_pressure.insert(channel, value);
+        }
+    }
+    let released: Vec<Channel> = sent_pressure
+        .keys()
+        .filter(|channel| !pressures.contains_key(channel))
+        .copied()
+        .collect();
+    for channel in released {
+        sink.channel_pressure(0.0, channel)?;
+        sent_pressure.remove(&channel);
+    }
+    Ok(())
+}
+
+/// Sums the paddles of every channel into a bend, returning to the center once all are released
+fn update_pitch_bend(
+    config: &Config,
+    enable_state: EnableState,
+    analog_data: &HashMap<u16, f32>,
+    sent_bend: &mut FxHashMap<Channel, f32>,
+    sink: &mut impl NoteSink,
+) -> Result<()> {
+    let mut bends: FxHashMap<Channel, f32> = FxHashMap::default();
+    for (hid_code, bend_config) in &config.pitch_bend_keys {
+        if !config.is_key_active(enable_state, hid_code) {
+            continue;
+        }
+        let depth = analog_data
+            .get(&hid_code.to_u16().unwrap())
+            .copied()
+            .unwrap_or(0.0);
+        if depth > 0.0 {
+            let bend = bends.entry(bend_config.channel).or_default();
+            match bend_config.direction {
+                BendDirection::Up => *bend += depth,
+                BendDirection::Down => *bend -= depth,
+            }
+        }
+    }
+
+    for (&channel, &bend) in &bends {
+        let bend = bend.clamp(-1.0, 1.0);
+        if sent_bend.get(&channel) != Some(&bend) {
+            sink.pitch_bend(bend, channel)?;
+            sent_bend.insert(channel, bend);
+        }
+    }
+    let released: Vec<Channel> = sent_bend
+        .keys()
+        .filter(|channel| !bends.contains_key(channel))
+        .copied()
+        .collect();
+    for channel in released {
+        sink.pitch_bend(0.0, channel)?;
+        sent_bend.remove(&channel);
+    }
+    Ok(())
+}
+
+/// Returns whether the hook went over its budget, which is logged as a warning
+fn run_poll_hook(hook: &mut PollHook, context: &PollContext, name: &str) -> bool {
+    let start = Instant::now();
+    hook(context);
+    let elapsed = start.elapsed();
+    let over_budget = elapsed > POLL_HOOK_BUDGET;
+    if over_budget {
+        warn!(
+            "{} hook took {:?}, exceeding its budget of {:?}",
+            name, elapsed, POLL_HOOK_BUDGET
+        );
+    }
+    over_budget
+}
+
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f32::total_cmp);
+    let middle = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+impl Drop for MidiService {
+    fn drop(&mut self) {
+        self.uninit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        CcConfig, EarlyReleaseConfig, OutOfScale, PitchBendConfig, Preset, RepeatRate, ScaleConfig,
+        ScaleKind, SoftHoldConfig, StrumConfig, SustainConfig, VelocityCurve,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn key(note_id: NoteID) -> KeyConfig {
+        KeyConfig {
+            note_id,
+            ..Default::default()
+        }
+    }
+
+    fn context(shifted_amount: i8, now: Instant) -> KeyContext {
+        KeyContext {
+            shifted_amount,
+            threshold_delta: 0.0,
+            quantize_to: None,
+            now,
+        }
+    }
+
+    /// Velocity follows the depth of the reading past the threshold, independent of timing
+    fn depth_key(note_id: NoteID) -> KeyConfig {
+        KeyConfig {
+            velocity_estimation: VelocityEstimation::Depth { window_ms: 0 },
+            ..key(note_id)
+        }
+    }
+
+    /// Feeds readings to the key one poll at a time, returning every message sent
+    fn play(
+        state: &mut KeyState,
+        config: &Config,
+        key_config: &KeyConfig,
+        values: &[f32],
+    ) -> Vec<Vec<u8>> {
+        play_shifted(state, config, key_config, 0, values)
+    }
+
+    /// Note and velocity byte of every note on
+    fn note_ons(messages: &[Vec<u8>]) -> Vec<(NoteID, u8)> {
+        messages
+            .iter()
+            .filter(|message| message[0] & 0xF0 == 0x90)
+            .map(|message| (message[1], message[2]))
+            .collect()
+    }
+
+    /// Runs one tick of the service with the given readings, returning every message sent
+    fn tick(service: &mut MidiService, readings: &[(HIDCodes, f32)]) -> Vec<Vec<u8>> {
+        let analog_data: HashMap<u16, f32> = readings
+            .iter()
+            .map(|(hid_code, value)| (hid_code.to_u16().unwrap(), *value))
+            .collect();
+        let mut sink = Vec::new();
+        service
+            .process_analog(&mut sink, || Ok(analog_data))
+            .unwrap();
+        sink
+    }
+
+    fn service_with_preset() -> MidiService {
+        let mut config = Config::default();
+        config.key_configs.insert(HIDCodes::A, key(60));
+        config.key_configs.insert(HIDCodes::S, key(62));
+        let lead = Preset {
+            key_configs: [(HIDCodes::A, key(72)), (HIDCodes::S, key(74))]
+                .into_iter()
+                .collect(),
+        };
+        config.presets.insert("lead".to_owned(), lead);
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.activate_preset("lead").unwrap();
+        service
+    }
+
+    #[test]
+    fn calibration_survives_preset_and_channel_switches() {
+        let mut service = service_with_preset();
+        service.velocity_calibration = Some(
+            [(HIDCodes::A, vec![0.4]), (HIDCodes::S, vec![0.8])]
+                .into_iter()
+                .collect(),
+        );
+        service.finish_velocity_calibration();
+        service.range_calibration = Some([(HIDCodes::A, (0.1, 0.9))].into_iter().collect());
+        service.finish_range_calibration();
+        service.set_default_channel(Some(3)).unwrap();
+        service.activate_preset("lead").unwrap();
+
+        let active = &service.config().key_configs[&HIDCodes::A];
+        assert!((active.velocity_gain - 1.5).abs() < 1e-6);
+        assert_eq!((active.calibration_min, active.calibration_max), (0.1, 0.9));
+        assert_eq!(active.channel, 3);
+
+        let source = service.source_config();
+        let lead = &source.presets["lead"].key_configs;
+        assert!((lead[&HIDCodes::S].velocity_gain - 0.75).abs() < 1e-6);
+        assert_eq!(lead[&HIDCodes::A].channel, 0);
+        assert_eq!(source.key_configs[&HIDCodes::A].velocity_gain, 1.0);
+    }
+
+    #[test]
+    fn applied_nudge_survives_preset_switches() {
+        let mut service = service_with_preset();
+        service.threshold_delta = 0.1;
+        service.apply_threshold_nudge();
+        service.activate_preset("lead").unwrap();
+
+        assert!((service.config().key_configs[&HIDCodes::A].threshold - 0.9).abs() < 1e-6);
+        let source = service.source_config();
+        assert!((source.presets["lead"].key_configs[&HIDCodes::S].threshold - 0.9).abs() < 1e-6);
+        assert_eq!(source.key_configs[&HIDCodes::S].threshold, 0.8);
+    }
+
+    fn pool_key(pool_seed: u64, humanize_seed: u64, humanize_velocity: f32) -> KeyConfig {
+        KeyConfig {
+            note_pool: Some(NotePool {
+                notes: (60..72).collect(),
+                no_repeat: false,
+                seed: Some(pool_seed),
+            }),
+            humanize_velocity,
+            humanize_seed: Some(humanize_seed),
+            ..depth_key(60)
+        }
+    }
+
+    fn play_presses(key_config: &KeyConfig) -> Vec<(NoteID, u8)> {
+        let mut state = KeyState::new();
+        let presses = [0.0, 0.9].repeat(16);
+        note_ons(&play(&mut state, &Config::default(), key_config, &presses))
+    }
+
+    #[test]
+    fn humanize_leaves_the_pool_sequence_alone() {
+        let plain: Vec<NoteID> = play_presses(&pool_key(7, 1, 0.0))
+            .into_iter()
+            .map(|(note, _)| note)
+            .collect();
+        let humanized: Vec<NoteID> = play_presses(&pool_key(7, 1, 0.3))
+            .into_iter()
+            .map(|(note, _)| note)
+            .collect();
+        assert_eq!(plain.len(), 16);
+        assert_eq!(plain, humanized);
+    }
+
+    #[test]
+    fn humanize_follows_its_own_seed() {
+        let velocities = |pool_seed, humanize_seed| -> Vec<u8> {
+            play_presses(&pool_key(pool_seed, humanize_seed, 0.3))
+                .into_iter()
+                .map(|(_, velocity)| velocity)
+                .collect()
+        };
+        assert_eq!(velocities(1, 5), velocities(2, 5));
+        assert_ne!(velocities(1, 5), velocities(1, 6));
+        assert!(velocities(1, 5).iter().any(|&velocity| velocity != 64));
+    }
+
+    #[test]
+    fn velocity_steps_snap_after_trim_and_scaling() {
+        let key_config = KeyConfig {
+            velocity_steps: Some(4),
+            velocity_trim: 10,
+            ..depth_key(60)
+        };
+        // Depth velocity 0.5 plus the trim lands in the third layer
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.9],
+        );
+        assert_eq!(note_ons(&messages), [(60, 79)]);
+
+        let key_config = KeyConfig {
+            velocity_steps: Some(4),
+            velocity_min: 64,
+            velocity_max: 127,
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.9],
+        );
+        assert_eq!(note_ons(&messages), [(60, 111)]);
+    }
+
+    #[test]
+    fn velocity_steps_snap_measured_releases() {
+        let key_config = KeyConfig {
+            velocity_steps: Some(4),
+            release_velocity_scale: Some(1000.0),
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.9, 0.5],
+        );
+        assert_eq!(messages.last().unwrap(), &[0x80, 60, 16]);
+    }
+
+    #[test]
+    fn velocity_gain_applies_after_the_curve() {
+        let curve = VelocityCurve::Exponential(4.0);
+        let key_config = KeyConfig {
+            velocity_curve: Some(curve),
+            velocity_gain: 2.0,
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.9],
+        );
+        let expected = (curve.apply(0.5) * 2.0 * 127.0).round() as u8;
+        assert!(expected < 127);
+        assert_eq!(note_ons(&messages), [(60, expected)]);
+    }
+
+    #[test]
+    fn velocity_calibration_equalizes_medians() {
+        let mut config = Config::default();
+        for (hid_code, note) in [(HIDCodes::A, 60), (HIDCodes::S, 62), (HIDCodes::D, 64)] {
+            config.key_configs.insert(hid_code, depth_key(note));
+        }
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        service.start_velocity_calibration();
+        // Depths past the 0.8 threshold, a quarter of the remaining travel is 0.25 velocity
+        let presses = [
+            (HIDCodes::A, [0.85, 0.85, 0.9]),
+            (HIDCodes::S, [0.9, 1.0, 0.9]),
+            (HIDCodes::D, [0.95, 0.95, 1.0]),
+        ];
+        for (hid_code, depths) in &presses {
+            for &depth in depths {
+                tick(&mut service, &[(hid_code.clone(), depth)]);
+                tick(&mut service, &[(hid_code.clone(), 0.0)]);
+            }
+        }
+        let captured = service.velocity_calibration.clone().unwrap();
+        let medians: Vec<f32> = presses
+            .iter()
+            .map(|(hid_code, _)| median(captured[hid_code].clone()).unwrap())
+            .collect();
+        for (median, expected) in medians.iter().zip([0.25, 0.5, 0.75]) {
+            assert!((median - expected).abs() < 1e-4, "{:?}", medians);
+        }
+
+        let gains = service.finish_velocity_calibration();
+        assert_eq!(gains.len(), 3);
+        for ((hid_code, _), median) in presses.iter().zip(medians) {
+            let gain = gains[hid_code];
+            assert!((median * gain - 0.5).abs() < 1e-6, "{:?}", hid_code);
+            assert_eq!(service.config().key_configs[hid_code].velocity_gain, gain);
+        }
+        // The softest key doubles, half of the remaining travel now plays at full velocity
+        assert_eq!(
+            note_ons(&tick(&mut service, &[(HIDCodes::A, 0.9)])),
+            [(60, 127)]
+        );
+    }
+
+    #[test]
+    fn press_within_one_poll_is_full_velocity() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key(60),
+            &[0.0, 0.95],
+        );
+        assert_eq!(note_ons(&messages), [(60, 127)]);
+    }
+
+    #[test]
+    fn press_within_one_poll_uses_the_poll_interval() {
+        let key_config = KeyConfig {
+            velocity_scale: 400.0,
+            ..key(60)
+        };
+        for pause in [Duration::from_millis(6), Duration::from_millis(30)] {
+            let mut state = KeyState::new();
+            let mut messages = play(&mut state, &Config::default(), &key_config, &[0.0]);
+            std::thread::sleep(pause);
+            messages.extend(play(&mut state, &Config::default(), &key_config, &[0.95]));
+            // Both pauses count as one tick, 0.95 travels in 5ms at 400 travels per second
+            assert_eq!(note_ons(&messages), [(60, 60)]);
+        }
+        // Faster polling measures a shorter press
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.95],
+        );
+        assert_eq!(note_ons(&messages), [(60, 127)]);
+    }
+
+    #[test]
+    fn poll_hooks_run_around_the_key_poll() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| -> PollHook {
+            let calls = calls.clone();
+            Box::new(move |context: &PollContext| {
+                calls.lock().unwrap().push(format!(
+                    "{} {} {}",
+                    name, context.tick, context.events_emitted
+                ));
+            })
+        };
+        let mut service = MidiService::new();
+        service.set_pre_poll_hook(record("pre"));
+        service.set_post_poll_hook(record("post"));
+        for events in [3, 0] {
+            let calls = calls.clone();
+            service
+                .poll_with(|_| {
+                    calls.lock().unwrap().push("keys".to_owned());
+                    Ok(events)
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["pre 0 0", "keys", "post 0 3", "pre 1 0", "keys", "post 1 0"]
+        );
+
+        // A failed key poll skips the post-poll hook
+        service.clear_poll_hooks();
+        service.set_post_poll_hook(record("post"));
+        assert!(service.poll_with(|_| Err(anyhow!("failed"))).is_err());
+        assert_eq!(calls.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn slow_poll_hooks_are_reported() {
+        let context = PollContext {
+            timestamp: Instant::now(),
+            tick: 0,
+            enable_state: EnableState::Off,
+            events_emitted: 0,
+        };
+        let mut quick: PollHook = Box::new(|_| {});
+        assert!(!run_poll_hook(&mut quick, &context, "Quick"));
+        let mut slow: PollHook = Box::new(|_| std::thread::sleep(POLL_HOOK_BUDGET * 2));
+        assert!(run_poll_hook(&mut slow, &context, "Slow"));
+    }
+
+    #[test]
+    fn toggle_key_cycles_through_partial_scope() {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            enable_cycle: vec![EnableState::Off, EnableState::Partial, EnableState::Full],
+            partial_scope: vec![HIDCodes::S],
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        config.key_configs.insert(HIDCodes::S, depth_key(62));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        let held = [(HIDCodes::A, 1.0), (HIDCodes::S, 1.0)];
+        let toggled = [(HIDCodes::A, 1.0), (HIDCodes::S, 1.0), (HIDCodes::F12, 1.0)];
+
+        tick(&mut service, &[(HIDCodes::F12, 1.0)]);
+        tick(&mut service, &[]);
+        assert_eq!(service.enable_state(), EnableState::Partial);
+        assert_eq!(tick(&mut service, &held), [[0x90, 62, 127]]);
+
+        assert_eq!(tick(&mut service, &toggled), [[0x90, 60, 127]]);
+        assert_eq!(service.enable_state(), EnableState::Full);
+        assert!(tick(&mut service, &held).is_empty());
+
+        let mut messages = tick(&mut service, &toggled);
+        assert_eq!(service.enable_state(), EnableState::Off);
+        messages.sort();
+        assert_eq!(
+            messages,
+            [vec![0x80, 60, 0], vec![0x80, 62, 0], vec![0xB0, 123, 0]]
+        );
+    }
+
+    #[test]
+    fn pool_notes_avoid_repeats_and_release_what_they_played() {
+        let key_config = KeyConfig {
+            note_pool: Some(NotePool {
+                notes: vec![60, 63, 67],
+                no_repeat: true,
+                seed: Some(3),
+            }),
+            ..depth_key(48)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[1.0, 0.0].repeat(16),
+        );
+        let notes: Vec<NoteID> = note_ons(&messages).iter().map(|&(note, _)| note).collect();
+        assert_eq!(notes.len(), 16);
+        assert!(notes.iter().all(|note| [60, 63, 67].contains(note)));
+        assert!(notes.windows(2).all(|pair| pair[0] != pair[1]));
+        for pair in messages.chunks(2) {
+            assert_eq!(pair[1][..2], [0x80, pair[0][1]]);
+        }
+    }
+
+    #[test]
+    fn seeded_pools_repeat_their_sequence() {
+        let notes = |seed| -> Vec<NoteID> {
+            play_presses(&pool_key(seed, 0, 0.0))
+                .into_iter()
+                .map(|(note, _)| note)
+                .collect()
+        };
+        assert_eq!(notes(11), notes(11));
+        assert_ne!(notes(11), notes(12));
+    }
+
+    #[test]
+    fn velocity_trim_offsets_and_clamps() {
+        let trimmed = |velocity_trim, value| {
+            let key_config = KeyConfig {
+                velocity_trim,
+                ..depth_key(60)
+            };
+            let messages = play(
+                &mut KeyState::new(),
+                &Config::default(),
+                &key_config,
+                &[0.0, value],
+            );
+            note_ons(&messages)[0].1
+        };
+        assert_eq!(trimmed(-20, 1.0), 107);
+        assert_eq!(trimmed(20, 1.0), 127);
+        assert_eq!(trimmed(20, 0.85), 52);
+    }
+
+    #[test]
+    fn timing_offset_delays_the_note_on() {
+        let key_config = KeyConfig {
+            timing_offset_ms: 20,
+            ..depth_key(60)
+        };
+        let mut state = KeyState::new();
+        let config = Config::default();
+        assert!(play(&mut state, &config, &key_config, &[0.0, 1.0]).is_empty());
+        std::thread::sleep(Duration::from_millis(25));
+        // The velocity measured at the press is kept, however deep the key is by now
+        let messages = play(&mut state, &config, &key_config, &[0.9]);
+        assert_eq!(note_ons(&messages), [(60, 127)]);
+    }
+
+    #[test]
+    fn cancelled_offset_note_stays_silent() {
+        let key_config = KeyConfig {
+            timing_offset_ms: 20,
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 1.0, 0.0],
+        );
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn negative_timing_offsets_are_ignored() {
+        let mut config = Config::default();
+        let key_config = KeyConfig {
+            timing_offset_ms: -10,
+            ..key(60)
+        };
+        config.key_configs.insert(HIDCodes::A, key_config);
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        assert_eq!(
+            service.config().key_configs[&HIDCodes::A].timing_offset_ms,
+            0
+        );
+    }
+
+    /// Status and note of every message
+    fn events(messages: &[Vec<u8>]) -> Vec<(u8, u8)> {
+        messages
+            .iter()
+            .map(|message| (message[0], message[1]))
+            .collect()
+    }
+
+    fn early_release_key() -> KeyConfig {
+        KeyConfig {
+            early_release: Some(EarlyReleaseConfig {
+                ticks: 2,
+                min_slope: 0.02,
+            }),
+            aftertouch: false,
+            ..depth_key(60)
+        }
+    }
+
+    #[test]
+    fn sustained_rise_releases_above_the_threshold() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &early_release_key(),
+            &[0.0, 1.0, 0.95, 0.9, 0.88],
+        );
+        assert_eq!(events(&messages), [(0x90, 60), (0x80, 60)]);
+    }
+
+    #[test]
+    fn early_release_rearms_when_pushed_down_again() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &early_release_key(),
+            &[0.0, 1.0, 0.95, 0.9, 0.95],
+        );
+        assert_eq!(events(&messages), [(0x90, 60), (0x80, 60), (0x90, 60)]);
+    }
+
+    #[test]
+    fn interrupted_rise_keeps_the_note() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &early_release_key(),
+            &[0.0, 1.0, 0.95, 0.95, 0.9, 0.9],
+        );
+        assert_eq!(events(&messages), [(0x90, 60)]);
+    }
+
+    fn soft_hold_key() -> KeyConfig {
+        KeyConfig {
+            soft_hold: Some(SoftHoldConfig {
+                max_depth: 0.5,
+                hold_ms: 20,
+                note: 36,
+                velocity: 0.3,
+            }),
+            ..depth_key(60)
+        }
+    }
+
+    #[test]
+    fn light_long_hold_plays_the_soft_note() {
+        let (mut state, config, key_config) = (KeyState::new(), Config::default(), soft_hold_key());
+        assert!(play(&mut state, &config, &key_config, &[0.3]).is_empty());
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(
+            play(&mut state, &config, &key_config, &[0.3]),
+            [[0x90, 36, 38]]
+        );
+        assert_eq!(
+            play(&mut state, &config, &key_config, &[0.0]),
+            [[0x80, 36, 38]]
+        );
+    }
+
+    #[test]
+    fn reaching_the_threshold_cancels_the_soft_hold() {
+        let (mut state, config, key_config) = (KeyState::new(), Config::default(), soft_hold_key());
+        let messages = play(&mut state, &config, &key_config, &[0.3, 1.0]);
+        assert_eq!(events(&messages), [(0x90, 60)]);
+        std::thread::sleep(Duration::from_millis(25));
+        let messages = play(&mut state, &config, &key_config, &[0.3, 0.3]);
+        assert_eq!(events(&messages), [(0x80, 60)]);
+    }
+
+    #[test]
+    fn going_deeper_restarts_the_hold_time() {
+        let (mut state, config, key_config) = (KeyState::new(), Config::default(), soft_hold_key());
+        play(&mut state, &config, &key_config, &[0.3]);
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(play(&mut state, &config, &key_config, &[0.6, 0.3]).is_empty());
+    }
+
+    #[test]
+    fn channel_setup_is_sent_in_channel_order() {
+        let channel_setup: FxHashMap<Channel, ChannelSetup> = [
+            (
+                9,
+                ChannelSetup {
+                    program: Some(2),
+                    ..ChannelSetup::default()
+                },
+            ),
+            (
+                0,
+                ChannelSetup {
+                    bank_msb: Some(1),
+                    bank_lsb: Some(3),
+                    program: Some(4),
+                    cc_defaults: vec![(7, 100), (10, 64)],
+                },
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let mut sink = Vec::new();
+        send_channel_setup(&mut sink, &channel_setup).unwrap();
+        assert_eq!(
+            sink,
+            [
+                vec![0xB0, 0, 1],
+                vec![0xB0, 32, 3],
+                vec![0xC0, 4],
+                vec![0xB0, 7, 100],
+                vec![0xB0, 10, 64],
+                vec![0xC9, 2],
+            ]
+        );
+    }
+
+    /// Like `play`, with the given shift applied
+    fn play_shifted(
+        state: &mut KeyState,
+        config: &Config,
+        key_config: &KeyConfig,
+        shifted_amount: i8,
+        values: &[f32],
+    ) -> Vec<Vec<u8>> {
+        let mut sink = Vec::new();
+        for &value in values {
+            let context = context(shifted_amount, Instant::now());
+            state
+                .update_value(config, key_config, value, &mut sink, context)
+                .unwrap();
+        }
+        sink
+    }
+
+    /// Feeds readings polled at the given times after `start`, returning every message sent
+    fn play_at(
+        state: &mut KeyState,
+        config: &Config,
+        key_config: &KeyConfig,
+        start: Instant,
+        readings: &[(Duration, f32)],
+    ) -> Vec<Vec<u8>> {
+        let mut sink = Vec::new();
+        for &(offset, value) in readings {
+            state
+                .update_value(
+                    config,
+                    key_config,
+                    value,
+                    &mut sink,
+                    context(0, start + offset),
+                )
+                .unwrap();
+        }
+        sink
+    }
+
+    fn shifted_notes(policy: ShiftOutOfRange, note: NoteID, shift: i8) -> Vec<(u8, u8)> {
+        let config = Config {
+            shift_out_of_range: policy,
+            ..Config::default()
+        };
+        let messages = play_shifted(
+            &mut KeyState::new(),
+            &config,
+            &depth_key(note),
+            shift,
+            &[0.0, 1.0, 0.0],
+        );
+        events(&messages)
+    }
+
+    #[test]
+    fn out_of_range_shifts_fold_by_octaves() {
+        assert_eq!(
+            shifted_notes(ShiftOutOfRange::Fold, 100, 12),
+            [(0x90, 100), (0x80, 100)]
+        );
+        assert_eq!(
+            shifted_notes(ShiftOutOfRange::Fold, 60, -48),
+            [(0x90, 24), (0x80, 24)]
+        );
+    }
+
+    #[test]
+    fn out_of_range_shifts_clamp_to_the_edges() {
+        assert_eq!(
+            shifted_notes(ShiftOutOfRange::Clamp, 100, 12),
+            [(0x90, 108), (0x80, 108)]
+        );
+        assert_eq!(
+            shifted_notes(ShiftOutOfRange::Clamp, 60, -48),
+            [(0x90, 21), (0x80, 21)]
+        );
+    }
+
+    #[test]
+    fn out_of_range_shifts_drop_by_default() {
+        assert!(shifted_notes(ShiftOutOfRange::Drop, 100, 12).is_empty());
+        assert_eq!(
+            shifted_notes(ShiftOutOfRange::Drop, 96, 12),
+            [(0x90, 108), (0x80, 108)]
+        );
+    }
+
+    #[test]
+    fn clamped_chord_notes_play_once() {
+        let config = Config {
+            shift_out_of_range: ShiftOutOfRange::Clamp,
+            ..Config::default()
+        };
+        let key_config = KeyConfig {
+            extra_notes: vec![7, 12],
+            ..depth_key(100)
+        };
+        let messages = play_shifted(&mut KeyState::new(), &config, &key_config, 12, &[0.0, 1.0]);
+        assert_eq!(events(&messages), [(0x90, 108)]);
+    }
+
+    #[test]
+    fn sustained_restrikes_pass_note_offs_through() {
+        let mut config = Config {
+            sustain: Some(SustainConfig {
+                keys: vec![HIDCodes::Space],
+                channel: 0,
+                threshold: 0.5,
+                hysteresis: 0.1,
+            }),
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        let pedal = [(HIDCodes::Space, 1.0)];
+        let pedal_and_key = [(HIDCodes::Space, 1.0), (HIDCodes::A, 1.0)];
+        let mut messages = tick(&mut service, &pedal);
+        for _ in 0..2 {
+            messages.extend(tick(&mut service, &pedal_and_key));
+            messages.extend(tick(&mut service, &pedal));
+        }
+        // Within the hysteresis the pedal stays down
+        messages.extend(tick(&mut service, &[(HIDCodes::Space, 0.45)]));
+        messages.extend(tick(&mut service, &[]));
+        assert_eq!(
+            events(&messages),
+            [
+                (0xB0, 64),
+                (0x90, 60),
+                (0x80, 60),
+                (0x90, 60),
+                (0x80, 60),
+                (0xB0, 64)
+            ]
+        );
+        assert_eq!(messages[0][2], 127);
+        assert_eq!(messages[5][2], 0);
+    }
+
+    fn nudge_service(sticky: bool) -> MidiService {
+        let mut config = Config {
+            threshold_nudge_up_keys: vec![HIDCodes::F11, HIDCodes::ArrowUp],
+            threshold_nudge_down_keys: vec![HIDCodes::F11, HIDCodes::ArrowDown],
+            threshold_nudge_sticky: sticky,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        service
+    }
+
+    #[test]
+    fn nudge_chords_step_the_threshold_once_per_press() {
+        let mut service = nudge_service(false);
+        let up = [(HIDCodes::F11, 1.0), (HIDCodes::ArrowUp, 1.0)];
+        tick(&mut service, &up);
+        tick(&mut service, &up);
+        assert!((service.threshold_delta() - 0.02).abs() < 1e-6);
+
+        // Half a chord does nothing
+        tick(&mut service, &[(HIDCodes::ArrowUp, 1.0)]);
+        assert!((service.threshold_delta() - 0.02).abs() < 1e-6);
+
+        for _ in 0..20 {
+            tick(&mut service, &up);
+            tick(&mut service, &[]);
+        }
+        assert!((service.threshold_delta() - 0.3).abs() < 1e-6);
+
+        tick(
+            &mut service,
+            &[(HIDCodes::F11, 1.0), (HIDCodes::ArrowDown, 1.0)],
+        );
+        assert!((service.threshold_delta() - 0.28).abs() < 1e-6);
+    }
+
+    #[test]
+    fn nudged_threshold_applies_to_the_next_press() {
+        let mut service = nudge_service(false);
+        service.threshold_delta = 0.1;
+        assert!(tick(&mut service, &[(HIDCodes::A, 0.85)]).is_empty());
+        assert_eq!(
+            note_ons(&tick(&mut service, &[(HIDCodes::A, 1.0)])),
+            [(60, 127)]
+        );
+    }
+
+    #[test]
+    fn nudges_reset_on_config_changes_unless_sticky() {
+        for sticky in [false, true] {
+            let mut service = nudge_service(sticky);
+            service.threshold_delta = 0.1;
+            service.set_config(service.source_config().clone()).unwrap();
+            let expected = if sticky { 0.1 } else { 0.0 };
+            assert_eq!(service.threshold_delta(), expected);
+        }
+    }
+
+    #[test]
+    fn old_configs_are_migrated_when_set() {
+        let mut config = Config {
+            version: 1,
+            ..Config::default()
+        };
+        let key_config = KeyConfig {
+            velocity_scale: 10.0,
+            ..key(60)
+        };
+        config.key_configs.insert(HIDCodes::A, key_config);
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        assert_eq!(service.config().version, CONFIG_VERSION);
+        assert_eq!(
+            service.config().key_configs[&HIDCodes::A].velocity_scale,
+            10.0
+        );
+    }
+
+    #[test]
+    fn transpose_is_clamped() {
+        let mut service = MidiService::new();
+        service.set_transpose(60);
+        assert_eq!(service.transpose(), TRANSPOSE_MAX);
+        service.set_transpose(i8::MIN);
+        assert_eq!(service.transpose(), -TRANSPOSE_MAX);
+    }
+
+    #[test]
+    fn transpose_adds_to_the_config_and_the_shift() {
+        let mut config = Config {
+            transpose: -1,
+            modifier_keys: vec![HIDCodes::LeftShift],
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        service.set_transpose(12);
+
+        let messages = tick(&mut service, &[(HIDCodes::A, 1.0)]);
+        assert_eq!(events(&messages), [(0x90, 71)]);
+        // Held notes keep their pitch
+        service.set_transpose(0);
+        assert!(tick(&mut service, &[(HIDCodes::A, 1.0)]).is_empty());
+        let messages = tick(&mut service, &[]);
+        assert_eq!(events(&messages), [(0x80, 71)]);
+
+        let messages = tick(
+            &mut service,
+            &[(HIDCodes::A, 1.0), (HIDCodes::LeftShift, 1.0)],
+        );
+        assert_eq!(events(&messages), [(0x90, 71)]);
+    }
+
+    fn gated_key(retry_within_press: bool) -> KeyConfig {
+        KeyConfig {
+            min_trigger_velocity: Some(0.5),
+            retry_within_press,
+            ..depth_key(60)
+        }
+    }
+
+    #[test]
+    fn grazes_below_the_velocity_gate_stay_silent() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &gated_key(false),
+            &[0.0, 0.85, 1.0, 0.0, 1.0, 0.0],
+        );
+        // The graze stays silent for the whole press, the next press plays
+        assert_eq!(events(&messages), [(0x90, 60), (0x80, 60)]);
+        assert_eq!(messages[0][2], 127);
+    }
+
+    #[test]
+    fn velocity_gate_retries_within_the_press() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &gated_key(true),
+            &[0.0, 0.85, 1.0],
+        );
+        assert_eq!(note_ons(&messages), [(60, 127)]);
+    }
+
+    #[test]
+    fn out_of_range_channels_are_rejected() {
+        let mut config = Config::default();
+        let key_config = KeyConfig {
+            channel: 16,
+            ..key(60)
+        };
+        config.key_configs.insert(HIDCodes::Q, key_config);
+        let mut service = MidiService::new();
+        let error = service.set_config(config).unwrap_err().to_string();
+        assert!(error.contains("Q"), "{}", error);
+        assert!(error.contains("key_configs.channel"), "{}", error);
+        assert_eq!(service.config().key_configs.len(), 0);
+    }
+
+    /// Writer whose output stays readable after it was handed to the service
+    #[cfg(feature = "midi2")]
+    #[derive(Clone, Default)]
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "midi2")]
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Enabled service writing MIDI 1.0 UMP to the returned writer, for the paths that only
+    /// send through the connection
+    #[cfg(feature = "midi2")]
+    fn connected_service(config: Config) -> (MidiService, SharedWriter) {
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        let writer = SharedWriter::default();
+        service
+            .select_ump_output(Box::new(writer.clone()), ump::Protocol::Midi1)
+            .unwrap();
+        take_messages(&writer);
+        (service, writer)
+    }
+
+    /// Messages written since the last call, without their UMP group byte
+    #[cfg(feature = "midi2")]
+    fn take_messages(writer: &SharedWriter) -> Vec<Vec<u8>> {
+        let mut bytes = writer.0.lock().unwrap();
+        let messages = bytes.chunks(4).map(|packet| packet[1..].to_vec()).collect();
+        bytes.clear();
+        messages
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn pitch_bend_range_is_sent_on_connect() {
+        let mut config = Config {
+            pitch_bend_range_semitones: Some(12),
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, key(60));
+        config.key_configs.insert(
+            HIDCodes::S,
+            KeyConfig {
+                channel: 1,
+                ..key(62)
+            },
+        );
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        let writer = SharedWriter::default();
+        service
+            .select_ump_output(Box::new(writer.clone()), ump::Protocol::Midi1)
+            .unwrap();
+
+        let rpn = |channel: u8| {
+            [(101, 0), (100, 0), (6, 12), (38, 0), (101, 127), (100, 127)]
+                .map(|(controller, value)| [0x20, 0xB0 | channel, controller, value])
+                .concat()
+        };
+        assert_eq!(*writer.0.lock().unwrap(), [rpn(0), rpn(1)].concat());
+    }
+
+    #[test]
+    fn aftertouch_smoothing_follows_its_time_constant() {
+        let key_config = KeyConfig {
+            aftertouch_smoothing_ms: 100.0,
+            ..key(60)
+        };
+        let mut state = KeyState::new();
+        let start = Instant::now();
+        state.pressure_updated = Some(start);
+        let smoothed = state.smooth_pressure(&key_config, 1.0, start + Duration::from_millis(100));
+        assert!(
+            (smoothed - (1.0 - (-1.0f32).exp())).abs() < 1e-6,
+            "{}",
+            smoothed
+        );
+
+        // Without smoothing the value passes straight through
+        let smoothed = state.smooth_pressure(&key(60), 0.3, start + Duration::from_millis(105));
+        assert_eq!(smoothed, 0.3);
+    }
+
+    #[test]
+    fn aftertouch_smoothing_starts_fresh_on_each_press() {
+        let key_config = KeyConfig {
+            aftertouch_smoothing_ms: 1000.0,
+            ..depth_key(60)
+        };
+        let aftertouch = |messages: &[Vec<u8>]| -> Vec<u8> {
+            messages
+                .iter()
+                .filter(|message| message[0] == 0xA0)
+                .map(|message| message[2])
+                .collect()
+        };
+        let mut state = KeyState::new();
+        let config = Config::default();
+        // A quick drop barely moves the slow filter
+        let messages = play(&mut state, &config, &key_config, &[1.0, 0.85]);
+        assert_eq!(aftertouch(&messages), [127]);
+
+        // The next press starts from its own depth instead of the previous one
+        play(&mut state, &config, &key_config, &[0.0, 0.9]);
+        assert_eq!(state.smoothed_pressure, 0.9);
+    }
+
+    #[test]
+    fn aftertouch_follows_the_key_flag() {
+        let sent = |aftertouch| -> usize {
+            let key_config = KeyConfig {
+                aftertouch,
+                ..depth_key(60)
+            };
+            let messages = play(
+                &mut KeyState::new(),
+                &Config::default(),
+                &key_config,
+                &[0.0, 0.9, 0.95, 1.0],
+            );
+            messages.iter().filter(|message| message[0] == 0xA0).count()
+        };
+        assert_eq!(sent(true), 2);
+        assert_eq!(sent(false), 0);
+    }
+
+    #[test]
+    fn channel_pressure_skips_keys_without_aftertouch() {
+        let mut config = Config {
+            aftertouch_mode: AftertouchMode::Channel,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let key_config = KeyConfig {
+            aftertouch: false,
+            ..depth_key(62)
+        };
+        config.key_configs.insert(HIDCodes::S, key_config);
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        let messages = tick(&mut service, &[(HIDCodes::A, 0.9), (HIDCodes::S, 1.0)]);
+        let pressure: Vec<&Vec<u8>> = messages
+            .iter()
+            .filter(|message| message[0] == 0xD0)
+            .collect();
+        assert_eq!(pressure, [&vec![0xD0, 114]]);
+    }
+
+    #[test]
+    fn high_resolution_cc_skips_unchanged_msbs() {
+        let mut config = Config::default();
+        let cc_config = CcConfig {
+            controller: 1,
+            channel: 0,
+            high_resolution: true,
+        };
+        config.cc_mappings.insert(HIDCodes::A, cc_config);
+        config.key_configs.insert(HIDCodes::S, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::A, 0.5)]),
+            [vec![0xB0, 1, 64], vec![0xB0, 33, 0]]
+        );
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::A, 0.5001)]),
+            [vec![0xB0, 33, 1]]
+        );
+        assert!(tick(&mut service, &[(HIDCodes::A, 0.5001)]).is_empty());
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::A, 0.0)]),
+            [vec![0xB0, 1, 0], vec![0xB0, 33, 0]]
+        );
+    }
+
+    /// Measured velocity of a press, pausing the given milliseconds before each reading
+    fn measured_velocity(key_config: &KeyConfig, readings: &[(u64, f32)]) -> f32 {
+        let mut state = KeyState::new();
+        for &(pause_ms, value) in readings {
+            std::thread::sleep(Duration::from_millis(pause_ms));
+            play(&mut state, &Config::default(), key_config, &[value]);
+        }
+        state.raw_velocity
+    }
+
+    #[test]
+    fn peak_speed_ignores_a_slow_start() {
+        let readings = [(0, 0.0), (0, 0.05), (40, 0.1), (5, 0.9)];
+        let estimated = |velocity_estimation| {
+            let key_config = KeyConfig {
+                velocity_estimation,
+                velocity_scale: 1.0,
+                ..key(60)
+            };
+            measured_velocity(&key_config, &readings)
+        };
+        let peak = estimated(VelocityEstimation::PeakSpeed);
+        let two_point = estimated(VelocityEstimation::TwoPoint);
+        assert!(peak > 3.0 * two_point, "{} vs {}", peak, two_point);
+    }
+
+    /// (status, note) of every note message
+    fn note_events(messages: &[Vec<u8>]) -> Vec<(u8, NoteID)> {
+        messages
+            .iter()
+            .filter(|message| matches!(message[0] & 0xF0, 0x80 | 0x90))
+            .map(|message| (message[0], message[1]))
+            .collect()
+    }
+
+    #[test]
+    fn noise_around_the_threshold_keeps_the_note() {
+        let noisy = [0.0, 0.85, 0.79, 0.81, 0.78, 0.82, 0.79];
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &depth_key(60),
+            &noisy,
+        );
+        assert_eq!(note_events(&messages), [(0x90, 60)]);
+    }
+
+    #[test]
+    fn release_threshold_sets_the_release_depth() {
+        let key_config = KeyConfig {
+            release_threshold: Some(0.5),
+            ..depth_key(60)
+        };
+        let mut state = KeyState::new();
+        let messages = play(
+            &mut state,
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.9, 0.6],
+        );
+        assert_eq!(note_events(&messages), [(0x90, 60)]);
+        let messages = play(&mut state, &Config::default(), &key_config, &[0.5]);
+        assert_eq!(note_events(&messages), [(0x80, 60)]);
+    }
+
+    #[test]
+    fn nudges_move_the_release_threshold_along() {
+        let key_config = KeyConfig {
+            release_threshold: Some(0.5),
+            ..key(60)
+        };
+        assert!((key_config.nudged_release_threshold(0.2) - 0.7).abs() < 1e-6);
+        assert!((key_config.nudged_release_threshold(-0.8) - 0.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn calibrated_keys_reach_full_velocity_early() {
+        let mut config = Config::default();
+        let key_config = KeyConfig {
+            calibration_max: 0.9,
+            ..depth_key(60)
+        };
+        config.key_configs.insert(HIDCodes::A, key_config);
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        tick(&mut service, &[(HIDCodes::A, 0.0)]);
+        let messages = tick(&mut service, &[(HIDCodes::A, 0.9)]);
+        assert_eq!(note_ons(&messages), [(60, 127)]);
+    }
+
+    #[test]
+    fn contact_time_spans_full_to_softest_velocity() {
+        let velocity = |ms| contact_time_velocity(Duration::from_millis(ms), 5.0, 45.0);
+        assert_eq!(velocity(0), 1.0);
+        assert_eq!(velocity(5), 1.0);
+        assert!((velocity(25) - (1.0 + 1.0 / 127.0) / 2.0).abs() < 1e-6);
+        assert!((velocity(45) - 1.0 / 127.0).abs() < 1e-6);
+        assert!((velocity(500) - 1.0 / 127.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn contact_time_runs_from_the_actuation_point() {
+        let key_config = KeyConfig {
+            actuation_point: 0.1,
+            velocity_estimation: VelocityEstimation::ContactTime {
+                fastest_ms: 5.0,
+                slowest_ms: 1000.0,
+            },
+            ..key(60)
+        };
+        // Resting on the actuation point does not start the clock
+        let slow = measured_velocity(&key_config, &[(0, 0.0), (0, 0.1), (30, 0.2), (200, 0.9)]);
+        let fast = measured_velocity(&key_config, &[(0, 0.0), (30, 0.1), (0, 0.2), (30, 0.9)]);
+        assert!(slow < 0.85, "{}", slow);
+        assert!(fast > slow + 0.1, "{} vs {}", fast, slow);
+        // Rest to past the threshold within one poll is as fast as it gets
+        assert_eq!(measured_velocity(&key_config, &[(0, 0.0), (0, 0.9)]), 1.0);
+    }
+
+    #[test]
+    fn held_notes_follow_the_shift_when_retriggered() {
+        let retriggered = |retrigger_on_shift| {
+            let key_config = KeyConfig {
+                retrigger_on_shift,
+                ..depth_key(60)
+            };
+            let mut state = KeyState::new();
+            let config = Config::default();
+            let mut messages = play(&mut state, &config, &key_config, &[0.0, 1.0]);
+            messages.extend(play_shifted(&mut state, &config, &key_config, 12, &[1.0]));
+            messages.extend(play_shifted(&mut state, &config, &key_config, 12, &[0.0]));
+            note_events(&messages)
+        };
+        assert_eq!(
+            retriggered(true),
+            [(0x90, 60), (0x80, 60), (0x90, 72), (0x80, 72)]
+        );
+        assert_eq!(retriggered(false), [(0x90, 60), (0x80, 60)]);
+    }
+
+    #[test]
+    fn pool_keys_keep_their_note_on_shifts() {
+        let key_config = KeyConfig {
+            retrigger_on_shift: true,
+            ..pool_key(1, 1, 0.0)
+        };
+        let mut state = KeyState::new();
+        let config = Config::default();
+        let mut messages = play(&mut state, &config, &key_config, &[0.0, 1.0]);
+        messages.extend(play_shifted(&mut state, &config, &key_config, 12, &[1.0]));
+        assert_eq!(note_events(&messages).len(), 1);
+    }
+
+    /// Ticks of a smoothed key until it plays a note on, `None` if it never does
+    fn smoothed_trigger_tick(smoothing: InputSmoothing, values: &[f32]) -> Option<usize> {
+        let mut config = Config {
+            input_smoothing: Some(smoothing),
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        values
+            .iter()
+            .position(|&value| !note_ons(&tick(&mut service, &[(HIDCodes::A, value)])).is_empty())
+    }
+
+    #[test]
+    fn median_smoothing_drops_single_spikes() {
+        let smoothing = InputSmoothing::MedianOf3;
+        assert_eq!(
+            smoothed_trigger_tick(smoothing, &[0.0, 0.0, 1.0, 0.0, 0.0]),
+            None
+        );
+        assert_eq!(
+            smoothed_trigger_tick(smoothing, &[0.0, 0.0, 1.0, 1.0]),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn exponential_smoothing_settles_over_polls() {
+        // 0.5, 0.75 and then 0.875 past the threshold
+        let smoothing = InputSmoothing::Exponential(0.5);
+        assert_eq!(
+            smoothed_trigger_tick(smoothing, &[0.0, 1.0, 1.0, 1.0]),
+            Some(3)
+        );
+        let unsmoothed = InputSmoothing::Exponential(1.0);
+        assert_eq!(smoothed_trigger_tick(unsmoothed, &[0.0, 1.0]), Some(1));
+    }
+
+    #[test]
+    fn disabling_silences_every_channel() {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let key_config = KeyConfig {
+            channel: 2,
+            ..depth_key(62)
+        };
+        config.key_configs.insert(HIDCodes::S, key_config);
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        assert_eq!(
+            note_ons(&tick(&mut service, &[(HIDCodes::A, 1.0)])),
+            [(60, 127)]
+        );
+        let held = [(HIDCodes::A, 1.0), (HIDCodes::F12, 1.0)];
+        let messages = tick(&mut service, &held);
+        assert_eq!(service.enable_state(), EnableState::Off);
+        assert_eq!(
+            messages,
+            [vec![0x80, 60, 127], vec![0xB0, 123, 0], vec![0xB2, 123, 0]]
+        );
+        // Keys stay silent while disabled
+        assert!(tick(&mut service, &[(HIDCodes::A, 1.0), (HIDCodes::S, 1.0)]).is_empty());
+    }
+
+    /// Pressure byte of every polyphonic aftertouch message
+    fn pressures(messages: &[Vec<u8>]) -> Vec<u8> {
+        messages
+            .iter()
+            .filter(|message| message[0] & 0xF0 == 0xA0)
+            .map(|message| message[2])
+            .collect()
+    }
+
+    #[test]
+    fn peak_hold_keeps_the_deepest_pressure() {
+        let sent = |aftertouch_response| {
+            let key_config = KeyConfig {
+                aftertouch_response,
+                ..depth_key(60)
+            };
+            let values = [0.0, 0.9, 1.0, 0.9, 0.85];
+            pressures(&play(
+                &mut KeyState::new(),
+                &Config::default(),
+                &key_config,
+                &values,
+            ))
+        };
+        assert_eq!(sent(AftertouchResponse::PeakHold), [127]);
+        assert_eq!(sent(AftertouchResponse::Continuous), [127, 114, 108]);
+    }
+
+    #[test]
+    fn peak_hold_starts_over_on_each_press() {
+        let key_config = KeyConfig {
+            aftertouch_response: AftertouchResponse::PeakHold,
+            ..depth_key(60)
+        };
+        // Still holding the first peak, the rise to 0.95 would stay unsent
+        let values = [0.0, 1.0, 0.0, 0.9, 0.95];
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &values,
+        );
+        assert_eq!(pressures(&messages), [121]);
+    }
+
+    fn windowed_key() -> KeyConfig {
+        KeyConfig {
+            velocity_estimation: VelocityEstimation::Depth { window_ms: 20 },
+            ..key(60)
+        }
+    }
+
+    #[test]
+    fn depth_window_plays_the_deepest_point() {
+        let key_config = windowed_key();
+        let mut state = KeyState::new();
+        let messages = play(
+            &mut state,
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.85, 1.0],
+        );
+        assert!(messages.is_empty());
+        std::thread::sleep(Duration::from_millis(25));
+        let messages = play(&mut state, &Config::default(), &key_config, &[0.9]);
+        assert_eq!(note_ons(&messages), [(60, 127)]);
+    }
+
+    #[test]
+    fn taps_within_the_depth_window_still_play() {
+        let key_config = windowed_key();
+        let mut state = KeyState::new();
+        let messages = play(
+            &mut state,
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.9, 0.0],
+        );
+        assert!(messages.is_empty());
+        std::thread::sleep(Duration::from_millis(25));
+        let messages = play(&mut state, &Config::default(), &key_config, &[0.0, 0.0]);
+        assert_eq!(note_events(&messages), [(0x90, 60), (0x80, 60)]);
+        assert_eq!(note_ons(&messages), [(60, 63)]);
+    }
+
+    #[test]
+    fn crossings_are_interpolated_between_polls() {
+        let key_config = KeyConfig {
+            actuation_point: 0.1,
+            ..key(60)
+        };
+        let config = Config::default();
+        let mut state = KeyState::new();
+        let fraction = |state: &KeyState, before: Instant, time: Instant| {
+            let tick = state.updated_at.unwrap() - before;
+            (time - before).as_secs_f32() / tick.as_secs_f32()
+        };
+
+        play(&mut state, &config, &key_config, &[0.0]);
+        let before = Instant::now() - Duration::from_millis(10);
+        state.updated_at = Some(before);
+        play(&mut state, &config, &key_config, &[0.5]);
+        let actuated_at = state.actuated_at.unwrap();
+        assert!((fraction(&state, before, actuated_at) - 0.2).abs() < 1e-3);
+
+        let before = Instant::now() - Duration::from_millis(10);
+        state.updated_at = Some(before);
+        play(&mut state, &config, &key_config, &[1.0]);
+        let &(crossed_at, depth) = state.press_samples.back().unwrap();
+        assert_eq!(depth, key_config.threshold);
+        assert!((fraction(&state, before, crossed_at) - 0.6).abs() < 1e-3);
+    }
+
+    #[test]
+    fn keys_sharing_a_note_hold_it_together() {
+        let mut config = Config::default();
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        config.key_configs.insert(HIDCodes::S, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        let messages = tick(&mut service, &[(HIDCodes::A, 1.0), (HIDCodes::S, 1.0)]);
+        assert_eq!(note_events(&messages), [(0x90, 60)]);
+        let messages = tick(&mut service, &[(HIDCodes::S, 1.0)]);
+        assert!(note_events(&messages).is_empty());
+        let messages = tick(&mut service, &[]);
+        assert_eq!(note_events(&messages), [(0x80, 60)]);
+    }
+
+    /// Velocity byte of every note off
+    fn note_offs(messages: &[Vec<u8>]) -> Vec<u8> {
+        messages
+            .iter()
+            .filter(|message| message[0] & 0xF0 == 0x80)
+            .map(|message| message[2])
+            .collect()
+    }
+
+    #[test]
+    fn release_speed_sets_the_note_off_velocity() {
+        let key_config = KeyConfig {
+            release_velocity_scale: Some(100.0),
+            ..depth_key(60)
+        };
+        let config = Config::default();
+        let fast = play(&mut KeyState::new(), &config, &key_config, &[0.0, 1.0, 0.0]);
+        assert_eq!(note_offs(&fast), [127]);
+
+        let mut state = KeyState::new();
+        play(&mut state, &config, &key_config, &[0.0, 1.0, 0.85]);
+        std::thread::sleep(Duration::from_millis(50));
+        let slow = play(&mut state, &config, &key_config, &[0.0]);
+        let velocity = note_offs(&slow)[0];
+        assert!((1..64).contains(&velocity), "{}", velocity);
+    }
+
+    #[test]
+    fn fixed_release_velocity_wins() {
+        let key_config = KeyConfig {
+            release_velocity_scale: Some(100.0),
+            fixed_release_velocity: Some(40),
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 1.0, 0.0, 0.9, 0.5],
+        );
+        assert_eq!(note_offs(&messages), [40, 40]);
+    }
+
+    #[test]
+    fn velocity_does_not_depend_on_the_polling_rate() {
+        let key_config = KeyConfig {
+            velocity_scale: 100.0,
+            ..key(60)
+        };
+        // Full travel in 25ms is 40 travels per second
+        let press = Duration::from_millis(25);
+        let bytes: Vec<Vec<(NoteID, u8)>> = [100u32, 200, 500]
+            .iter()
+            .map(|&rate| {
+                let interval = Duration::from_secs(1) / rate;
+                let readings: Vec<(Duration, f32)> = (0..=rate / 20)
+                    .map(|poll| {
+                        let offset = interval * poll;
+                        (
+                            offset,
+                            (offset.as_secs_f32() / press.as_secs_f32()).min(1.0),
+                        )
+                    })
+                    .collect();
+                let messages = play_at(
+                    &mut KeyState::new(),
+                    &Config::default(),
+                    &key_config,
+                    Instant::now(),
+                    &readings,
+                );
+                note_ons(&messages)
+            })
+            .collect();
+        assert_eq!(bytes, [[(60, 51)], [(60, 51)], [(60, 51)]]);
+    }
+
+    fn samples(readings: &[(u64, f32)]) -> VecDeque<(Instant, f32)> {
+        let start = Instant::now();
+        readings
+            .iter()
+            .map(|&(ms, depth)| (start + Duration::from_millis(ms), depth))
+            .collect()
+    }
+
+    #[test]
+    fn least_squares_needs_samples_spread_over_time() {
+        assert_eq!(least_squares_slope(&samples(&[])), None);
+        assert_eq!(least_squares_slope(&samples(&[(0, 0.5)])), None);
+        assert_eq!(
+            least_squares_slope(&samples(&[(3, 0.1), (3, 0.4), (3, 0.9)])),
+            None
+        );
+    }
+
+    #[test]
+    fn least_squares_follows_a_ramp() {
+        // A tenth of the travel every 2ms is 50 travels per second
+        let ramp: Vec<(u64, f32)> = (0..8).map(|i| (2 * i, 0.1 * i as f32)).collect();
+        let slope = least_squares_slope(&samples(&ramp)).unwrap();
+        assert!((slope - 50.0).abs() < 1e-3, "{}", slope);
+    }
+
+    /// A press recorded at 1kHz, with the sensor noise of a real switch
+    const RECORDED_PRESS: [f32; 32] = [
+        0.0, 0.004, 0.0, 0.012, 0.031, 0.052, 0.089, 0.118, 0.161, 0.197, 0.246, 0.281, 0.334,
+        0.372, 0.418, 0.469, 0.503, 0.556, 0.589, 0.641, 0.679, 0.726, 0.768, 0.805, 0.851, 0.883,
+        0.921, 0.947, 0.972, 0.988, 0.997, 1.0,
+    ];
+
+    #[test]
+    fn recorded_press_velocity_stays_in_a_tight_band() {
+        let key_config = KeyConfig {
+            velocity_estimation: VelocityEstimation::LeastSquares,
+            velocity_scale: 100.0,
+            ..key(60)
+        };
+        // Poll the trace every 4ms, starting at each possible phase
+        let bytes = |key_config: &KeyConfig| -> Vec<u8> {
+            (0..4)
+                .flat_map(|phase| {
+                    let readings: Vec<(Duration, f32)> = (phase..RECORDED_PRESS.len())
+                        .step_by(4)
+                        .map(|ms| (Duration::from_millis(ms as u64), RECORDED_PRESS[ms]))
+                        .collect();
+                    let messages = play_at(
+                        &mut KeyState::new(),
+                        &Config::default(),
+                        key_config,
+                        Instant::now(),
+                        &readings,
+                    );
+                    note_ons(&messages)
+                })
+                .map(|(_, velocity)| velocity)
+                .collect()
+        };
+        let bytes = bytes(&key_config);
+        assert_eq!(bytes.len(), 4);
+        assert!(
+            bytes.iter().all(|velocity| (49..=54).contains(velocity)),
+            "{:?}",
+            bytes
+        );
+    }
+
+    fn arp_config() -> Config {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            arpeggiator: Some(crate::config::ArpeggiatorConfig {
+                bpm: Some(60.0),
+                steps_per_beat: 1,
+                ..Default::default()
+            }),
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        config
+    }
+
+    #[test]
+    fn disabling_flushes_the_sounding_arp_note() {
+        let mut service = MidiService::new();
+        service.set_config(arp_config()).unwrap();
+        service.enable_state = EnableState::Full;
+
+        // The press is collected, the next tick plays it as the first step
+        assert!(tick(&mut service, &[(HIDCodes::A, 1.0)]).is_empty());
+        assert_eq!(tick(&mut service, &[(HIDCodes::A, 1.0)]), [[0x90, 60, 127]]);
+        let messages = tick(&mut service, &[(HIDCodes::A, 1.0), (HIDCodes::F12, 1.0)]);
+        assert_eq!(service.enable_state(), EnableState::Off);
+        assert_eq!(messages, [vec![0x80, 60, 127], vec![0xB0, 123, 0]]);
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn config_changes_flush_the_sounding_arp_note() {
+        let (mut service, writer) = connected_service(arp_config());
+        tick(&mut service, &[(HIDCodes::A, 1.0)]);
+        assert_eq!(tick(&mut service, &[(HIDCodes::A, 1.0)]), [[0x90, 60, 127]]);
+
+        let config = Config {
+            arpeggiator: None,
+            ..service.config().clone()
+        };
+        service.set_config(config).unwrap();
+        assert_eq!(take_messages(&writer), [[0x80, 60, 127]]);
+        // The held key triggers again, now without the arpeggiator
+        assert_eq!(tick(&mut service, &[(HIDCodes::A, 1.0)]), [[0x90, 60, 127]]);
+    }
+
+    fn shared_note_config() -> Config {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        config.key_configs.insert(HIDCodes::S, depth_key(60));
+        config
+    }
+
+    const BOTH_HELD: [(HIDCodes, f32); 2] = [(HIDCodes::A, 1.0), (HIDCodes::S, 1.0)];
+
+    #[test]
+    fn disabling_ends_a_shared_note_once() {
+        let mut service = MidiService::new();
+        service.set_config(shared_note_config()).unwrap();
+        service.enable_state = EnableState::Full;
+        assert_eq!(tick(&mut service, &BOTH_HELD), [[0x90, 60, 127]]);
+
+        let toggled = [(HIDCodes::A, 1.0), (HIDCodes::S, 1.0), (HIDCodes::F12, 1.0)];
+        assert_eq!(
+            tick(&mut service, &toggled),
+            [vec![0x80, 60, 127], vec![0xB0, 123, 0]]
+        );
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn all_notes_off_ends_a_shared_note_once() {
+        let (mut service, writer) = connected_service(shared_note_config());
+        assert_eq!(tick(&mut service, &BOTH_HELD), [[0x90, 60, 127]]);
+
+        service.all_notes_off().unwrap();
+        assert_eq!(
+            take_messages(&writer),
+            [[0x80, 60, 127], [0xB0, 123, 0], [0xB0, 120, 0]]
+        );
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn config_changes_end_a_shared_note_once() {
+        let (mut service, writer) = connected_service(shared_note_config());
+        assert_eq!(tick(&mut service, &BOTH_HELD), [[0x90, 60, 127]]);
+
+        service.set_config(shared_note_config()).unwrap();
+        assert_eq!(take_messages(&writer), [[0x80, 60, 127]]);
+        // Both keys start over and share the note again
+        assert_eq!(tick(&mut service, &BOTH_HELD), [[0x90, 60, 127]]);
+    }
+
+    #[test]
+    fn bend_paddles_sum_clamp_and_return_to_center() {
+        let mut config = Config::default();
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        for (hid_code, direction) in [
+            (HIDCodes::Z, BendDirection::Up),
+            (HIDCodes::C, BendDirection::Up),
+            (HIDCodes::X, BendDirection::Down),
+        ] {
+            let bend = PitchBendConfig {
+                channel: 0,
+                direction,
+            };
+            config.pitch_bend_keys.insert(hid_code, bend);
+        }
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::Z, 0.5)]),
+            [[0xE0, 0x00, 0x60]]
+        );
+        // Opposite paddles cancel out
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::Z, 0.5), (HIDCodes::X, 0.25)]),
+            [[0xE0, 0x00, 0x50]]
+        );
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::Z, 1.0), (HIDCodes::C, 0.5)]),
+            [[0xE0, 0x7F, 0x7F]]
+        );
+        // An unchanged bend is not sent again
+        assert!(tick(&mut service, &[(HIDCodes::Z, 1.0), (HIDCodes::C, 0.8)]).is_empty());
+        assert_eq!(tick(&mut service, &[]), [[0xE0, 0x00, 0x40]]);
+        assert!(tick(&mut service, &[]).is_empty());
+    }
+
+    #[test]
+    fn sustain_uses_hysteresis_and_lifts_when_disabled() {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            sustain: Some(SustainConfig {
+                keys: vec![HIDCodes::Space],
+                channel: 1,
+                threshold: 0.5,
+                hysteresis: 0.1,
+            }),
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        let mut pedal = |depth: f32| tick(&mut service, &[(HIDCodes::Space, depth)]);
+
+        assert!(pedal(0.45).is_empty());
+        assert_eq!(pedal(0.55), [[0xB1, 64, 127]]);
+        // Wobbling within the hysteresis band keeps the pedal down
+        assert!(pedal(0.45).is_empty());
+        assert!(pedal(0.41).is_empty());
+        assert_eq!(pedal(0.35), [[0xB1, 64, 0]]);
+        assert!(pedal(0.45).is_empty());
+        assert_eq!(pedal(0.6), [[0xB1, 64, 127]]);
+
+        let messages = tick(
+            &mut service,
+            &[(HIDCodes::Space, 0.6), (HIDCodes::F12, 1.0)],
+        );
+        assert_eq!(service.enable_state(), EnableState::Off);
+        assert_eq!(messages.last().unwrap(), &[0xB1, 64, 0]);
+        assert!(tick(&mut service, &[(HIDCodes::Space, 0.6)]).is_empty());
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn all_notes_off_releases_held_keys_and_silences_every_channel() {
+        let mut config = Config {
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let key_config = KeyConfig {
+            channel: 2,
+            ..depth_key(62)
+        };
+        config.key_configs.insert(HIDCodes::S, key_config);
+        let (mut service, writer) = connected_service(config);
+        let held = [(HIDCodes::A, 1.0), (HIDCodes::S, 1.0)];
+        assert_eq!(tick(&mut service, &held).len(), 2);
+
+        service.all_notes_off().unwrap();
+        let mut messages = take_messages(&writer);
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                [0x80, 60, 127],
+                [0x82, 62, 127],
+                [0xB0, 120, 0],
+                [0xB0, 123, 0],
+                [0xB2, 120, 0],
+                [0xB2, 123, 0],
+            ]
+        );
+        // The states start over, so keys still held trigger again
+        assert_eq!(note_ons(&tick(&mut service, &held)).len(), 2);
+    }
+
+    #[test]
+    fn all_notes_off_without_a_connection_does_nothing() {
+        let mut config = Config::default();
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        tick(&mut service, &[(HIDCodes::A, 1.0)]);
+
+        service.all_notes_off().unwrap();
+        assert!(service.key_states[&HIDCodes::A].pressed);
+    }
+
+    /// Service with the F12 toggle that starts disabled
+    fn toggled_service(config: Config) -> MidiService {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            ..config
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service
+    }
+
+    /// Presses and lets go of the toggle key, returning the messages of the press
+    fn toggle(service: &mut MidiService) -> Vec<Vec<u8>> {
+        let messages = tick(service, &[(HIDCodes::F12, 1.0)]);
+        tick(service, &[]);
+        messages
+    }
+
+    #[test]
+    fn toggling_sends_the_configured_sysex() {
+        let enable = vec![0xF0, 0x7D, 0x01, 0xF7];
+        let disable = vec![0xF0, 0x7D, 0x02, 0xF7];
+        let mut service = toggled_service(Config {
+            on_enable_sysex: Some(enable.clone()),
+            on_disable_sysex: Some(disable.clone()),
+            ..Config::default()
+        });
+        assert_eq!(toggle(&mut service), [enable]);
+        assert_eq!(toggle(&mut service)[0], disable);
+    }
+
+    #[test]
+    fn malformed_sysex_is_rejected_with_its_field() {
+        let mut config = Config {
+            on_enable_sysex: Some(vec![0xF0, 0x7D, 0x81, 0xF7]),
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let error = MidiService::new().set_config(config).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("on_enable_sysex"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn toggling_starts_and_stops_the_transport() {
+        let mut service = toggled_service(Config {
+            transport_on_toggle: Some(TransportStart::Start),
+            ..Config::default()
+        });
+        // Without a clock running, only the transport messages are sent
+        assert_eq!(toggle(&mut service), [[0xFA]]);
+        assert_eq!(toggle(&mut service)[0], [0xFC]);
+
+        let mut service = toggled_service(Config {
+            transport_on_toggle: Some(TransportStart::Continue),
+            ..Config::default()
+        });
+        assert_eq!(toggle(&mut service), [[0xFB]]);
+        assert_eq!(toggle(&mut service)[0], [0xFC]);
+
+        let mut service = toggled_service(Config::default());
+        assert!(toggle(&mut service).is_empty());
+    }
+
+    #[test]
+    fn aftertouch_rate_limit_sends_the_latest_pressure() {
+        let config = Config {
+            aftertouch_max_rate: Some(20.0),
+            ..Config::default()
+        };
+        let ms = Duration::from_millis;
+        let readings = [
+            (ms(0), 1.0),
+            (ms(10), 0.95),
+            (ms(20), 0.9),
+            (ms(40), 0.92),
+            (ms(61), 0.93),
+            (ms(65), 0.0),
+            (ms(70), 1.0),
+        ];
+        let messages = play_at(
+            &mut KeyState::new(),
+            &config,
+            &depth_key(60),
+            Instant::now(),
+            &readings,
+        );
+        // The next aftertouch waits 50ms and carries the latest depth, the skipped updates are
+        // never sent late. Note ons and offs are not held back.
+        assert_eq!(
+            events(&messages),
+            [(0x90, 60), (0xA0, 60), (0xA0, 60), (0x80, 60), (0x90, 60)]
+        );
+        assert_eq!(pressures(&messages), [121, 118]);
+    }
+
+    #[test]
+    fn pre_touch_reports_depth_from_the_actuation_point() {
+        let key_config = KeyConfig {
+            actuation_point: 0.2,
+            pre_touch: Some(PreTouch::ControlChange(2)),
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 0.0, 0.6, 0.6, 1.0, 0.0, 0.0],
+        );
+        // The note still obeys the threshold, a key at rest stops after a single 0
+        assert_eq!(
+            messages,
+            [
+                vec![0xB0, 2, 64],
+                vec![0x90, 60, 127],
+                vec![0xB0, 2, 127],
+                vec![0x80, 60, 0],
+                vec![0xB0, 2, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn pre_touch_aftertouch_starts_before_the_note() {
+        let key_config = KeyConfig {
+            actuation_point: 0.2,
+            pre_touch: Some(PreTouch::PolyAftertouch),
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.6, 0.7, 1.0, 1.0],
+        );
+        // Regular aftertouch stays out of the way of the pre-touch values
+        assert_eq!(
+            events(&messages),
+            [(0xA0, 60), (0xA0, 60), (0x90, 60), (0xA0, 60)]
+        );
+        assert_eq!(pressures(&messages), [64, 79, 127]);
+    }
+
+    #[test]
+    fn deep_presses_add_the_second_note() {
+        let key_config = KeyConfig {
+            second_note: Some((72, 0.95)),
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.9, 0.97, 0.9, 0.98, 0.0],
+        );
+        // The second note takes the press velocity and ends first on a quick release
+        assert_eq!(
+            note_events(&messages),
+            [
+                (0x90, 60),
+                (0x90, 72),
+                (0x80, 72),
+                (0x90, 72),
+                (0x80, 72),
+                (0x80, 60)
+            ]
+        );
+        assert_eq!(note_ons(&messages), [(60, 63), (72, 63), (72, 63)]);
+    }
+
+    #[test]
+    fn a_press_straight_through_both_zones_plays_both_notes() {
+        let key_config = KeyConfig {
+            second_note: Some((72, 0.95)),
+            ..depth_key(60)
+        };
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            &[0.0, 1.0, 0.0],
+        );
+        assert_eq!(
+            note_events(&messages),
+            [(0x90, 60), (0x90, 72), (0x80, 72), (0x80, 60)]
+        );
+    }
+
+    fn latch_key(note_id: NoteID) -> KeyConfig {
+        KeyConfig {
+            latch: true,
+            ..depth_key(note_id)
+        }
+    }
+
+    #[test]
+    fn latched_notes_end_with_the_next_press() {
+        let messages = play(
+            &mut KeyState::new(),
+            &Config::default(),
+            &latch_key(60),
+            &[1.0, 0.0, 0.0, 0.9, 0.0, 1.0],
+        );
+        // The releasing press sets the note off velocity, the third press starts over
+        assert_eq!(
+            messages,
+            [vec![0x90, 60, 127], vec![0x80, 60, 63], vec![0x90, 60, 127]]
+        );
+    }
+
+    #[test]
+    fn disabling_ends_latched_notes() {
+        let mut config = Config {
+            toggle_keys: vec![HIDCodes::F12],
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, latch_key(60));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        tick(&mut service, &[(HIDCodes::A, 1.0)]);
+        assert!(tick(&mut service, &[]).is_empty());
+
+        assert_eq!(
+            tick(&mut service, &[(HIDCodes::F12, 1.0)]),
+            [vec![0x80, 60, 127], vec![0xB0, 123, 0]]
+        );
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn config_changes_end_latched_notes() {
+        let mut config = Config {
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, latch_key(60));
+        let (mut service, writer) = connected_service(config.clone());
+        tick(&mut service, &[(HIDCodes::A, 1.0)]);
+        tick(&mut service, &[]);
+
+        service.set_config(config).unwrap();
+        assert_eq!(take_messages(&writer), [[0x80, 60, 127]]);
+    }
+
+    #[test]
+    fn note_repeat_rolls_at_the_rate_and_follows_the_depth() {
+        // Sixteenths at 150 BPM are 100ms apart
+        let key_config = KeyConfig {
+            note_repeat: Some(NoteRepeatConfig {
+                rate: RepeatRate::Sixteenth,
+                bpm: Some(150.0),
+            }),
+            ..depth_key(60)
+        };
+        let ms = Duration::from_millis;
+        let readings = [
+            (ms(0), 1.0),
+            (ms(50), 1.0),
+            (ms(101), 0.9),
+            (ms(150), 0.9),
+            (ms(202), 1.0),
+            (ms(250), 0.0),
+            (ms(400), 0.0),
+        ];
+        let messages = play_at(
+            &mut KeyState::new(),
+            &Config::default(),
+            &key_config,
+            Instant::now(),
+            &readings,
+        );
+        // Every repeat ends the previous hit, releasing between repeats ends the last one
+        assert_eq!(
+            note_events(&messages),
+            [
+                (0x90, 60),
+                (0x80, 60),
+                (0x90, 60),
+                (0x80, 60),
+                (0x90, 60),
+                (0x80, 60)
+            ]
+        );
+        assert_eq!(note_ons(&messages), [(60, 127), (60, 63), (60, 127)]);
+    }
+
+    fn strum_key(reverse_above_velocity: Option<f32>) -> KeyConfig {
+        KeyConfig {
+            extra_notes: vec![4, 7],
+            strum: Some(StrumConfig {
+                step_ms: 10,
+                reverse_above_velocity,
+            }),
+            ..depth_key(60)
+        }
+    }
+
+    fn strummed(key_config: &KeyConfig, readings: &[(u64, f32)]) -> Vec<(u8, NoteID)> {
+        let readings: Vec<(Duration, f32)> = readings
+            .iter()
+            .map(|&(ms, value)| (Duration::from_millis(ms), value))
+            .collect();
+        let messages = play_at(
+            &mut KeyState::new(),
+            &Config::default(),
+            key_config,
+            Instant::now(),
+            &readings,
+        );
+        note_events(&messages)
+    }
+
+    #[test]
+    fn strummed_chords_spread_out_over_the_polls() {
+        let readings = [(0, 0.9), (5, 0.9), (10, 0.9), (25, 0.9)];
+        assert_eq!(
+            strummed(&strum_key(None), &readings),
+            [(0x90, 60), (0x90, 64), (0x90, 67)]
+        );
+        // Fast presses strum from the top note down
+        let readings = [(0, 1.0), (10, 1.0), (20, 1.0)];
+        assert_eq!(
+            strummed(&strum_key(Some(0.9)), &readings),
+            [(0x90, 67), (0x90, 64), (0x90, 60)]
+        );
+    }
+
+    #[test]
+    fn releasing_drops_the_notes_a_strum_has_not_reached() {
+        let readings = [(0, 0.9), (12, 0.9), (15, 0.0), (40, 0.0)];
+        assert_eq!(
+            strummed(&strum_key(None), &readings),
+            [(0x90, 60), (0x90, 64), (0x80, 60), (0x80, 64)]
+        );
+    }
+
+    #[test]
+    fn fixed_velocity_ignores_the_press_speed() {
+        let key_config = KeyConfig {
+            fixed_velocity: Some(100),
+            ..key(60)
+        };
+        let ms = Duration::from_millis;
+        // A slow press, a press within one poll and a tap that barely passes the threshold
+        let presses = [
+            vec![(ms(0), 0.0), (ms(100), 0.5), (ms(200), 1.0), (ms(300), 0.0)],
+            vec![(ms(0), 0.0), (ms(1), 1.0), (ms(2), 0.0)],
+            vec![(ms(0), 0.0), (ms(5), 0.81), (ms(6), 0.0)],
+        ];
+        for readings in presses {
+            let messages = play_at(
+                &mut KeyState::new(),
+                &Config::default(),
+                &key_config,
+                Instant::now(),
+                &readings,
+            );
+            assert_eq!(messages, [[0x90, 60, 100], [0x80, 60, 100]]);
+        }
+    }
+
+    fn d_minor(out_of_scale: OutOfScale) -> Config {
+        Config {
+            scale: Some(ScaleConfig {
+                root: 2,
+                kind: ScaleKind::NaturalMinor,
+                out_of_scale,
+            }),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn scale_lock_snaps_or_skips_after_the_shift() {
+        let snap = d_minor(OutOfScale::Snap);
+        // C# lies between C and D, ties go to the lower tone
+        let messages = play(&mut KeyState::new(), &snap, &depth_key(61), &[1.0, 0.0]);
+        assert_eq!(note_events(&messages), [(0x90, 60), (0x80, 60)]);
+        // Shifted up a fifth, C# becomes G# and snaps to G
+        let messages = play_shifted(&mut KeyState::new(), &snap, &depth_key(61), 7, &[1.0, 0.0]);
+        assert_eq!(note_events(&messages), [(0x90, 67), (0x80, 67)]);
+
+        let skip = d_minor(OutOfScale::Skip);
+        assert!(play(&mut KeyState::new(), &skip, &depth_key(61), &[1.0, 0.0]).is_empty());
+        let messages = play(&mut KeyState::new(), &skip, &depth_key(62), &[1.0, 0.0]);
+        assert_eq!(note_events(&messages), [(0x90, 62), (0x80, 62)]);
+    }
+
+    #[test]
+    fn scale_locked_notes_end_where_they_started() {
+        let config = d_minor(OutOfScale::Snap);
+        let mut state = KeyState::new();
+        let mut messages = play_shifted(&mut state, &config, &depth_key(61), 7, &[1.0]);
+        // The shift is let go before the key
+        messages.extend(play_shifted(&mut state, &config, &depth_key(61), 0, &[0.0]));
+        assert_eq!(note_events(&messages), [(0x90, 67), (0x80, 67)]);
+    }
+
+    fn channel_pressures(messages: &[Vec<u8>]) -> Vec<u8> {
+        messages
+            .iter()
+            .filter(|message| message[0] == 0xD0)
+            .map(|message| message[1])
+            .collect()
+    }
+
+    #[test]
+    fn channel_pressure_aggregates_held_keys_until_the_last_lets_go() {
+        for (aggregate, both) in [
+            (PressureAggregate::Max, 127),
+            (PressureAggregate::Average, 121),
+        ] {
+            let mut config = Config {
+                channel_pressure: Some(aggregate),
+                ..Config::default()
+            };
+            config.key_configs.insert(HIDCodes::A, depth_key(60));
+            config.key_configs.insert(HIDCodes::S, depth_key(62));
+            let mut service = MidiService::new();
+            service.set_config(config).unwrap();
+            service.enable_state = EnableState::Full;
+            let mut pressure = |readings: &[(HIDCodes, f32)]| tick(&mut service, readings);
+
+            let both_held = [(HIDCodes::A, 0.9), (HIDCodes::S, 1.0)];
+            assert_eq!(channel_pressures(&pressure(&both_held)), [both]);
+            // Only changes of the 7-bit value are sent
+            assert!(pressure(&both_held).is_empty());
+            // Poly aftertouch keeps going alongside
+            let messages = pressure(&[(HIDCodes::A, 0.9), (HIDCodes::S, 0.95)]);
+            assert_eq!(pressures(&messages), [121]);
+            let messages = pressure(&[(HIDCodes::A, 0.9)]);
+            assert_eq!(channel_pressures(&messages), [114]);
+            assert_eq!(channel_pressures(&pressure(&[])), [0]);
+            assert!(pressure(&[]).is_empty());
+        }
+    }
+
+    fn wiggle(from: u64, to: u64, amplitude: f32) -> Vec<(Duration, f32)> {
+        (from..to)
+            .step_by(10)
+            .map(|ms| {
+                // A 5 Hz wiggle around a held depth of 0.9
+                let swing = if ms % 200 < 100 {
+                    amplitude
+                } else {
+                    -amplitude
+                };
+                (Duration::from_millis(ms), 0.9 + swing)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wiggling_a_held_key_sends_vibrato_that_fades_when_still() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            vibrato: Some(VibratoConfig {
+                target: VibratoTarget::ModWheel,
+                ..VibratoConfig::default()
+            }),
+            ..depth_key(60)
+        };
+        let mod_wheel = |messages: &[Vec<u8>]| -> Vec<u8> {
+            messages
+                .iter()
+                .filter(|message| message[..2] == [0xB0, CC_MOD_WHEEL])
+                .map(|message| message[2])
+                .collect()
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+
+        // Holding still and wiggling below the sensitivity is plain aftertouch
+        let messages = play_at(
+            &mut state,
+            &config,
+            &key_config,
+            start,
+            &wiggle(0, 500, 0.01),
+        );
+        assert!(mod_wheel(&messages).is_empty());
+
+        let messages = play_at(
+            &mut state,
+            &config,
+            &key_config,
+            start,
+            &wiggle(500, 1000, 0.05),
+        );
+        assert!(mod_wheel(&messages).iter().any(|&value| value > 0));
+
+        let still: Vec<_> = (1000..1500)
+            .step_by(10)
+            .map(|ms| (Duration::from_millis(ms), 0.9))
+            .collect();
+        let messages = play_at(&mut state, &config, &key_config, start, &still);
+        let faded = mod_wheel(&messages);
+        assert!(faded.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(faded.last(), Some(&0));
+    }
+
+    #[test]
+    fn vibrato_bends_both_ways_and_returns_to_center_on_release() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            vibrato: Some(VibratoConfig::default()),
+            ..depth_key(60)
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let bends = |messages: &[Vec<u8>]| -> Vec<u16> {
+            messages
+                .iter()
+                .filter(|message| message[0] == 0xE0)
+                .map(|message| u16::from(message[1]) | u16::from(message[2]) << 7)
+                .collect()
+        };
+
+        let messages = play_at(
+            &mut state,
+            &config,
+            &key_config,
+            start,
+            &wiggle(0, 1000, 0.05),
+        );
+        let bent = bends(&messages);
+        assert!(bent.iter().any(|&bend| bend > 8192));
+        assert!(bent.iter().any(|&bend| bend < 8192));
+
+        let messages = play_at(
+            &mut state,
+            &config,
+            &key_config,
+            start,
+            &[(Duration::from_millis(1000), 0.0)],
+        );
+        assert_eq!(bends(&messages), [8192]);
+    }
+
+    fn at(ms: u64, value: f32) -> (Duration, f32) {
+        (Duration::from_millis(ms), value)
+    }
+
+    #[test]
+    fn presses_bouncing_back_within_min_retrigger_stay_silent() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            min_retrigger_ms: 20,
+            ..depth_key(60)
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let mut play = |readings: &[(Duration, f32)]| {
+            note_events(&play_at(&mut state, &config, &key_config, start, readings))
+        };
+
+        assert_eq!(play(&[at(0, 1.0), at(5, 0.0)]), [(0x90, 60), (0x80, 60)]);
+        // The bounce stays silent for the rest of its press, even past the interval
+        assert!(play(&[at(10, 1.0), at(30, 1.0), at(35, 0.0)]).is_empty());
+        assert_eq!(play(&[at(60, 1.0)]), [(0x90, 60)]);
+    }
+
+    #[test]
+    fn note_offs_wait_for_min_note() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            min_note_ms: 30,
+            ..depth_key(60)
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let mut play = |readings: &[(Duration, f32)]| {
+            note_events(&play_at(&mut state, &config, &key_config, start, readings))
+        };
+
+        assert_eq!(play(&[at(0, 1.0)]), [(0x90, 60)]);
+        assert!(play(&[at(10, 0.0), at(20, 0.0)]).is_empty());
+        assert_eq!(play(&[at(30, 0.0)]), [(0x80, 60)]);
+
+        // Coming back down before then keeps the same note going
+        assert_eq!(play(&[at(100, 1.0)]), [(0x90, 60)]);
+        assert!(play(&[at(110, 0.0), at(120, 1.0), at(160, 1.0)]).is_empty());
+    }
+
+    #[test]
+    fn debounce_is_off_by_default() {
+        let config = Config::default();
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let readings = [at(0, 1.0), at(1, 0.0), at(2, 1.0), at(3, 0.0)];
+        assert_eq!(
+            note_events(&play_at(
+                &mut state,
+                &config,
+                &depth_key(60),
+                start,
+                &readings
+            )),
+            [(0x90, 60), (0x80, 60), (0x90, 60), (0x80, 60)]
+        );
+    }
+
+    #[test]
+    fn range_calibration_learns_fully_pressed_keys_silently() {
+        let mut config = Config::default();
+        config.key_configs.insert(HIDCodes::A, key(60));
+        config.key_configs.insert(HIDCodes::S, key(62));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        assert_eq!(service.range_calibration_pending(), None);
+
+        service.start_range_calibration().unwrap();
+        let mut pending = service.range_calibration_pending().unwrap();
+        pending.sort_by_key(|hid_code| hid_code.to_u16());
+        assert_eq!(pending, [HIDCodes::A, HIDCodes::S]);
+
+        // S only gets brushed, so it keeps its old range
+        for readings in [
+            [(HIDCodes::A, 0.1), (HIDCodes::S, 0.2)],
+            [(HIDCodes::A, 0.9), (HIDCodes::S, 0.3)],
+            [(HIDCodes::A, 0.0), (HIDCodes::S, 0.0)],
+        ] {
+            assert!(tick(&mut service, &readings).is_empty());
+        }
+        assert_eq!(service.range_calibration_pending(), Some(vec![HIDCodes::S]));
+
+        let ranges = service.finish_range_calibration();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[&HIDCodes::A], (0.0, 0.9));
+        let active = &service.config().key_configs;
+        assert_eq!(
+            (
+                active[&HIDCodes::A].calibration_min,
+                active[&HIDCodes::A].calibration_max
+            ),
+            (0.0, 0.9)
+        );
+        assert_eq!(
+            (
+                active[&HIDCodes::S].calibration_min,
+                active[&HIDCodes::S].calibration_max
+            ),
+            (key(62).calibration_min, key(62).calibration_max)
+        );
+        assert_eq!(service.range_calibration_pending(), None);
+
+        // Playing resumes once finished
+        let messages = tick(&mut service, &[(HIDCodes::A, 0.9)]);
+        assert_eq!(note_events(&messages), [(0x90, 60)]);
+    }
+
+    #[test]
+    fn rapid_trigger_follows_direction_until_the_key_is_let_up() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            rapid_trigger: Some(RapidTriggerConfig {
+                press_sensitivity: 0.1,
+                release_sensitivity: 0.1,
+            }),
+            ..depth_key(60)
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let mut play = |values: &[f32]| {
+            let readings: Vec<_> = values
+                .iter()
+                .map(|&value| (Duration::ZERO, value))
+                .collect();
+            note_events(&play_at(&mut state, &config, &key_config, start, &readings))
+        };
+
+        assert_eq!(play(&[0.9]), [(0x90, 60)]);
+        // Rising less than the release distance from the deepest point holds the note
+        assert!(play(&[0.95, 0.86]).is_empty());
+        assert_eq!(play(&[0.84]), [(0x80, 60)]);
+        // Pressing down again from the highest point retriggers, well short of the threshold
+        assert!(play(&[0.5, 0.59]).is_empty());
+        assert_eq!(play(&[0.61]), [(0x90, 60)]);
+        assert_eq!(play(&[0.55, 0.5]), [(0x80, 60)]);
+
+        // Letting up fully returns to the regular threshold
+        assert!(play(&[0.0, 0.5]).is_empty());
+        assert_eq!(play(&[0.9]), [(0x90, 60)]);
+    }
+
+    #[test]
+    fn key_states_snapshot_what_the_engine_sees() {
+        let mut config = Config::default();
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        config.key_configs.insert(HIDCodes::S, depth_key(62));
+        let mut service = MidiService::new();
+        service.set_config(config).unwrap();
+        service.enable_state = EnableState::Full;
+        tick(&mut service, &[(HIDCodes::A, 1.0), (HIDCodes::S, 0.5)]);
+
+        let snapshots = service.key_states();
+        let keys: Vec<_> = snapshots.iter().map(|s| s.hid_code.clone()).collect();
+        assert_eq!(keys, [HIDCodes::A, HIDCodes::S]);
+        let (a, s) = (&snapshots[0], &snapshots[1]);
+        assert_eq!(
+            (a.value, a.pressed, a.velocity, a.note),
+            (1.0, true, 1.0, Some(60))
+        );
+        assert_eq!((s.value, s.pressed, s.note), (0.5, false, None));
+
+        let json = serde_json::to_value(a).unwrap();
+        assert_eq!(json["hid_code"], "A");
+        assert_eq!(json["note"], 60);
+    }
+
+    #[test]
+    fn brushes_shorter_than_min_press_stay_silent() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            min_press_ms: 20,
+            ..depth_key(60)
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let mut play = |readings: &[(Duration, f32)]| {
+            play_at(&mut state, &config, &key_config, start, readings)
+        };
+
+        assert!(play(&[at(0, 0.9), at(10, 0.9), at(15, 0.0)]).is_empty());
+
+        // A press that stays down long enough keeps the velocity measured when it began
+        assert!(play(&[at(100, 0.9), at(110, 1.0)]).is_empty());
+        assert_eq!(note_ons(&play(&[at(120, 1.0)])), [(60, 63)]);
+        assert_eq!(note_events(&play(&[at(130, 0.0)])), [(0x80, 60)]);
+    }
+
+    #[test]
+    fn reaching_min_press_depth_qualifies_right_away() {
+        let config = Config::default();
+        let key_config = KeyConfig {
+            min_press_ms: 20,
+            min_press_depth: Some(0.95),
+            ..depth_key(60)
+        };
+        let start = Instant::now();
+        let mut state = KeyState::new();
+        let mut play = |readings: &[(Duration, f32)]| {
+            play_at(&mut state, &config, &key_config, start, readings)
+        };
+
+        assert!(play(&[at(0, 0.9)]).is_empty());
+        assert_eq!(note_ons(&play(&[at(5, 1.0)])), [(60, 63)]);
+        assert_eq!(note_ons(&play(&[at(100, 0.0), at(200, 1.0)])), [(60, 127)]);
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn activating_a_preset_ends_held_notes() {
+        let mut config = Config {
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let lead = Preset {
+            key_configs: [(HIDCodes::A, depth_key(72))].into_iter().collect(),
+        };
+        config.presets.insert("lead".to_owned(), lead);
+        let (mut service, writer) = connected_service(config);
+        assert_eq!(tick(&mut service, &[(HIDCodes::A, 1.0)]), [[0x90, 60, 127]]);
+
+        service.activate_preset("lead").unwrap();
+        assert_eq!(take_messages(&writer), [[0x80, 60, 127]]);
+        // The held key starts over with the note of the preset
+        assert_eq!(tick(&mut service, &[(HIDCodes::A, 1.0)]), [[0x90, 72, 127]]);
+    }
+
+    #[cfg(feature = "midi2")]
+    #[test]
+    fn channel_changes_release_held_notes_on_their_old_channel() {
+        let mut config = Config {
+            reset_controllers_on_switch: false,
+            ..Config::default()
+        };
+        config.key_configs.insert(HIDCodes::A, depth_key(60));
+        let key_config = KeyConfig {
+            channel: 1,
+            ..depth_key(62)
+        };
+        config.key_configs.insert(HIDCodes::S, key_config);
+        let (mut service, writer) = connected_service(config);
+        let held = [(HIDCodes::A, 1.0), (HIDCodes::S, 1.0)];
+        tick(&mut service, &held);
+
+        service.set_default_channel(Some(3)).unwrap();
+        let mut messages = take_messages(&writer);
+        messages.sort();
+        assert_eq!(messages, [[0x80, 60, 127], [0x81, 62, 127]]);
+        let mut messages = tick(&mut service, &held);
+        messages.sort();
+        assert_eq!(messages, [[0x93, 60, 127], [0x93, 62, 127]]);
+
+        service.set_default_channel(None).unwrap();
+        let mut messages = take_messages(&writer);
+        messages.sort();
+        assert_eq!(messages, [[0x83, 60, 127], [0x83, 62, 127]]);
+        let mut messages = tick(&mut service, &held);
+        messages.sort();
+        assert_eq!(messages, [[0x90, 60, 127], [0x91, 62, 127]]);
     }
 }
@@ -0,0 +1,282 @@
+use crate::config::{Config, MonoConfig, NotePriority};
+use crate::note::NoteSink;
+use crate::{Channel, NoteID};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+
+#[derive(Debug, Clone, Copy)]
+struct HeldNote {
+    note_id: NoteID,
+    velocity: f32,
+    high_resolution: bool,
+}
+
+#[derive(Debug, Default)]
+struct Voice {
+    // Ordered from oldest to newest
+    held: Vec<HeldNote>,
+    sounding: Option<HeldNote>,
+}
+
+/// Notes held on each mono channel and the one of them currently sounding
+#[derive(Debug, Default)]
+pub(crate) struct MonoVoices {
+    voices: FxHashMap<Channel, Voice>,
+}
+
+impl MonoVoices {
+    /// Ends every sounding voice and forgets the held notes, their note offs are dropped later
+    pub(crate) fn release(&mut self, sink: &mut impl NoteSink) -> Result<()> {
+        for (channel, voice) in self.voices.drain() {
+            if let Some(note) = voice.sounding {
+                sink.note_off(note.note_id, note.velocity, channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.voices.clear();
+    }
+}
+
+/// Lets only one note per mono channel sound, picking among the held ones by priority and
+/// retriggering the remaining ones on release. Other channels are forwarded as is.
+pub(crate) struct MonoSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    voices: &'a mut MonoVoices,
+    mono_channels: &'a FxHashMap<Channel, MonoConfig>,
+}
+
+impl<'a, S: NoteSink> MonoSink<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, voices: &'a mut MonoVoices, config: &'a Config) -> Self {
+        Self {
+            inner,
+            voices,
+            mono_channels: &config.mono_channels,
+        }
+    }
+
+    fn press(&mut self, note: HeldNote, channel: Channel) -> Result<()> {
+        let Some(mono) = self.mono_channels.get(&channel) else {
+            return start(self.inner, note, channel);
+        };
+        let voice = self.voices.voices.entry(channel).or_default();
+        voice.held.retain(|held| held.note_id != note.note_id);
+        voice.held.push(note);
+        switch(self.inner, voice, mono, note.velocity, channel)
+    }
+
+    fn release(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let Some(mono) = self.mono_channels.get(&channel) else {
+            return self.inner.note_off(note_id, velocity, channel);
+        };
+        let Some(voice) = self.voices.voices.get_mut(&channel) else {
+            return Ok(());
+        };
+        voice.held.retain(|held| held.note_id != note_id);
+        if voice.sounding.map(|note| note.note_id) != Some(note_id) {
+            return Ok(());
+        }
+        switch(self.inner, voice, mono, velocity, channel)
+    }
+}
+
+fn switch(
+    sink: &mut impl NoteSink,
+    voice: &mut Voice,
+    mono: &MonoConfig,
+    off_velocity: f32,
+    channel: Channel,
+) -> Result<()> {
+    let next = match mono.priority {
+        NotePriority::Last => voice.held.last(),
+        NotePriority::High => voice.held.iter().max_by_key(|note| note.note_id),
+        NotePriority::Low => voice.held.iter().min_by_key(|note| note.note_id),
+    }
+    .copied();
+    if next.map(|note| note.note_id) == voice.sounding.map(|note| note.note_id) {
+        return Ok(());
+    }
+
+    match (voice.sounding.take(), next) {
+        // Overlapping the notes makes the receiver glide instead of retriggering its envelopes
+        (Some(previous), Some(next)) if mono.legato => {
+            start(sink, next, channel)?;
+            sink.note_off(previous.note_id, off_velocity, channel)?;
+        }
+        (previous, next) => {
+            if let Some(previous) = previous {
+                sink.note_off(previous.note_id, off_velocity, channel)?;
+            }
+            if let Some(next) = next {
+                start(sink, next, channel)?;
+            }
+        }
+    }
+    voice.sounding = next;
+    Ok(())
+}
+
+fn start(sink: &mut impl NoteSink, note: HeldNote, channel: Channel) -> Result<()> {
+    if note.high_resolution {
+        sink.note_on_high_resolution(note.note_id, note.velocity, channel)
+    } else {
+        sink.note_on(note.note_id, note.velocity, channel)
+    }
+}
+
+impl<S: NoteSink> NoteSink for MonoSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let note = HeldNote {
+            note_id,
+            velocity,
+            high_resolution: false,
+        };
+        self.press(note, channel)
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        let note = HeldNote {
+            note_id,
+            velocity,
+            high_resolution: true,
+        };
+        self.press(note, channel)
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.release(note_id, velocity, channel)
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        if self.mono_channels.contains_key(&channel) {
+            // Held notes that are not sounding have no pressure to report
+            let sounding = self
+                .voices
+                .voices
+                .get(&channel)
+                .and_then(|voice| voice.sounding);
+            if sounding.map(|note| note.note_id) != Some(note_id) {
+                return Ok(());
+            }
+        }
+        self.inner.polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.inner.sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays presses (`true`) and releases of notes on channel 0, returning (status, note) pairs
+    fn play(priority: NotePriority, legato: bool, events: &[(bool, NoteID)]) -> Vec<(u8, u8)> {
+        let mut config = Config::default();
+        config
+            .mono_channels
+            .insert(0, MonoConfig { priority, legato });
+        let mut voices = MonoVoices::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = MonoSink::new(&mut output, &mut voices, &config);
+        for &(pressed, note_id) in events {
+            if pressed {
+                sink.note_on(note_id, 1.0, 0).unwrap();
+            } else {
+                sink.note_off(note_id, 0.0, 0).unwrap();
+            }
+        }
+        output
+            .iter()
+            .map(|message| (message[0], message[1]))
+            .collect()
+    }
+
+    #[test]
+    fn last_priority_returns_to_the_held_note() {
+        let events = [(true, 60), (true, 64), (false, 64), (false, 60)];
+        assert_eq!(
+            play(NotePriority::Last, false, &events),
+            [
+                (0x90, 60),
+                (0x80, 60),
+                (0x90, 64),
+                (0x80, 64),
+                (0x90, 60),
+                (0x80, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn high_and_low_priority_keep_their_extreme() {
+        let events = [(true, 60), (true, 64), (true, 62), (false, 64)];
+        assert_eq!(
+            play(NotePriority::High, false, &events),
+            [(0x90, 60), (0x80, 60), (0x90, 64), (0x80, 64), (0x90, 62)]
+        );
+        assert_eq!(play(NotePriority::Low, false, &events), [(0x90, 60)]);
+    }
+
+    #[test]
+    fn legato_overlaps_the_notes() {
+        let events = [(true, 60), (true, 64), (false, 64)];
+        assert_eq!(
+            play(NotePriority::Last, true, &events),
+            [(0x90, 60), (0x90, 64), (0x80, 60), (0x90, 60), (0x80, 64)]
+        );
+    }
+
+    #[test]
+    fn releasing_a_silent_held_note_sends_nothing() {
+        let events = [(true, 60), (true, 64), (false, 60)];
+        assert_eq!(
+            play(NotePriority::Last, false, &events),
+            [(0x90, 60), (0x80, 60), (0x90, 64)]
+        );
+    }
+
+    #[test]
+    fn other_channels_are_forwarded() {
+        let config = Config::default();
+        let mut voices = MonoVoices::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = MonoSink::new(&mut output, &mut voices, &config);
+        sink.note_on(60, 1.0, 0).unwrap();
+        sink.note_on(64, 1.0, 0).unwrap();
+        assert_eq!(output, [vec![0x90, 60, 127], vec![0x90, 64, 127]]);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::config::Config;
+use crate::note::{NoteSink, CC_DATA_ENTRY_MSB, CC_RPN_LSB, CC_RPN_MSB, RPN_NULL};
+use crate::{Channel, NoteID};
+use anyhow::Result;
+
+const MPE_MASTER_CHANNEL: Channel = 0;
+const MPE_MEMBER_CHANNELS_MAX: u8 = 15;
+
+const RPN_MPE_CONFIGURATION: (u8, u8) = (0, 6);
+
+/// Hands out the member channels of the lower MPE zone round-robin, one per sounding note
+#[derive(Debug, Default)]
+pub(crate) struct ChannelAllocator {
+    next: u8,
+    // Ordered from oldest to newest
+    sounding: Vec<(NoteID, Channel)>,
+}
+
+impl ChannelAllocator {
+    fn allocate(&mut self, note_id: NoteID, member_channels: u8) -> Channel {
+        let free = (0..member_channels)
+            .map(|offset| 1 + (self.next + offset) % member_channels)
+            .find(|channel| self.sounding.iter().all(|&(_, used)| used != *channel));
+        // With more notes than member channels the oldest channel is shared
+        let channel = free.unwrap_or_else(|| self.sounding[0].1);
+        self.next = channel % member_channels;
+        self.sounding.push((note_id, channel));
+        channel
+    }
+
+    fn free(&mut self, note_id: NoteID) -> Option<Channel> {
+        let index = self
+            .sounding
+            .iter()
+            .position(|&(note, _)| note == note_id)?;
+        Some(self.sounding.remove(index).1)
+    }
+
+    fn channel_of(&self, note_id: NoteID) -> Option<Channel> {
+        self.sounding
+            .iter()
+            .rev()
+            .find(|&&(note, _)| note == note_id)
+            .map(|&(_, channel)| channel)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.sounding.clear();
+        self.next = 0;
+    }
+}
+
+/// Routes notes and their pressure to MPE member channels, everything else is forwarded as is.
+/// Without MPE enabled this is a plain passthrough.
+pub(crate) struct MpeSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    allocator: &'a mut ChannelAllocator,
+    member_channels: u8,
+}
+
+impl<'a, S: NoteSink> MpeSink<'a, S> {
+    pub(crate) fn new(
+        inner: &'a mut S,
+        allocator: &'a mut ChannelAllocator,
+        config: &Config,
+    ) -> Self {
+        Self {
+            inner,
+            allocator,
+            member_channels: member_channels(config),
+        }
+    }
+
+    fn allocate(&mut self, note_id: NoteID, channel: Channel) -> Channel {
+        match self.member_channels {
+            0 => channel,
+            member_channels => self.allocator.allocate(note_id, member_channels),
+        }
+    }
+}
+
+impl<S: NoteSink> NoteSink for MpeSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let channel = self.allocate(note_id, channel);
+        self.inner.note_on(note_id, velocity, channel)
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        let channel = self.allocate(note_id, channel);
+        self.inner
+            .note_on_high_resolution(note_id, velocity, channel)
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let channel = self.allocator.free(note_id).unwrap_or(channel);
+        self.inner.note_off(note_id, velocity, channel)
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        match self.allocator.channel_of(note_id) {
+            // Each member channel carries a single note, so its pressure is the channel pressure
+            Some(channel) => self.inner.channel_pressure(pressure, channel),
+            None => self.inner.polyphonic_aftertouch(note_id, pressure, channel),
+        }
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.inner.sysex(message)
+    }
+}
+
+pub(crate) fn member_channels(config: &Config) -> u8 {
+    if config.mpe {
+        config.mpe_zone_size.clamp(1, MPE_MEMBER_CHANNELS_MAX)
+    } else {
+        0
+    }
+}
+
+/// Sends the MPE configuration RPN for the lower zone, a size of 0 turns MPE off on the receiver
+pub(crate) fn send_mpe_configuration(sink: &mut impl NoteSink, config: &Config) -> Result<()> {
+    for (controller, value) in [
+        (CC_RPN_MSB, RPN_MPE_CONFIGURATION.0),
+        (CC_RPN_LSB, RPN_MPE_CONFIGURATION.1),
+        (CC_DATA_ENTRY_MSB, member_channels(config)),
+        (CC_RPN_MSB, RPN_NULL.0),
+        (CC_RPN_LSB, RPN_NULL.1),
+    ] {
+        sink.control_change(controller, value, MPE_MASTER_CHANNEL)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mpe_config(zone_size: u8) -> Config {
+        Config {
+            mpe: true,
+            mpe_zone_size: zone_size,
+            ..Config::default()
+        }
+    }
+
+    /// Plays presses (`true`) and releases of notes on channel 0, returning (status, note) pairs
+    fn play(config: &Config, events: &[(bool, NoteID)]) -> Vec<(u8, u8)> {
+        let mut allocator = ChannelAllocator::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = MpeSink::new(&mut output, &mut allocator, config);
+        for &(pressed, note_id) in events {
+            if pressed {
+                sink.note_on(note_id, 1.0, 0).unwrap();
+            } else {
+                sink.note_off(note_id, 0.0, 0).unwrap();
+            }
+        }
+        output
+            .iter()
+            .map(|message| (message[0], message[1]))
+            .collect()
+    }
+
+    #[test]
+    fn notes_take_member_channels_round_robin() {
+        let events = [(true, 60), (true, 64), (false, 60), (true, 67), (true, 72)];
+        assert_eq!(
+            play(&mpe_config(3), &events),
+            [(0x91, 60), (0x92, 64), (0x81, 60), (0x93, 67), (0x91, 72)]
+        );
+    }
+
+    #[test]
+    fn a_full_zone_shares_the_oldest_channel() {
+        let events = [(true, 60), (true, 64), (true, 67), (false, 60), (false, 67)];
+        assert_eq!(
+            play(&mpe_config(2), &events),
+            [(0x91, 60), (0x92, 64), (0x91, 67), (0x81, 60), (0x81, 67)]
+        );
+    }
+
+    #[test]
+    fn without_mpe_notes_keep_their_channel() {
+        let events = [(true, 60), (true, 64), (false, 60)];
+        assert_eq!(
+            play(&Config::default(), &events),
+            [(0x90, 60), (0x90, 64), (0x80, 60)]
+        );
+    }
+
+    #[test]
+    fn pressure_and_bend_follow_the_member_channel() {
+        let config = mpe_config(4);
+        let mut allocator = ChannelAllocator::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = MpeSink::new(&mut output, &mut allocator, &config);
+        sink.note_on(60, 1.0, 0).unwrap();
+        sink.note_on(64, 1.0, 0).unwrap();
+        sink.polyphonic_aftertouch(64, 1.0, 0).unwrap();
+        sink.polyphonic_aftertouch(67, 1.0, 0).unwrap();
+        sink.pitch_bend(0.0, 2).unwrap();
+        assert_eq!(
+            output[2..],
+            [vec![0xD2, 127], vec![0xA0, 67, 127], vec![0xE2, 0x00, 0x40],]
+        );
+    }
+
+    #[test]
+    fn the_configuration_rpn_announces_the_zone() {
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        send_mpe_configuration(&mut output, &mpe_config(20)).unwrap();
+        assert_eq!(
+            output,
+            [
+                vec![0xB0, 101, 0],
+                vec![0xB0, 100, 6],
+                vec![0xB0, 6, 15],
+                vec![0xB0, 101, 127],
+                vec![0xB0, 100, 127],
+            ]
+        );
+    }
+}
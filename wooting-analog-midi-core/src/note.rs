@@ -5,11 +5,47 @@ use midir::MidiOutputConnection;
 const NOTE_ON_MSG: u8 = 0x90;
 const NOTE_OFF_MSG: u8 = 0x80;
 const POLY_AFTERTOUCH_MSG: u8 = 0xA0;
+const CONTROL_CHANGE_MSG: u8 = 0xB0;
+const PROGRAM_CHANGE_MSG: u8 = 0xC0;
+const CHANNEL_PRESSURE_MSG: u8 = 0xD0;
+const PITCH_BEND_MSG: u8 = 0xE0;
+pub(crate) const CLOCK_TICK_MSG: u8 = 0xF8;
+pub(crate) const CLOCK_START_MSG: u8 = 0xFA;
+pub(crate) const CLOCK_CONTINUE_MSG: u8 = 0xFB;
+pub(crate) const CLOCK_STOP_MSG: u8 = 0xFC;
+pub(crate) const PITCH_BEND_CENTER: u16 = 8192;
+pub(crate) const CC_BANK_SELECT_MSB: u8 = 0;
+pub(crate) const CC_MOD_WHEEL: u8 = 1;
+pub(crate) const CC_DATA_ENTRY_MSB: u8 = 6;
+pub(crate) const CC_BANK_SELECT_LSB: u8 = 32;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+// Controllers 0 to 31 have their LSB 32 controllers above
+pub(crate) const CC_LSB_OFFSET: u8 = 32;
+pub(crate) const CC_RPN_LSB: u8 = 100;
+pub(crate) const CC_RPN_MSB: u8 = 101;
+pub(crate) const RPN_PITCH_BEND_RANGE: (u8, u8) = (0, 0);
+pub(crate) const RPN_NULL: (u8, u8) = (127, 127);
+pub(crate) const CC_SUSTAIN: u8 = 64;
+const CC_HIGH_RESOLUTION_VELOCITY_PREFIX: u8 = 88;
+pub(crate) const CC_ALL_SOUND_OFF: u8 = 120;
+pub(crate) const CC_RESET_ALL_CONTROLLERS: u8 = 121;
+pub(crate) const CC_ALL_NOTES_OFF: u8 = 123;
 pub(crate) const MIDI_NOTE_MAX: NoteID = 108;
 pub(crate) const MIDI_NOTE_MIN: NoteID = 21;
+const VELOCITY_STEPS_MIN: u8 = 2;
+const VELOCITY_STEPS_MAX: u8 = 16;
 
 pub(crate) trait NoteSink {
     fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()>;
+    /// Note on with a 14-bit velocity, sinks without a higher resolution send a plain note on
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.note_on(note_id, velocity, channel)
+    }
     fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()>;
     fn polyphonic_aftertouch(
         &mut self,
@@ -17,18 +53,211 @@ pub(crate) trait NoteSink {
         pressure: f32,
         channel: Channel,
     ) -> Result<()>;
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()>;
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()>;
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()>;
+    /// Bends by `value` in -1.0..=1.0, where 0.0 is the center
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()>;
+    fn system_realtime(&mut self, status: u8) -> Result<()>;
+    /// Sends a complete SysEx message including the 0xF0 and 0xF7 framing bytes
+    fn sysex(&mut self, message: &[u8]) -> Result<()>;
+
+    /// Sets a registered parameter to a (MSB, LSB) value and deselects it again afterwards, so
+    /// stray data entry messages cannot change it
+    fn registered_parameter(
+        &mut self,
+        parameter: (u8, u8),
+        value: (u8, u8),
+        channel: Channel,
+    ) -> Result<()> {
+        self.control_change(CC_RPN_MSB, parameter.0, channel)?;
+        self.control_change(CC_RPN_LSB, parameter.1, channel)?;
+        self.control_change(CC_DATA_ENTRY_MSB, value.0, channel)?;
+        self.control_change(CC_DATA_ENTRY_LSB, value.1, channel)?;
+        self.control_change(CC_RPN_MSB, RPN_NULL.0, channel)?;
+        self.control_change(CC_RPN_LSB, RPN_NULL.1, channel)
+    }
+
+    /// Sends a 14-bit value as MSB on `controller` and LSB on `controller + 32`. The MSB is
+    /// skipped while it matches the one of `previous`, the LSB always follows as receivers reset
+    /// it on every MSB.
+    fn control_change_14bit(
+        &mut self,
+        controller: u8,
+        value: u16,
+        previous: Option<u16>,
+        channel: Channel,
+    ) -> Result<()> {
+        let msb = (value >> 7) as u8 & 0x7F;
+        if previous.map(|previous| (previous >> 7) as u8 & 0x7F) != Some(msb) {
+            self.control_change(controller, msb, channel)?;
+        }
+        self.control_change(controller + CC_LSB_OFFSET, (value & 0x7F) as u8, channel)
+    }
+
+    fn reset_controllers(&mut self, channel: Channel, defaults: &[(u8, u8)]) -> Result<()> {
+        self.control_change(CC_RESET_ALL_CONTROLLERS, 0, channel)?;
+        self.control_change(CC_SUSTAIN, 0, channel)?;
+        self.pitch_bend(0.0, channel)?;
+        for &(controller, value) in defaults {
+            self.control_change(controller, value, channel)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a velocity into the 7-bit note on velocity and the 7 extra bits of the CC88 prefix
+/// 7-bit value of a velocity or pressure in 0.0..=1.0, rounded to the nearest step
+pub(crate) fn unit_to_byte(unit: f32) -> u8 {
+    (unit.clamp(0.0, 1.0) * 127.0).round() as u8
+}
+
+pub(crate) fn high_resolution_velocity(velocity: f32) -> (u8, u8) {
+    let value = (velocity.clamp(0.0, 1.0) * 16383.0).round() as u16;
+    ((value >> 7) as u8, (value & 0x7F) as u8)
+}
+
+pub(crate) fn pitch_bend_value(value: f32) -> u16 {
+    ((value.clamp(-1.0, 1.0) + 1.0) * PITCH_BEND_CENTER as f32)
+        .round()
+        .min(16383.0) as u16
+}
+
+/// Forwards to another sink while counting the sent messages
+pub(crate) struct CountingSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    pub(crate) count: usize,
+}
+
+impl<'a, S: NoteSink> CountingSink<'a, S> {
+    pub(crate) fn new(inner: &'a mut S) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<S: NoteSink> NoteSink for CountingSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.count += 1;
+        self.inner.note_on(note_id, velocity, channel)
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.count += 1;
+        self.inner
+            .note_on_high_resolution(note_id, velocity, channel)
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.count += 1;
+        self.inner.note_off(note_id, velocity, channel)
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.count += 1;
+        self.inner.polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.count += 1;
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.count += 1;
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.count += 1;
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.count += 1;
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.count += 1;
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.count += 1;
+        self.inner.sysex(message)
+    }
+}
+
+/// Snaps a velocity to the center of one of `steps` equally sized layers
+pub(crate) fn quantize_velocity(velocity: f32, steps: u8) -> f32 {
+    let steps = steps.clamp(VELOCITY_STEPS_MIN, VELOCITY_STEPS_MAX) as f32;
+    let step = (velocity.clamp(0.0, 1.0) * steps).floor().min(steps - 1.0);
+    (step + 0.5) / steps
+}
+
+/// Raw byte output for complete MIDI 1.0 messages
+pub(crate) trait MidiWrite {
+    fn write_message(&mut self, message: &[u8]) -> Result<()>;
+}
+
+impl MidiWrite for MidiOutputConnection {
+    fn write_message(&mut self, message: &[u8]) -> Result<()> {
+        self.send(message)?;
+        Ok(())
+    }
+}
+
+/// Collects the sent messages in tests
+#[cfg(test)]
+impl MidiWrite for Vec<Vec<u8>> {
+    fn write_message(&mut self, message: &[u8]) -> Result<()> {
+        self.push(message.to_vec());
+        Ok(())
+    }
 }
 
-impl NoteSink for MidiOutputConnection {
+impl<W: MidiWrite> NoteSink for W {
     fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
-        let vbyte = (f32::min(velocity, 1.0) * 127.0) as u8;
-        self.send(&[NOTE_ON_MSG | channel, note_id, vbyte])?;
+        // Velocity 0 would be read as a note off
+        let vbyte = unit_to_byte(velocity).max(1);
+        self.write_message(&[NOTE_ON_MSG | (channel & 0x0F), note_id, vbyte])?;
+        Ok(())
+    }
+
+    /// Sends the low bits as a CC88 prefix as defined by CA-031, receivers that ignore it still
+    /// get the high bits as a regular note on
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        let (msb, lsb) = match high_resolution_velocity(velocity) {
+            (0, _) => (1, 0),
+            value => value,
+        };
+        self.write_message(&[
+            CONTROL_CHANGE_MSG | (channel & 0x0F),
+            CC_HIGH_RESOLUTION_VELOCITY_PREFIX,
+            lsb,
+        ])?;
+        self.write_message(&[NOTE_ON_MSG | (channel & 0x0F), note_id, msb])?;
         Ok(())
     }
 
     fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
-        let vbyte = (f32::min(velocity, 1.0) * 127.0) as u8;
-        self.send(&[NOTE_OFF_MSG | channel, note_id, vbyte])?;
+        let vbyte = unit_to_byte(velocity);
+        self.write_message(&[NOTE_OFF_MSG | (channel & 0x0F), note_id, vbyte])?;
         Ok(())
     }
 
@@ -38,11 +267,168 @@ impl NoteSink for MidiOutputConnection {
         pressure: f32,
         channel: Channel,
     ) -> Result<()> {
-        self.send(&[
-            POLY_AFTERTOUCH_MSG | channel,
+        self.write_message(&[
+            POLY_AFTERTOUCH_MSG | (channel & 0x0F),
             note_id,
-            (f32::min(pressure, 1.0) * 127.0) as u8,
+            unit_to_byte(pressure),
         ])?;
         Ok(())
     }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.write_message(&[
+            CONTROL_CHANGE_MSG | (channel & 0x0F),
+            controller & 0x7F,
+            value & 0x7F,
+        ])?;
+        Ok(())
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.write_message(&[PROGRAM_CHANGE_MSG | (channel & 0x0F), program & 0x7F])?;
+        Ok(())
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.write_message(&[
+            CHANNEL_PRESSURE_MSG | (channel & 0x0F),
+            unit_to_byte(pressure),
+        ])?;
+        Ok(())
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        let value = pitch_bend_value(value);
+        self.write_message(&[
+            PITCH_BEND_MSG | (channel & 0x0F),
+            (value & 0x7F) as u8,
+            (value >> 7) as u8,
+        ])?;
+        Ok(())
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.write_message(&[status])?;
+        Ok(())
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.write_message(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on_bytes(velocities: &[f32], steps: u8) -> Vec<u8> {
+        let mut sink: Vec<Vec<u8>> = Vec::new();
+        for &velocity in velocities {
+            sink.note_on(60, quantize_velocity(velocity, steps), 0)
+                .unwrap();
+        }
+        sink.iter().map(|message| message[2]).collect()
+    }
+
+    #[test]
+    fn four_velocity_steps() {
+        let velocities = [0.0, 0.2499, 0.25, 0.4999, 0.5, 0.7499, 0.75, 1.0];
+        assert_eq!(
+            note_on_bytes(&velocities, 4),
+            [16, 16, 48, 48, 79, 79, 111, 111]
+        );
+    }
+
+    #[test]
+    fn eight_velocity_steps() {
+        let velocities = [
+            0.0, 0.1249, 0.125, 0.25, 0.375, 0.5, 0.625, 0.75, 0.875, 1.0,
+        ];
+        assert_eq!(
+            note_on_bytes(&velocities, 8),
+            [8, 8, 24, 40, 56, 71, 87, 103, 119, 119]
+        );
+    }
+
+    #[test]
+    fn channels_are_masked_into_the_status_byte() {
+        let mut sink: Vec<Vec<u8>> = Vec::new();
+        sink.note_on(60, 1.0, 16).unwrap();
+        sink.note_off(60, 0.0, 17).unwrap();
+        sink.polyphonic_aftertouch(60, 1.0, 31).unwrap();
+        sink.control_change(7, 100, 16).unwrap();
+        sink.program_change(4, 16).unwrap();
+        sink.channel_pressure(1.0, 16).unwrap();
+        sink.pitch_bend(0.0, 16).unwrap();
+        assert_eq!(
+            sink,
+            [
+                vec![0x90, 60, 127],
+                vec![0x81, 60, 0],
+                vec![0xAF, 60, 127],
+                vec![0xB0, 7, 100],
+                vec![0xC0, 4],
+                vec![0xD0, 127],
+                vec![0xE0, 0x00, 0x40],
+            ]
+        );
+    }
+
+    #[test]
+    fn registered_parameters_end_with_the_null_rpn() {
+        let mut sink: Vec<Vec<u8>> = Vec::new();
+        sink.registered_parameter(RPN_PITCH_BEND_RANGE, (12, 0), 2)
+            .unwrap();
+        assert_eq!(
+            sink,
+            [
+                [0xB2, 101, 0],
+                [0xB2, 100, 0],
+                [0xB2, 6, 12],
+                [0xB2, 38, 0],
+                [0xB2, 101, 127],
+                [0xB2, 100, 127],
+            ]
+        );
+    }
+
+    #[test]
+    fn fourteen_bit_controllers_send_the_msb_only_on_change() {
+        let mut sink: Vec<Vec<u8>> = Vec::new();
+        sink.control_change_14bit(7, 0x1234, None, 2).unwrap();
+        sink.control_change_14bit(7, 0x1235, Some(0x1234), 2)
+            .unwrap();
+        sink.control_change_14bit(7, 0x12B5, Some(0x1235), 2)
+            .unwrap();
+        assert_eq!(
+            sink,
+            [
+                vec![0xB2, 7, 0x24],
+                vec![0xB2, 39, 0x34],
+                vec![0xB2, 39, 0x35],
+                vec![0xB2, 7, 0x25],
+                vec![0xB2, 39, 0x35],
+            ]
+        );
+    }
+
+    #[test]
+    fn velocities_and_pressures_round_to_the_same_byte() {
+        let mut sink: Vec<Vec<u8>> = Vec::new();
+        for unit in [0.5, 0.125, 1.5, -0.5] {
+            sink.note_on(60, unit, 0).unwrap();
+            sink.note_off(60, unit, 0).unwrap();
+            sink.polyphonic_aftertouch(60, unit, 0).unwrap();
+            sink.channel_pressure(unit, 0).unwrap();
+        }
+        let bytes: Vec<u8> = sink
+            .iter()
+            .map(|message| *message.last().unwrap())
+            .collect();
+        // Only a note on keeps its velocity above 0, which would be read as a note off
+        assert_eq!(
+            bytes,
+            [64, 64, 64, 64, 16, 16, 16, 16, 127, 127, 127, 127, 1, 0, 0, 0]
+        );
+    }
 }
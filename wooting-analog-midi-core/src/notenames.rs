@@ -0,0 +1,130 @@
+use crate::NoteID;
+use serde::{Deserialize, Serialize};
+
+/// Octave number of middle C (note 60), both conventions are in wide use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MiddleC {
+    /// Yamaha and many DAWs, the lowest note is C-2
+    C3,
+    /// Scientific pitch notation, the lowest note is C-1
+    #[default]
+    C4,
+}
+
+impl MiddleC {
+    fn octave_offset(self) -> i16 {
+        match self {
+            MiddleC::C3 => 2,
+            MiddleC::C4 => 1,
+        }
+    }
+}
+
+const PITCH_CLASS_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Name of a note with sharps, e.g. "C#4" for 61
+pub fn name_of(note: NoteID, middle_c: MiddleC) -> String {
+    let octave = note as i16 / 12 - middle_c.octave_offset();
+    format!("{}{}", PITCH_CLASS_NAMES[note as usize % 12], octave)
+}
+
+/// Name of a note with middle C as C4
+pub fn note_name(note: NoteID) -> String {
+    name_of(note, MiddleC::C4)
+}
+
+/// Parses a note name with middle C as C4
+pub fn parse(name: &str) -> Option<NoteID> {
+    parse_in(name, MiddleC::C4)
+}
+
+/// Parses a note name like "C4", "f#2" or "Bb5", any number of sharps or flats is allowed.
+/// Plain note numbers are accepted as well.
+pub fn parse_in(name: &str, middle_c: MiddleC) -> Option<NoteID> {
+    let name = name.trim();
+    if let Ok(note) = name.parse::<u8>() {
+        return (note <= 127).then_some(note);
+    }
+
+    let mut chars = name.chars();
+    let pitch_class: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave_start = rest
+        .find(|c: char| c == '-' || c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (accidentals, octave) = rest.split_at(octave_start);
+    let mut alteration: i32 = 0;
+    for accidental in accidentals.chars() {
+        match accidental {
+            '#' | '♯' => alteration += 1,
+            'b' | '♭' => alteration -= 1,
+            _ => return None,
+        }
+    }
+    let octave: i32 = octave.parse().ok()?;
+
+    let note = octave
+        .checked_add(middle_c.octave_offset() as i32)?
+        .checked_mul(12)?
+        .checked_add(pitch_class + alteration)?;
+    (0..=127).contains(&note).then_some(note as NoteID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_note_parses_back() {
+        for middle_c in [MiddleC::C3, MiddleC::C4] {
+            for note in 0..=127 {
+                let name = name_of(note, middle_c);
+                assert_eq!(parse_in(&name, middle_c), Some(note), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn middle_c_conventions() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(name_of(60, MiddleC::C3), "C3");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(name_of(0, MiddleC::C3), "C-2");
+        assert_eq!(note_name(127), "G9");
+        assert_eq!(name_of(127, MiddleC::C3), "G8");
+        assert_eq!(parse("C4"), Some(60));
+        assert_eq!(parse_in("C4", MiddleC::C3), Some(72));
+    }
+
+    #[test]
+    fn accidentals_and_numbers() {
+        assert_eq!(parse("f#2"), Some(42));
+        assert_eq!(parse("Bb5"), Some(82));
+        assert_eq!(parse("C♯4"), Some(61));
+        assert_eq!(parse("E♭4"), Some(63));
+        assert_eq!(parse("Cb4"), Some(59));
+        assert_eq!(parse("B#3"), Some(60));
+        assert_eq!(parse("Dbb4"), Some(60));
+        assert_eq!(parse(" 64 "), Some(64));
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        for name in ["", "H4", "C", "C#", "Cx4", "128", "G#9", "Cb-1"] {
+            assert_eq!(parse(name), None, "{}", name);
+        }
+        assert_eq!(parse_in("C-2", MiddleC::C4), None);
+        assert_eq!(parse_in("C-2", MiddleC::C3), Some(0));
+    }
+}
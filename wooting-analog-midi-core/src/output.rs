@@ -0,0 +1,216 @@
+use crate::note::{MidiWrite, NoteSink};
+#[cfg(feature = "midi2")]
+use crate::ump::UmpSink;
+use crate::{Channel, NoteID};
+use anyhow::Result;
+#[cfg(feature = "midi2")]
+use log::warn;
+use midir::MidiOutputConnection;
+#[cfg(feature = "midi2")]
+use std::io::Write;
+
+/// Collects the messages of one poll so they can be flushed to the port in a single pass. The
+/// buffers keep their capacity between polls.
+#[derive(Debug, Default)]
+pub(crate) struct MidiBuffer {
+    bytes: Vec<u8>,
+    message_ends: Vec<usize>,
+}
+
+impl MidiBuffer {
+    /// Runs one poll with the buffer as its output, then sends everything it generated in one
+    /// pass. Whatever was generated is sent, even if the poll failed halfway.
+    pub(crate) fn batch<T>(
+        &mut self,
+        output: &mut impl MidiWrite,
+        poll: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let result = poll(self);
+        self.flush(output).and(result)
+    }
+
+    /// Sends all buffered messages in order, the buffer is emptied even if sending fails
+    fn flush(&mut self, output: &mut impl MidiWrite) -> Result<()> {
+        let mut start = 0;
+        let mut result = Ok(());
+        for &end in &self.message_ends {
+            if result.is_ok() {
+                result = output.write_message(&self.bytes[start..end]);
+            }
+            start = end;
+        }
+        self.bytes.clear();
+        self.message_ends.clear();
+        result
+    }
+}
+
+impl MidiWrite for MidiBuffer {
+    fn write_message(&mut self, message: &[u8]) -> Result<()> {
+        self.bytes.extend_from_slice(message);
+        self.message_ends.push(self.bytes.len());
+        Ok(())
+    }
+}
+
+/// Destination of everything the service sends
+pub(crate) enum Output {
+    Port(MidiOutputConnection),
+    #[cfg(feature = "midi2")]
+    Ump(UmpSink<Box<dyn Write + Send>>),
+}
+
+impl Output {
+    pub(crate) fn close(self) {
+        match self {
+            Output::Port(connection) => {
+                connection.close();
+            }
+            #[cfg(feature = "midi2")]
+            Output::Ump(sink) => {
+                if let Err(err) = sink.into_inner().flush() {
+                    warn!("Failed to flush UMP output: {err}");
+                }
+            }
+        }
+    }
+
+    fn sink(&mut self) -> &mut dyn NoteSink {
+        match self {
+            Output::Port(connection) => connection,
+            #[cfg(feature = "midi2")]
+            Output::Ump(sink) => sink,
+        }
+    }
+}
+
+impl NoteSink for Output {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.sink().note_on(note_id, velocity, channel)
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.sink()
+            .note_on_high_resolution(note_id, velocity, channel)
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.sink().note_off(note_id, velocity, channel)
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.sink()
+            .polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.sink().control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.sink().program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.sink().channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.sink().pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.sink().system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.sink().sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    /// Port that fails every send
+    struct ClosedPort;
+
+    impl MidiWrite for ClosedPort {
+        fn write_message(&mut self, _message: &[u8]) -> Result<()> {
+            bail!("Port closed")
+        }
+    }
+
+    #[test]
+    fn a_poll_is_sent_in_order_once_it_is_done() {
+        let mut buffer = MidiBuffer::default();
+        let mut port: Vec<Vec<u8>> = Vec::new();
+        buffer
+            .batch(&mut port, |buffer| {
+                // A retriggered note ends before it starts again
+                buffer.note_off(60, 0.5, 0)?;
+                buffer.note_on(60, 1.0, 0)?;
+                buffer.control_change(64, 127, 0)?;
+                assert_eq!(buffer.message_ends, [3, 6, 9]);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            port,
+            [vec![0x80, 60, 64], vec![0x90, 60, 127], vec![0xB0, 64, 127]]
+        );
+        assert!(buffer.bytes.is_empty() && buffer.message_ends.is_empty());
+    }
+
+    #[test]
+    fn every_poll_is_flushed_once() {
+        let mut buffer = MidiBuffer::default();
+        let mut port: Vec<Vec<u8>> = Vec::new();
+        for note_id in [60, 62] {
+            buffer
+                .batch(&mut port, |buffer| buffer.note_on(note_id, 1.0, 0))
+                .unwrap();
+        }
+        assert_eq!(port, [vec![0x90, 60, 127], vec![0x90, 62, 127]]);
+    }
+
+    #[test]
+    fn an_empty_poll_sends_nothing() {
+        let mut buffer = MidiBuffer::default();
+        // Any send would fail
+        assert_eq!(buffer.batch(&mut ClosedPort, |_| Ok(3)).unwrap(), 3);
+    }
+
+    #[test]
+    fn a_failed_poll_still_sends_what_it_generated() {
+        let mut buffer = MidiBuffer::default();
+        let mut port: Vec<Vec<u8>> = Vec::new();
+        let result = buffer.batch(&mut port, |buffer| -> Result<()> {
+            buffer.note_on(60, 1.0, 0)?;
+            bail!("No analog data")
+        });
+        assert!(result.is_err());
+        assert_eq!(port, [vec![0x90, 60, 127]]);
+    }
+
+    #[test]
+    fn a_failed_send_empties_the_buffer() {
+        let mut buffer = MidiBuffer::default();
+        let result = buffer.batch(&mut ClosedPort, |buffer| buffer.note_on(60, 1.0, 0));
+        assert!(result.is_err());
+        let mut port: Vec<Vec<u8>> = Vec::new();
+        buffer.batch(&mut port, |_| Ok(())).unwrap();
+        assert!(port.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::note::NoteSink;
+use crate::{Channel, NoteID};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+
+#[derive(Debug, Clone, Copy)]
+struct Contributor {
+    key: u16,
+    pressure: f32,
+}
+
+/// Keys holding each sounding (note, channel) pair, several keys can map to the same note
+#[derive(Debug, Default)]
+pub(crate) struct SharedNotes {
+    holders: FxHashMap<(NoteID, Channel), Vec<Contributor>>,
+}
+
+impl SharedNotes {
+    pub(crate) fn clear(&mut self) {
+        self.holders.clear();
+    }
+}
+
+/// Sends the messages of one key, starting a note only for its first holder and ending it only
+/// once its last holder lets go. Aftertouch of a shared note is the highest of its holders.
+pub(crate) struct SharedNoteSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    notes: &'a mut SharedNotes,
+    key: u16,
+}
+
+impl<'a, S: NoteSink> SharedNoteSink<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, notes: &'a mut SharedNotes, key: u16) -> Self {
+        Self { inner, notes, key }
+    }
+
+    /// Adds the key as a holder, `true` if nobody held the note before
+    fn hold(&mut self, note_id: NoteID, channel: Channel) -> bool {
+        let holders = self.notes.holders.entry((note_id, channel)).or_default();
+        let first = holders.is_empty();
+        if !holders.iter().any(|holder| holder.key == self.key) {
+            holders.push(Contributor {
+                key: self.key,
+                pressure: 0.0,
+            });
+        }
+        first
+    }
+}
+
+impl<S: NoteSink> NoteSink for SharedNoteSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        if !self.hold(note_id, channel) {
+            return Ok(());
+        }
+        self.inner.note_on(note_id, velocity, channel)
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        if !self.hold(note_id, channel) {
+            return Ok(());
+        }
+        self.inner
+            .note_on_high_resolution(note_id, velocity, channel)
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        if let Some(holders) = self.notes.holders.get_mut(&(note_id, channel)) {
+            holders.retain(|holder| holder.key != self.key);
+            if !holders.is_empty() {
+                return Ok(());
+            }
+            self.notes.holders.remove(&(note_id, channel));
+        }
+        self.inner.note_off(note_id, velocity, channel)
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        let Some(holders) = self.notes.holders.get_mut(&(note_id, channel)) else {
+            return self.inner.polyphonic_aftertouch(note_id, pressure, channel);
+        };
+        for holder in holders.iter_mut() {
+            if holder.key == self.key {
+                holder.pressure = pressure;
+            }
+        }
+        let pressure = holders
+            .iter()
+            .map(|holder| holder.pressure)
+            .fold(pressure, f32::max);
+        self.inner.polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.inner.sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_notes_start_once_and_end_with_the_last_holder() {
+        let mut notes = SharedNotes::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        SharedNoteSink::new(&mut output, &mut notes, 1)
+            .note_on(60, 1.0, 0)
+            .unwrap();
+        SharedNoteSink::new(&mut output, &mut notes, 2)
+            .note_on(60, 1.0, 0)
+            .unwrap();
+        assert_eq!(output, [vec![0x90, 60, 127]]);
+
+        SharedNoteSink::new(&mut output, &mut notes, 1)
+            .note_off(60, 0.0, 0)
+            .unwrap();
+        assert_eq!(output.len(), 1);
+        SharedNoteSink::new(&mut output, &mut notes, 2)
+            .note_off(60, 0.0, 0)
+            .unwrap();
+        assert_eq!(output[1], [0x80, 60, 0]);
+    }
+
+    #[test]
+    fn shared_notes_are_counted_per_channel() {
+        let mut notes = SharedNotes::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        SharedNoteSink::new(&mut output, &mut notes, 1)
+            .note_on(60, 1.0, 0)
+            .unwrap();
+        SharedNoteSink::new(&mut output, &mut notes, 2)
+            .note_on(60, 1.0, 1)
+            .unwrap();
+        assert_eq!(output, [vec![0x90, 60, 127], vec![0x91, 60, 127]]);
+    }
+
+    #[test]
+    fn shared_aftertouch_follows_the_strongest_holder() {
+        let mut notes = SharedNotes::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        for key in [1, 2] {
+            SharedNoteSink::new(&mut output, &mut notes, key)
+                .note_on(60, 1.0, 0)
+                .unwrap();
+        }
+        output.clear();
+        SharedNoteSink::new(&mut output, &mut notes, 1)
+            .polyphonic_aftertouch(60, 1.0, 0)
+            .unwrap();
+        SharedNoteSink::new(&mut output, &mut notes, 2)
+            .polyphonic_aftertouch(60, 0.5, 0)
+            .unwrap();
+        SharedNoteSink::new(&mut output, &mut notes, 1)
+            .note_off(60, 0.0, 0)
+            .unwrap();
+        SharedNoteSink::new(&mut output, &mut notes, 2)
+            .polyphonic_aftertouch(60, 0.5, 0)
+            .unwrap();
+        let pressures: Vec<u8> = output.iter().map(|message| message[2]).collect();
+        assert_eq!(pressures, [127, 127, 64]);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::config::Config;
+use crate::config_file::degree_list;
+use crate::note::NoteSink;
+use crate::{Channel, NoteID};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// Bend range receivers assume without being told otherwise
+const DEFAULT_BEND_RANGE: u8 = 2;
+const MPE_DEFAULT_BEND_RANGE: u8 = 48;
+
+/// Scale of a Scala .scl file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScalaScale {
+    pub description: String,
+    /// Cents above the base note of every degree after it, the last one is the period
+    pub pitches: Vec<f64>,
+}
+
+/// Keyboard mapping of a Scala .kbm file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyboardMapping {
+    pub first_note: NoteID,
+    pub last_note: NoteID,
+    /// Note playing the base note of the scale
+    pub middle_note: NoteID,
+    pub reference_note: NoteID,
+    pub reference_frequency: f64,
+    /// Scale degree the mapping repeats at, 0 uses the period of the scale
+    pub octave_degree: usize,
+    /// Scale degree of each key of the repeating pattern, `None` for silent keys. Empty maps the
+    /// keys to consecutive degrees.
+    #[serde(with = "degree_list")]
+    pub keys: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+            keys: vec![],
+        }
+    }
+}
+
+/// Maps notes to the pitches of a scale, played as the nearest note plus a pitch bend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tuning {
+    pub scale: ScalaScale,
+    pub mapping: KeyboardMapping,
+}
+
+impl Tuning {
+    pub fn new(scale: ScalaScale, mapping: Option<KeyboardMapping>) -> Result<Self> {
+        if scale.pitches.is_empty() {
+            bail!("Scale \"{}\" has no pitches", scale.description);
+        }
+        Ok(Self {
+            scale,
+            mapping: mapping.unwrap_or_default(),
+        })
+    }
+
+    /// Loads a .scl file and optionally a .kbm file
+    pub fn load(scale_path: &Path, mapping_path: Option<&Path>) -> Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))
+        };
+        let scale = parse_scl(&read(scale_path)?)?;
+        let mapping = mapping_path
+            .map(|path| parse_kbm(&read(path)?))
+            .transpose()?;
+        Self::new(scale, mapping)
+    }
+
+    /// Note to send and the remaining offset in cents, `None` for notes without a pitch
+    pub fn retune(&self, note: NoteID) -> Option<(NoteID, f64)> {
+        let target = self.target(note)?;
+        let nearest = target.round();
+        if !(0.0..=127.0).contains(&nearest) {
+            return None;
+        }
+        Some((nearest as NoteID, (target - nearest) * 100.0))
+    }
+
+    /// Pitch of a note as a fractional equal tempered note number
+    fn target(&self, note: NoteID) -> Option<f64> {
+        let mapping = &self.mapping;
+        if !(mapping.first_note..=mapping.last_note).contains(&note) {
+            return None;
+        }
+        let cents = self.cents(note)?;
+        let reference_cents = self.cents(mapping.reference_note)?;
+        let reference = 69.0 + 12.0 * (mapping.reference_frequency / 440.0).log2();
+        Some(reference + (cents - reference_cents) / 100.0)
+    }
+
+    /// Cents above the base note at `middle_note`
+    fn cents(&self, note: NoteID) -> Option<f64> {
+        let mapping = &self.mapping;
+        let offset = note as i64 - mapping.middle_note as i64;
+        let degree = if mapping.keys.is_empty() {
+            offset
+        } else {
+            let size = mapping.keys.len() as i64;
+            let octave_degree = match mapping.octave_degree {
+                0 => self.scale.pitches.len(),
+                degree => degree,
+            } as i64;
+            let degree = mapping.keys[offset.rem_euclid(size) as usize]? as i64;
+            offset.div_euclid(size) * octave_degree + degree
+        };
+
+        let len = self.scale.pitches.len() as i64;
+        let period = self.scale.pitches[self.scale.pitches.len() - 1];
+        let step = match degree.rem_euclid(len) {
+            0 => 0.0,
+            step => self.scale.pitches[step as usize - 1],
+        };
+        Some(degree.div_euclid(len) as f64 * period + step)
+    }
+}
+
+fn data_lines(source: &str) -> impl Iterator<Item = &str> {
+    source
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.starts_with('!'))
+}
+
+/// Parses the contents of a Scala .scl file
+pub fn parse_scl(source: &str) -> Result<ScalaScale> {
+    let mut lines = data_lines(source);
+    let description = lines.next().context("Scale is empty")?.trim().to_owned();
+    let count: usize = first_token(lines.next())
+        .parse()
+        .context("Invalid note count")?;
+    let pitches = lines
+        .take(count)
+        .map(|line| parse_pitch(first_token(Some(line))))
+        .collect::<Result<Vec<f64>>>()?;
+    if pitches.len() != count {
+        bail!("Scale lists {} of {} pitches", pitches.len(), count);
+    }
+    Ok(ScalaScale {
+        description,
+        pitches,
+    })
+}
+
+fn first_token(line: Option<&str>) -> &str {
+    line.and_then(|line| line.split_whitespace().next())
+        .unwrap_or("")
+}
+
+/// Cents contain a period, everything else is a ratio like 3/2 or a whole number like 2
+fn parse_pitch(token: &str) -> Result<f64> {
+    if token.contains('.') {
+        return token
+            .parse()
+            .with_context(|| format!("Invalid cents \"{}\"", token));
+    }
+    let (numerator, denominator) = token.split_once('/').unwrap_or((token, "1"));
+    let numerator: f64 = numerator
+        .parse()
+        .with_context(|| format!("Invalid ratio \"{}\"", token))?;
+    let denominator: f64 = denominator
+        .parse()
+        .with_context(|| format!("Invalid ratio \"{}\"", token))?;
+    if numerator <= 0.0 || denominator <= 0.0 {
+        bail!("Ratio \"{}\" is not positive", token);
+    }
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+/// Parses the contents of a Scala .kbm file
+pub fn parse_kbm(source: &str) -> Result<KeyboardMapping> {
+    let mut lines = data_lines(source).map(|line| first_token(Some(line)));
+    let mut field = |name: &str| -> Result<&str> {
+        lines
+            .next()
+            .with_context(|| format!("Keyboard mapping is missing the {}", name))
+    };
+    let size: usize = field("map size")?.parse().context("Invalid map size")?;
+    let first_note = field("first note")?.parse().context("Invalid first note")?;
+    let last_note = field("last note")?.parse().context("Invalid last note")?;
+    let middle_note = field("middle note")?
+        .parse()
+        .context("Invalid middle note")?;
+    let reference_note = field("reference note")?
+        .parse()
+        .context("Invalid reference note")?;
+    let reference_frequency: f64 = field("reference frequency")?
+        .parse()
+        .context("Invalid reference frequency")?;
+    let octave_degree = field("octave degree")?
+        .parse()
+        .context("Invalid octave degree")?;
+    let keys = (0..size)
+        .map(|_| match field("key mapping")? {
+            "x" | "X" => Ok(None),
+            degree => degree.parse().map(Some).context("Invalid key mapping"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !reference_frequency.is_finite() || reference_frequency <= 0.0 {
+        bail!(
+            "Reference frequency {} is not positive",
+            reference_frequency
+        );
+    }
+    Ok(KeyboardMapping {
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_frequency,
+        octave_degree,
+        keys,
+    })
+}
+
+/// Bend range the receiver is expected to use, in semitones
+pub(crate) fn bend_range(config: &Config) -> u8 {
+    match config.pitch_bend_range_semitones {
+        Some(range) => range,
+        None if config.mpe => MPE_DEFAULT_BEND_RANGE,
+        None => DEFAULT_BEND_RANGE,
+    }
+}
+
+/// Plays notes at their tuned pitch by bending the channel right before each note on. Bends of
+/// simultaneous notes only stay apart with MPE giving each note its own channel.
+pub(crate) struct TuningSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    tuning: Option<&'a Tuning>,
+    bend_range: f32,
+}
+
+impl<'a, S: NoteSink> TuningSink<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, config: &'a Config) -> Self {
+        Self {
+            inner,
+            tuning: config.tuning.as_ref(),
+            bend_range: bend_range(config) as f32,
+        }
+    }
+
+    /// Bends the channel for a starting note, `None` if the note has no pitch
+    fn bend_for(&mut self, note_id: NoteID, channel: Channel) -> Result<Option<NoteID>> {
+        let Some(tuning) = self.tuning else {
+            return Ok(Some(note_id));
+        };
+        let Some((note_id, cents)) = tuning.retune(note_id) else {
+            return Ok(None);
+        };
+        // Offsets beyond the range were reported when the config was applied
+        let bend = (cents as f32 / 100.0 / self.bend_range).clamp(-1.0, 1.0);
+        self.inner.pitch_bend(bend, channel)?;
+        Ok(Some(note_id))
+    }
+
+    fn mapped(&self, note_id: NoteID) -> Option<NoteID> {
+        match self.tuning {
+            Some(tuning) => tuning.retune(note_id).map(|(note_id, _)| note_id),
+            None => Some(note_id),
+        }
+    }
+}
+
+impl<S: NoteSink> NoteSink for TuningSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        match self.bend_for(note_id, channel)? {
+            Some(note_id) => self.inner.note_on(note_id, velocity, channel),
+            None => Ok(()),
+        }
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        match self.bend_for(note_id, channel)? {
+            Some(note_id) => self
+                .inner
+                .note_on_high_resolution(note_id, velocity, channel),
+            None => Ok(()),
+        }
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        match self.mapped(note_id) {
+            Some(note_id) => self.inner.note_off(note_id, velocity, channel),
+            None => Ok(()),
+        }
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        match self.mapped(note_id) {
+            Some(note_id) => self.inner.polyphonic_aftertouch(note_id, pressure, channel),
+            None => Ok(()),
+        }
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.inner.sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scale of two degrees 130 cents apart with a period of 260 cents, based on note 60
+    fn tuning() -> Tuning {
+        let scale = parse_scl("! wide.scl\nWide steps\n 2\n!\n130.0\n260.0 period\n").unwrap();
+        let mapping = KeyboardMapping {
+            reference_note: 60,
+            reference_frequency: 440.0 * 2f64.powf(-9.0 / 12.0),
+            ..KeyboardMapping::default()
+        };
+        Tuning::new(scale, Some(mapping)).unwrap()
+    }
+
+    fn assert_retune(tuning: &Tuning, note: NoteID, expected: (NoteID, f64)) {
+        let (note_id, cents) = tuning.retune(note).unwrap();
+        assert_eq!(note_id, expected.0);
+        assert!((cents - expected.1).abs() < 1e-6, "{} cents", cents);
+    }
+
+    #[test]
+    fn scl_pitches_accept_cents_ratios_and_integers() {
+        let scale = parse_scl("Mixed\n3\n100.0\n3/2 fifth\n2\n").unwrap();
+        assert_eq!(scale.description, "Mixed");
+        assert_eq!(scale.pitches.len(), 3);
+        assert_eq!(scale.pitches[0], 100.0);
+        assert!((scale.pitches[1] - 701.955).abs() < 1e-3);
+        assert!((scale.pitches[2] - 1200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn scl_with_missing_pitches_is_rejected() {
+        assert!(parse_scl("Short\n3\n100.0\n200.0\n").is_err());
+        assert!(parse_scl("Negative\n1\n-3/2\n").is_err());
+    }
+
+    #[test]
+    fn notes_are_retuned_to_the_nearest_note_and_an_offset() {
+        let tuning = tuning();
+        assert_retune(&tuning, 60, (60, 0.0));
+        assert_retune(&tuning, 61, (61, 30.0));
+        assert_retune(&tuning, 62, (63, -40.0));
+        assert_retune(&tuning, 59, (59, -30.0));
+    }
+
+    #[test]
+    fn kbm_patterns_repeat_and_skip_silent_keys() {
+        let source = "! mapping\n3\n0\n127\n60\n60\n261.6255653\n0\n! keys\n0\nx\n1\n";
+        let mapping = parse_kbm(source).unwrap();
+        assert_eq!(mapping.keys, [Some(0), None, Some(1)]);
+        let tuning = Tuning::new(tuning().scale, Some(mapping)).unwrap();
+        assert_retune(&tuning, 60, (60, 0.0));
+        assert_eq!(tuning.retune(61), None);
+        assert_retune(&tuning, 62, (61, 30.0));
+        assert_retune(&tuning, 63, (63, -40.0));
+    }
+
+    #[test]
+    fn notes_outside_the_mapping_have_no_pitch() {
+        let mut tuning = tuning();
+        tuning.mapping.first_note = 50;
+        tuning.mapping.last_note = 70;
+        assert_eq!(tuning.retune(49), None);
+        assert_eq!(tuning.retune(71), None);
+        assert!(tuning.retune(70).is_some());
+    }
+
+    #[test]
+    fn notes_are_bent_before_they_start() {
+        let config = Config {
+            tuning: Some(tuning()),
+            ..Config::default()
+        };
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = TuningSink::new(&mut output, &config);
+        sink.note_on(62, 1.0, 0).unwrap();
+        sink.note_off(62, 0.0, 0).unwrap();
+        // -40 cents of a 2 semitone range
+        let bend = crate::note::pitch_bend_value(-0.2);
+        assert_eq!(
+            output,
+            [
+                vec![0xE0, (bend & 0x7F) as u8, (bend >> 7) as u8],
+                vec![0x90, 63, 127],
+                vec![0x80, 63, 0],
+            ]
+        );
+    }
+}
@@ -0,0 +1,660 @@
+use crate::note::{pitch_bend_value, NoteSink};
+use crate::{Channel, NoteID};
+use anyhow::Result;
+use std::io::Write;
+
+const MT_SYSTEM: u32 = 0x1;
+const MT_MIDI1_CHANNEL_VOICE: u32 = 0x2;
+const MT_DATA_64: u32 = 0x3;
+const MT_MIDI2_CHANNEL_VOICE: u32 = 0x4;
+
+const REGISTERED_PER_NOTE_CONTROLLER: u32 = 0x0;
+const PER_NOTE_PITCH_BEND: u32 = 0x6;
+const NOTE_OFF: u32 = 0x8;
+const NOTE_ON: u32 = 0x9;
+const POLY_PRESSURE: u32 = 0xA;
+const CONTROL_CHANGE: u32 = 0xB;
+const PROGRAM_CHANGE: u32 = 0xC;
+const CHANNEL_PRESSURE: u32 = 0xD;
+const PITCH_BEND: u32 = 0xE;
+
+const SYSEX7_COMPLETE: u32 = 0x0;
+const SYSEX7_START: u32 = 0x1;
+const SYSEX7_CONTINUE: u32 = 0x2;
+const SYSEX7_END: u32 = 0x3;
+const SYSEX7_BYTES_PER_PACKET: usize = 6;
+
+pub const PER_NOTE_PITCH_CENTER: u32 = 0x8000_0000;
+
+/// Protocol negotiated with the receiving endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Midi1,
+    Midi2,
+}
+
+/// A MIDI 2.0 channel voice message with full resolution values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelVoice {
+    NoteOff { note: NoteID, velocity: u16 },
+    NoteOn { note: NoteID, velocity: u16 },
+    PolyPressure { note: NoteID, pressure: u32 },
+    RegisteredPerNoteController { note: NoteID, index: u8, value: u32 },
+    PerNotePitchBend { note: NoteID, value: u32 },
+    ControlChange { index: u8, value: u32 },
+    ProgramChange { program: u8 },
+    ChannelPressure { pressure: u32 },
+    PitchBend { value: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet {
+    Midi1([u32; 1]),
+    Midi2([u32; 2]),
+}
+
+impl Packet {
+    pub fn words(&self) -> &[u32] {
+        match self {
+            Packet::Midi1(words) => words,
+            Packet::Midi2(words) => words,
+        }
+    }
+}
+
+/// Encodes a message as a 64-bit MIDI 2.0 channel voice packet.
+pub fn encode_midi2(group: u8, channel: Channel, message: &ChannelVoice) -> [u32; 2] {
+    let header = |status: u32, byte3: u8, byte4: u8| {
+        MT_MIDI2_CHANNEL_VOICE << 28
+            | (group as u32 & 0xF) << 24
+            | status << 20
+            | (channel as u32 & 0xF) << 16
+            | (byte3 as u32 & 0x7F) << 8
+            | byte4 as u32
+    };
+    match *message {
+        ChannelVoice::NoteOff { note, velocity } => {
+            [header(NOTE_OFF, note, 0), (velocity as u32) << 16]
+        }
+        ChannelVoice::NoteOn { note, velocity } => {
+            [header(NOTE_ON, note, 0), (velocity as u32) << 16]
+        }
+        ChannelVoice::PolyPressure { note, pressure } => [header(POLY_PRESSURE, note, 0), pressure],
+        ChannelVoice::RegisteredPerNoteController { note, index, value } => {
+            [header(REGISTERED_PER_NOTE_CONTROLLER, note, index), value]
+        }
+        ChannelVoice::PerNotePitchBend { note, value } => {
+            [header(PER_NOTE_PITCH_BEND, note, 0), value]
+        }
+        ChannelVoice::ControlChange { index, value } => [header(CONTROL_CHANGE, index, 0), value],
+        ChannelVoice::ProgramChange { program } => {
+            [header(PROGRAM_CHANGE, 0, 0), (program as u32 & 0x7F) << 24]
+        }
+        ChannelVoice::ChannelPressure { pressure } => [header(CHANNEL_PRESSURE, 0, 0), pressure],
+        ChannelVoice::PitchBend { value } => [header(PITCH_BEND, 0, 0), value],
+    }
+}
+
+/// Translates a message to MIDI 1.0 bytes, returns None if MIDI 1.0 has no equivalent.
+pub fn translate_to_midi1(channel: Channel, message: &ChannelVoice) -> Option<[u8; 3]> {
+    let channel = channel & 0xF;
+    match *message {
+        ChannelVoice::NoteOff { note, velocity } => Some([
+            0x80 | channel,
+            note & 0x7F,
+            downscale(velocity as u32, 16, 7),
+        ]),
+        // A note on with velocity 0 would be read as note off by MIDI 1.0 receivers
+        ChannelVoice::NoteOn { note, velocity } => Some([
+            0x90 | channel,
+            note & 0x7F,
+            downscale(velocity as u32, 16, 7).max(1),
+        ]),
+        ChannelVoice::PolyPressure { note, pressure } => {
+            Some([0xA0 | channel, note & 0x7F, downscale(pressure, 32, 7)])
+        }
+        ChannelVoice::ControlChange { index, value } => {
+            Some([0xB0 | channel, index & 0x7F, downscale(value, 32, 7)])
+        }
+        ChannelVoice::ProgramChange { program } => Some([0xC0 | channel, program & 0x7F, 0]),
+        ChannelVoice::ChannelPressure { pressure } => {
+            Some([0xD0 | channel, downscale(pressure, 32, 7), 0])
+        }
+        ChannelVoice::PitchBend { value } => {
+            let value = value >> 18;
+            Some([0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8])
+        }
+        ChannelVoice::RegisteredPerNoteController { .. }
+        | ChannelVoice::PerNotePitchBend { .. } => None,
+    }
+}
+
+/// Wraps MIDI 1.0 channel voice bytes into a 32-bit packet.
+pub fn encode_midi1(group: u8, bytes: [u8; 3]) -> [u32; 1] {
+    [MT_MIDI1_CHANNEL_VOICE << 28
+        | (group as u32 & 0xF) << 24
+        | (bytes[0] as u32) << 16
+        | (bytes[1] as u32) << 8
+        | bytes[2] as u32]
+}
+
+/// Wraps a system real time status byte into a 32-bit packet.
+pub fn encode_system(group: u8, status: u8) -> [u32; 1] {
+    [MT_SYSTEM << 28 | (group as u32 & 0xF) << 24 | (status as u32) << 16]
+}
+
+/// Splits the payload of a SysEx message, without its 0xF0 and 0xF7 framing, into 64-bit
+/// SysEx7 packets.
+pub fn encode_sysex7(group: u8, payload: &[u8]) -> Vec<[u32; 2]> {
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![&[]]
+    } else {
+        payload.chunks(SYSEX7_BYTES_PER_PACKET).collect()
+    };
+    let last = chunks.len() - 1;
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let status = match (index, last) {
+                (0, 0) => SYSEX7_COMPLETE,
+                (0, _) => SYSEX7_START,
+                (index, last) if index == last => SYSEX7_END,
+                _ => SYSEX7_CONTINUE,
+            };
+            let mut bytes = [0u8; SYSEX7_BYTES_PER_PACKET];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            [
+                MT_DATA_64 << 28
+                    | (group as u32 & 0xF) << 24
+                    | status << 20
+                    | (chunk.len() as u32) << 16
+                    | (bytes[0] as u32 & 0x7F) << 8
+                    | bytes[1] as u32 & 0x7F,
+                u32::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]) & 0x7F7F_7F7F,
+            ]
+        })
+        .collect()
+}
+
+pub fn encode(
+    protocol: Protocol,
+    group: u8,
+    channel: Channel,
+    message: &ChannelVoice,
+) -> Option<Packet> {
+    match protocol {
+        Protocol::Midi2 => Some(Packet::Midi2(encode_midi2(group, channel, message))),
+        Protocol::Midi1 => translate_to_midi1(channel, message)
+            .map(|bytes| Packet::Midi1(encode_midi1(group, bytes))),
+    }
+}
+
+fn downscale(value: u32, source_bits: u32, target_bits: u32) -> u8 {
+    (value >> (source_bits - target_bits)) as u8
+}
+
+/// Min-center-max upscaling as described by the MIDI 2.0 translation rules
+pub fn upscale(value: u32, source_bits: u32, target_bits: u32) -> u32 {
+    let scale_bits = target_bits - source_bits;
+    let shifted = value << scale_bits;
+    if value <= 1 << (source_bits - 1) {
+        return shifted;
+    }
+    let repeat_bits = source_bits - 1;
+    let mut repeat = value & ((1 << repeat_bits) - 1);
+    repeat = if scale_bits > repeat_bits {
+        repeat << (scale_bits - repeat_bits)
+    } else {
+        repeat >> (repeat_bits - scale_bits)
+    };
+    let mut result = shifted;
+    while repeat != 0 {
+        result |= repeat;
+        repeat >>= repeat_bits;
+    }
+    result
+}
+
+fn to_u16(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * u16::MAX as f32) as u16
+}
+
+fn to_u32(value: f32) -> u32 {
+    (value.clamp(0.0, 1.0) as f64 * u32::MAX as f64) as u32
+}
+
+/// Writes Universal MIDI Packets as big endian words to any writer (file, FIFO, ...).
+pub struct UmpSink<W: Write> {
+    writer: W,
+    protocol: Protocol,
+    group: u8,
+    pressure_controller: Option<u8>,
+}
+
+impl<W: Write> UmpSink<W> {
+    pub fn new(writer: W, protocol: Protocol) -> Self {
+        Self {
+            writer,
+            protocol,
+            group: 0,
+            pressure_controller: None,
+        }
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn set_group(&mut self, group: u8) {
+        self.group = group;
+    }
+
+    /// Sends pressure as the given registered per-note controller instead of poly pressure.
+    pub fn set_pressure_controller(&mut self, index: Option<u8>) {
+        self.pressure_controller = index;
+    }
+
+    pub fn send(&mut self, channel: Channel, message: &ChannelVoice) -> Result<()> {
+        if let Some(packet) = encode(self.protocol, self.group, channel, message) {
+            for word in packet.words() {
+                self.writer.write_all(&word.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> NoteSink for UmpSink<W> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let velocity = to_u16(velocity);
+        self.send(
+            channel,
+            &ChannelVoice::NoteOn {
+                note: note_id,
+                velocity,
+            },
+        )
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let velocity = to_u16(velocity);
+        self.send(
+            channel,
+            &ChannelVoice::NoteOff {
+                note: note_id,
+                velocity,
+            },
+        )
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        let value = to_u32(pressure);
+        let message = match self.pressure_controller {
+            // Per-note controllers have no MIDI 1.0 equivalent, fall back to poly pressure
+            Some(index) if self.protocol == Protocol::Midi2 => {
+                ChannelVoice::RegisteredPerNoteController {
+                    note: note_id,
+                    index,
+                    value,
+                }
+            }
+            _ => ChannelVoice::PolyPressure {
+                note: note_id,
+                pressure: value,
+            },
+        };
+        self.send(channel, &message)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        let value = upscale(value as u32 & 0x7F, 7, 32);
+        self.send(
+            channel,
+            &ChannelVoice::ControlChange {
+                index: controller,
+                value,
+            },
+        )
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.send(channel, &ChannelVoice::ProgramChange { program })
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        let pressure = to_u32(pressure);
+        self.send(channel, &ChannelVoice::ChannelPressure { pressure })
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        let value = upscale(pitch_bend_value(value) as u32, 14, 32);
+        self.send(channel, &ChannelVoice::PitchBend { value })
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        for word in encode_system(self.group, status) {
+            self.writer.write_all(&word.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        let payload = message
+            .strip_prefix(&[0xF0])
+            .and_then(|message| message.strip_suffix(&[0xF7]))
+            .unwrap_or(message);
+        for packet in encode_sysex7(self.group, payload) {
+            for word in packet {
+                self.writer.write_all(&word.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn midi2_channel_voice_packets() {
+        let cases = [
+            (
+                0,
+                0,
+                ChannelVoice::NoteOn {
+                    note: 0x3C,
+                    velocity: 0xC104,
+                },
+                [0x4090_3C00, 0xC104_0000],
+            ),
+            (
+                5,
+                3,
+                ChannelVoice::NoteOff {
+                    note: 0x40,
+                    velocity: 0x1234,
+                },
+                [0x4583_4000, 0x1234_0000],
+            ),
+            (
+                0,
+                1,
+                ChannelVoice::PolyPressure {
+                    note: 0x3C,
+                    pressure: 0x8000_0000,
+                },
+                [0x40A1_3C00, 0x8000_0000],
+            ),
+            (
+                0,
+                0,
+                ChannelVoice::RegisteredPerNoteController {
+                    note: 0x3C,
+                    index: 3,
+                    value: 0xDEAD_BEEF,
+                },
+                [0x4000_3C03, 0xDEAD_BEEF],
+            ),
+            (
+                0,
+                2,
+                ChannelVoice::PerNotePitchBend {
+                    note: 0x3C,
+                    value: PER_NOTE_PITCH_CENTER,
+                },
+                [0x4062_3C00, 0x8000_0000],
+            ),
+            (
+                0,
+                15,
+                ChannelVoice::ControlChange {
+                    index: 7,
+                    value: 0xFFFF_FFFF,
+                },
+                [0x40BF_0700, 0xFFFF_FFFF],
+            ),
+            (
+                0,
+                0,
+                ChannelVoice::ProgramChange { program: 5 },
+                [0x40C0_0000, 0x0500_0000],
+            ),
+            (
+                0,
+                0,
+                ChannelVoice::ChannelPressure {
+                    pressure: 0x1234_5678,
+                },
+                [0x40D0_0000, 0x1234_5678],
+            ),
+            (
+                15,
+                0,
+                ChannelVoice::PitchBend { value: 0x8000_0000 },
+                [0x4FE0_0000, 0x8000_0000],
+            ),
+        ];
+        for (group, channel, message, words) in cases {
+            assert_eq!(
+                encode_midi2(group, channel, &message),
+                words,
+                "{:?}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn midi2_fields_are_masked() {
+        let message = ChannelVoice::NoteOn {
+            note: 0xBC,
+            velocity: 0xFFFF,
+        };
+        assert_eq!(
+            encode_midi2(0x12, 0x13, &message),
+            [0x4293_3C00, 0xFFFF_0000]
+        );
+    }
+
+    #[test]
+    fn translation_to_midi1() {
+        let cases = [
+            (
+                0,
+                ChannelVoice::NoteOn {
+                    note: 0x3C,
+                    velocity: 0xC104,
+                },
+                Some([0x90, 0x3C, 0x60]),
+            ),
+            // Velocities that scale down to 0 still start the note
+            (
+                0,
+                ChannelVoice::NoteOn {
+                    note: 0x3C,
+                    velocity: 0x01FF,
+                },
+                Some([0x90, 0x3C, 0x01]),
+            ),
+            (
+                3,
+                ChannelVoice::NoteOff {
+                    note: 0x40,
+                    velocity: 0xFFFF,
+                },
+                Some([0x83, 0x40, 0x7F]),
+            ),
+            (
+                1,
+                ChannelVoice::PolyPressure {
+                    note: 0x3C,
+                    pressure: 0x8000_0000,
+                },
+                Some([0xA1, 0x3C, 0x40]),
+            ),
+            (
+                15,
+                ChannelVoice::ControlChange {
+                    index: 7,
+                    value: 0xFFFF_FFFF,
+                },
+                Some([0xBF, 0x07, 0x7F]),
+            ),
+            (
+                0,
+                ChannelVoice::ProgramChange { program: 5 },
+                Some([0xC0, 0x05, 0x00]),
+            ),
+            (
+                0,
+                ChannelVoice::ChannelPressure {
+                    pressure: 0x1234_5678,
+                },
+                Some([0xD0, 0x09, 0x00]),
+            ),
+            (
+                0,
+                ChannelVoice::PitchBend { value: 0x8000_0000 },
+                Some([0xE0, 0x00, 0x40]),
+            ),
+            (
+                0,
+                ChannelVoice::PitchBend { value: 0xFFFF_FFFF },
+                Some([0xE0, 0x7F, 0x7F]),
+            ),
+            (
+                0,
+                ChannelVoice::RegisteredPerNoteController {
+                    note: 0x3C,
+                    index: 3,
+                    value: 0,
+                },
+                None,
+            ),
+            (
+                0,
+                ChannelVoice::PerNotePitchBend {
+                    note: 0x3C,
+                    value: PER_NOTE_PITCH_CENTER,
+                },
+                None,
+            ),
+        ];
+        for (channel, message, bytes) in cases {
+            assert_eq!(
+                translate_to_midi1(channel, &message),
+                bytes,
+                "{:?}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn midi1_and_system_packets() {
+        assert_eq!(encode_midi1(0, [0x90, 0x3C, 0x60]), [0x2090_3C60]);
+        assert_eq!(encode_midi1(3, [0xE5, 0x00, 0x40]), [0x23E5_0040]);
+        assert_eq!(encode_system(0, 0xF8), [0x10F8_0000]);
+        assert_eq!(encode_system(1, 0xFC), [0x11FC_0000]);
+    }
+
+    #[test]
+    fn sysex7_packets() {
+        let payload: Vec<u8> = (1..=13).collect();
+        // Complete
+        assert_eq!(encode_sysex7(0, &[]), [[0x3000_0000, 0x0000_0000]]);
+        assert_eq!(
+            encode_sysex7(0, &payload[..3]),
+            [[0x3003_0102, 0x0300_0000]]
+        );
+        assert_eq!(
+            encode_sysex7(2, &payload[..6]),
+            [[0x3206_0102, 0x0304_0506]]
+        );
+        // Start and end
+        assert_eq!(
+            encode_sysex7(0, &payload[..7]),
+            [[0x3016_0102, 0x0304_0506], [0x3031_0700, 0x0000_0000]]
+        );
+        assert_eq!(
+            encode_sysex7(0, &payload[..12]),
+            [[0x3016_0102, 0x0304_0506], [0x3036_0708, 0x090A_0B0C]]
+        );
+        // Start, continue and end
+        assert_eq!(
+            encode_sysex7(0, &payload),
+            [
+                [0x3016_0102, 0x0304_0506],
+                [0x3026_0708, 0x090A_0B0C],
+                [0x3031_0D00, 0x0000_0000],
+            ]
+        );
+        // Data bytes are 7-bit
+        assert_eq!(
+            encode_sysex7(0, &[0xFF, 0x80]),
+            [[0x3002_7F00, 0x0000_0000]]
+        );
+    }
+
+    #[test]
+    fn min_center_max_upscaling() {
+        let cases = [
+            (0x00, 7, 16, 0x0000),
+            (0x40, 7, 16, 0x8000),
+            (0x41, 7, 16, 0x8208),
+            (0x7F, 7, 16, 0xFFFF),
+            (0x00, 7, 32, 0x0000_0000),
+            (0x40, 7, 32, 0x8000_0000),
+            (0x7F, 7, 32, 0xFFFF_FFFF),
+            (0x0001, 14, 32, 0x0004_0000),
+            (0x2000, 14, 32, 0x8000_0000),
+            (0x3FFF, 14, 32, 0xFFFF_FFFF),
+        ];
+        for (value, source_bits, target_bits, expected) in cases {
+            assert_eq!(
+                upscale(value, source_bits, target_bits),
+                expected,
+                "{:#x} from {} to {} bits",
+                value,
+                source_bits,
+                target_bits
+            );
+        }
+    }
+
+    #[test]
+    fn sink_falls_back_to_midi1() {
+        let mut sink = UmpSink::new(Vec::new(), Protocol::Midi2);
+        sink.set_pressure_controller(Some(1));
+        sink.note_on(0x3C, 1.0, 0).unwrap();
+        sink.polyphonic_aftertouch(0x3C, 0.0, 0).unwrap();
+        sink.set_protocol(Protocol::Midi1);
+        sink.note_on(0x3C, 1.0, 0).unwrap();
+        sink.polyphonic_aftertouch(0x3C, 1.0, 0).unwrap();
+        sink.sysex(&[0xF0, 0x7E, 0xF7]).unwrap();
+        assert_eq!(
+            sink.into_inner(),
+            [
+                [0x40, 0x90, 0x3C, 0x00, 0xFF, 0xFF, 0x00, 0x00],
+                [0x40, 0x00, 0x3C, 0x01, 0x00, 0x00, 0x00, 0x00],
+            ]
+            .concat()
+            .into_iter()
+            .chain([0x20, 0x90, 0x3C, 0x7F])
+            .chain([0x20, 0xA0, 0x3C, 0x7F])
+            .chain([0x30, 0x01, 0x7E, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .collect::<Vec<u8>>()
+        );
+    }
+}
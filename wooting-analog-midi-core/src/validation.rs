@@ -0,0 +1,1110 @@
+use crate::config::{
+    ChannelSetup, Config, InputSmoothing, KeyConfig, ShiftOutOfRange, VelocityCurve,
+    VelocityEstimation,
+};
+use crate::keynames::{self, NamingScheme};
+use crate::note::{CC_LSB_OFFSET, MIDI_NOTE_MAX, MIDI_NOTE_MIN};
+use crate::{Channel, NoteID, TRANSPOSE_MAX};
+use anyhow::{bail, Result};
+use rustc_hash::FxHashMap;
+use std::fmt;
+use wooting_analog_wrapper::{HIDCodes, ToPrimitive};
+
+const CHANNEL_MAX: Channel = 15;
+const NOTE_MAX: NoteID = 127;
+
+/// A problem found by `Config::validate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// Key whose mapping is at fault, `None` for settings of the whole config
+    pub key: Option<HIDCodes>,
+    /// Path of the offending field as written in a config file, e.g. "key_configs.threshold"
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(
+                f,
+                "{} of {}: {}",
+                self.field,
+                keynames::name_of(key, NamingScheme::default()),
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.field, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Default)]
+struct Problems(Vec<ConfigError>);
+
+impl Problems {
+    fn config(&mut self, field: &str, message: String) {
+        self.0.push(ConfigError {
+            key: None,
+            field: field.to_owned(),
+            message,
+        });
+    }
+
+    fn key(&mut self, key: &HIDCodes, field: &str, message: String) {
+        self.0.push(ConfigError {
+            key: Some(key.clone()),
+            field: field.to_owned(),
+            message,
+        });
+    }
+}
+
+impl Config {
+    /// Checks for values that cannot work, reporting every problem at once
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut problems = Problems::default();
+        validate_channels(self, &mut problems);
+        validate_channel_setup(&self.channel_setup, &mut problems);
+        validate_key_configs(self, &mut problems);
+        validate_key_roles(self, &mut problems);
+        validate_sysex("on_enable_sysex", &self.on_enable_sysex, &mut problems);
+        validate_sysex("on_disable_sysex", &self.on_disable_sysex, &mut problems);
+        validate_features(self, &mut problems);
+        if problems.0.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.0)
+        }
+    }
+}
+
+/// `Config::validate` as an error listing every problem
+pub(crate) fn ensure_valid(config: &Config) -> Result<()> {
+    if let Err(errors) = config.validate() {
+        let problems: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        bail!("Invalid config:\n{}", problems.join("\n"));
+    }
+    Ok(())
+}
+
+/// Entries in HID code order, so problems are reported in the same order every time
+fn by_key<T>(map: &FxHashMap<HIDCodes, T>) -> Vec<(&HIDCodes, &T)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(hid_code, _)| hid_code.to_u16());
+    entries
+}
+
+fn by_channel<T>(map: &FxHashMap<Channel, T>) -> Vec<(&Channel, &T)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|&(&channel, _)| channel);
+    entries
+}
+
+fn out_of_range(channel: Channel) -> String {
+    format!(
+        "Channel {} is out of range, channels are zero-based from 0 to 15",
+        channel
+    )
+}
+
+fn validate_channels(config: &Config, problems: &mut Problems) {
+    for (hid_code, cc) in by_key(&config.cc_mappings) {
+        if cc.channel > CHANNEL_MAX {
+            problems.key(hid_code, "cc_mappings.channel", out_of_range(cc.channel));
+        }
+    }
+    for (hid_code, bend) in by_key(&config.pitch_bend_keys) {
+        if bend.channel > CHANNEL_MAX {
+            problems.key(
+                hid_code,
+                "pitch_bend_keys.channel",
+                out_of_range(bend.channel),
+            );
+        }
+    }
+    for (hid_code, program) in by_key(&config.program_change_keys) {
+        if program.channel > CHANNEL_MAX {
+            problems.key(
+                hid_code,
+                "program_change_keys.channel",
+                out_of_range(program.channel),
+            );
+        }
+    }
+    for (&channel, _) in by_channel(&config.mono_channels) {
+        if channel > CHANNEL_MAX {
+            problems.config("mono_channels", out_of_range(channel));
+        }
+    }
+    for (&channel, polyphony) in by_channel(&config.polyphony) {
+        if channel > CHANNEL_MAX {
+            problems.config("polyphony", out_of_range(channel));
+        }
+        if polyphony.max_voices == 0 {
+            problems.config(
+                "polyphony.max_voices",
+                format!("Polyphony of channel {} must be at least 1", channel),
+            );
+        }
+    }
+    if let Some(channel) = config
+        .default_channel
+        .filter(|&channel| channel > CHANNEL_MAX)
+    {
+        problems.config("default_channel", out_of_range(channel));
+    }
+    if let Some(sustain) = &config.sustain {
+        if sustain.channel > CHANNEL_MAX {
+            problems.config("sustain.channel", out_of_range(sustain.channel));
+        }
+    }
+}
+
+fn validate_channel_setup(
+    channel_setup: &FxHashMap<Channel, ChannelSetup>,
+    problems: &mut Problems,
+) {
+    for (&channel, setup) in by_channel(channel_setup) {
+        if channel > CHANNEL_MAX {
+            problems.config("channel_setup", out_of_range(channel));
+        }
+        let values = [setup.bank_msb, setup.bank_lsb, setup.program]
+            .into_iter()
+            .flatten()
+            .chain(
+                setup
+                    .cc_defaults
+                    .iter()
+                    .flat_map(|&(cc, value)| [cc, value]),
+            );
+        for value in values {
+            if value > 127 {
+                problems.config(
+                    "channel_setup",
+                    format!(
+                        "Channel setup for channel {} contains {} which exceeds 127",
+                        channel, value
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn validate_key_configs(config: &Config, problems: &mut Problems) {
+    match &config.default_preset {
+        Some(name) if !config.presets.contains_key(name) => problems.config(
+            "default_preset",
+            format!("There is no preset named \"{}\"", name),
+        ),
+        Some(_) => {}
+        None if config.key_configs.is_empty() => {
+            problems.config("key_configs", "No key plays a note".to_owned())
+        }
+        None => {}
+    }
+    for (hid_code, key_config) in by_key(&config.key_configs) {
+        validate_key_config("key_configs", hid_code, key_config, problems);
+    }
+    for (name, preset) in &config.presets {
+        let section = format!("preset.{}.key_configs", name);
+        for (hid_code, key_config) in by_key(&preset.key_configs) {
+            validate_key_config(&section, hid_code, key_config, problems);
+        }
+    }
+
+    if config.transpose.unsigned_abs() > TRANSPOSE_MAX as u8 {
+        problems.config(
+            "transpose",
+            format!(
+                "Transpose of {} is beyond the limit of {} semitones",
+                config.transpose, TRANSPOSE_MAX
+            ),
+        );
+    }
+
+    // A shift that moves every note out of range makes the modifier keys play nothing
+    if config.shift_out_of_range == ShiftOutOfRange::Drop {
+        let mut shifted = config
+            .key_configs
+            .values()
+            .filter(|key_config| key_config.shift_amount != 0)
+            .peekable();
+        let range = MIDI_NOTE_MIN as i16..=MIDI_NOTE_MAX as i16;
+        if shifted.peek().is_some()
+            && shifted.all(|key_config| {
+                let shifted = key_config.note_id as i16
+                    + key_config.shift_amount as i16
+                    + config.transpose as i16;
+                !range.contains(&shifted)
+            })
+        {
+            problems.config(
+                "key_configs.shift_amount",
+                format!(
+                    "Shifting moves every note outside of {} to {}, set shift_out_of_range to \
+                     Fold or Clamp to keep them",
+                    MIDI_NOTE_MIN, MIDI_NOTE_MAX
+                ),
+            );
+        }
+    }
+}
+
+fn validate_key_config(
+    section: &str,
+    hid_code: &HIDCodes,
+    key_config: &KeyConfig,
+    problems: &mut Problems,
+) {
+    let mut problem = |field: &str, message: String| {
+        problems.key(hid_code, &format!("{}.{}", section, field), message);
+    };
+
+    if key_config.channel > CHANNEL_MAX {
+        problem("channel", out_of_range(key_config.channel));
+    }
+
+    let notes = [("note_id", Some(key_config.note_id))]
+        .into_iter()
+        .chain([("second_note", key_config.second_note.map(|(note, _)| note))])
+        .chain([(
+            "soft_hold.note",
+            key_config
+                .soft_hold
+                .as_ref()
+                .map(|soft_hold| soft_hold.note),
+        )])
+        .chain(
+            key_config
+                .note_pool
+                .iter()
+                .flat_map(|pool| &pool.notes)
+                .map(|&note| ("note_pool.notes", Some(note))),
+        );
+    for (field, note) in notes {
+        if let Some(note) = note.filter(|&note| note > NOTE_MAX) {
+            problem(
+                field,
+                format!("Note {} is above the highest MIDI note 127", note),
+            );
+        }
+    }
+    let depth = |value: f32| (0.0..=1.0).contains(&value);
+    if !depth(key_config.actuation_point) {
+        problem(
+            "actuation_point",
+            format!(
+                "Actuation point {} has to lie within 0 to 1",
+                key_config.actuation_point
+            ),
+        );
+    }
+    if !depth(key_config.threshold) || key_config.threshold <= key_config.actuation_point {
+        problem(
+            "threshold",
+            format!(
+                "Threshold {} has to lie above the actuation point {} and up to 1",
+                key_config.threshold, key_config.actuation_point
+            ),
+        );
+    }
+    if !(key_config.velocity_scale.is_finite() && key_config.velocity_scale > 0.0) {
+        problem(
+            "velocity_scale",
+            format!(
+                "Velocity scale {} has to be positive",
+                key_config.velocity_scale
+            ),
+        );
+    }
+    if key_config.velocity_min > key_config.velocity_max || key_config.velocity_max > 127 {
+        problem(
+            "velocity_min",
+            format!(
+                "Velocity window {}..={} is invalid, it has to lie within 0 to 127",
+                key_config.velocity_min, key_config.velocity_max
+            ),
+        );
+    }
+    if key_config
+        .fixed_velocity
+        .is_some_and(|velocity| velocity == 0 || velocity > 127)
+    {
+        problem(
+            "fixed_velocity",
+            "Fixed velocity has to lie within 1 to 127".to_owned(),
+        );
+    }
+    if key_config
+        .fixed_release_velocity
+        .is_some_and(|velocity| velocity > 127)
+    {
+        problem(
+            "fixed_release_velocity",
+            "Fixed release velocity has to lie within 0 to 127".to_owned(),
+        );
+    }
+    if key_config
+        .release_velocity_scale
+        .is_some_and(|scale| !(scale.is_finite() && scale > 0.0))
+    {
+        problem(
+            "release_velocity_scale",
+            "Release velocity scale has to be positive".to_owned(),
+        );
+    }
+    if !(key_config.humanize_velocity.is_finite() && key_config.humanize_velocity >= 0.0) {
+        problem(
+            "humanize_velocity",
+            format!(
+                "Velocity humanization of {} is invalid",
+                key_config.humanize_velocity
+            ),
+        );
+    }
+    if let Some(bpm) = key_config
+        .note_repeat
+        .as_ref()
+        .and_then(|repeat| repeat.bpm)
+    {
+        if !(bpm.is_finite() && bpm > 0.0) {
+            problem(
+                "note_repeat.bpm",
+                format!("Note repeat tempo of {} BPM is invalid", bpm),
+            );
+        }
+    }
+    if let VelocityEstimation::ContactTime {
+        fastest_ms,
+        slowest_ms,
+    } = key_config.velocity_estimation
+    {
+        if !(fastest_ms >= 0.0 && slowest_ms.is_finite() && fastest_ms < slowest_ms) {
+            problem(
+                "velocity_estimation",
+                format!(
+                    "Contact time range {}..{} ms is invalid",
+                    fastest_ms, slowest_ms
+                ),
+            );
+        }
+    }
+    if let Some(rapid) = &key_config.rapid_trigger {
+        let valid = |value: f32| value > 0.0 && value < 1.0;
+        if !(valid(rapid.press_sensitivity) && valid(rapid.release_sensitivity)) {
+            problem(
+                "rapid_trigger",
+                format!(
+                    "Rapid trigger sensitivities {} and {} have to lie between 0 and 1",
+                    rapid.press_sensitivity, rapid.release_sensitivity
+                ),
+            );
+        }
+    }
+    let (min, max) = (key_config.calibration_min, key_config.calibration_max);
+    if !(depth(min) && depth(max) && min < max) {
+        problem(
+            "calibration_min",
+            format!(
+                "Calibration window {}..{} is invalid, it has to lie within 0 to 1",
+                min, max
+            ),
+        );
+    }
+    if key_config
+        .release_threshold
+        .is_some_and(|release_threshold| {
+            release_threshold.is_nan() || release_threshold >= key_config.threshold
+        })
+    {
+        problem(
+            "release_threshold",
+            format!(
+                "Release threshold {:?} has to be below the threshold {}",
+                key_config.release_threshold, key_config.threshold
+            ),
+        );
+    }
+    if let Some(
+        VelocityCurve::Exponential(k) | VelocityCurve::Logarithmic(k) | VelocityCurve::SCurve(k),
+    ) = key_config.velocity_curve
+    {
+        if !(k.is_finite() && k >= 0.0) {
+            problem(
+                "velocity_curve",
+                format!("Velocity curve steepness {} is invalid", k),
+            );
+        }
+    }
+    if let Some(vibrato) = &key_config.vibrato {
+        let valid = |value: f32| value.is_finite() && value >= 0.0;
+        if !(valid(vibrato.sensitivity) && valid(vibrato.depth)) {
+            problem(
+                "vibrato",
+                format!(
+                    "Vibrato sensitivity {} and depth {} must not be negative",
+                    vibrato.sensitivity, vibrato.depth
+                ),
+            );
+        }
+    }
+}
+
+/// Keys that switch the output on and off cannot play anything as well
+fn validate_key_roles(config: &Config, problems: &mut Problems) {
+    for hid_code in &config.toggle_keys {
+        let mapped = [
+            ("key_configs", config.key_configs.contains_key(hid_code)),
+            ("cc_mappings", config.cc_mappings.contains_key(hid_code)),
+            (
+                "pitch_bend_keys",
+                config.pitch_bend_keys.contains_key(hid_code),
+            ),
+            (
+                "program_change_keys",
+                config.program_change_keys.contains_key(hid_code),
+            ),
+        ];
+        let presets = config
+            .presets
+            .iter()
+            .filter(|(_, preset)| preset.key_configs.contains_key(hid_code))
+            .map(|(name, _)| format!("preset.{}.key_configs", name));
+        let fields = mapped
+            .into_iter()
+            .filter(|&(_, mapped)| mapped)
+            .map(|(field, _)| field.to_owned())
+            .chain(presets);
+        for field in fields {
+            problems.key(
+                hid_code,
+                "toggle_keys",
+                format!("Toggle key is also mapped in {}", field),
+            );
+        }
+    }
+}
+
+fn validate_sysex(field: &str, sysex: &Option<Vec<u8>>, problems: &mut Problems) {
+    let Some(sysex) = sysex else {
+        return;
+    };
+    if sysex.len() < 2 || sysex[0] != 0xF0 || sysex[sysex.len() - 1] != 0xF7 {
+        problems.config(field, "Has to start with 0xF0 and end with 0xF7".to_owned());
+    } else if let Some(index) = sysex[1..sysex.len() - 1].iter().position(|&b| b > 0x7F) {
+        problems.config(
+            field,
+            format!(
+                "Contains 0x{:02X} at byte {}, SysEx data bytes must be below 0x80",
+                sysex[index + 1],
+                index + 1
+            ),
+        );
+    }
+}
+
+fn validate_features(config: &Config, problems: &mut Problems) {
+    if let Some(bpm) = config.clock_bpm {
+        if !(bpm.is_finite() && bpm > 0.0) {
+            problems.config(
+                "clock_bpm",
+                format!("Clock tempo of {} BPM is invalid", bpm),
+            );
+        }
+    }
+    for (hid_code, cc) in by_key(&config.cc_mappings) {
+        if cc.high_resolution && cc.controller >= CC_LSB_OFFSET {
+            problems.key(
+                hid_code,
+                "cc_mappings.high_resolution",
+                format!(
+                    "Controller {} has no LSB controller, high resolution needs one from 0 to 31",
+                    cc.controller
+                ),
+            );
+        }
+    }
+    if let Some(InputSmoothing::Exponential(coefficient)) = config.input_smoothing {
+        if !(coefficient > 0.0 && coefficient <= 1.0) {
+            problems.config(
+                "input_smoothing",
+                format!(
+                    "Input smoothing coefficient {} has to lie above 0 and up to 1",
+                    coefficient
+                ),
+            );
+        }
+    }
+    if let Some(arp) = &config.arpeggiator {
+        if arp.bpm.is_some_and(|bpm| !(bpm.is_finite() && bpm > 0.0)) {
+            problems.config(
+                "arpeggiator.bpm",
+                format!("Arpeggiator tempo of {:?} BPM is invalid", arp.bpm),
+            );
+        }
+        if arp.steps_per_beat == 0 || !(arp.gate > 0.0 && arp.gate <= 1.0) {
+            problems.config(
+                "arpeggiator",
+                format!(
+                    "Arpeggiator needs at least one step per beat and a gate within 0 to 1, got \
+                     {} and {}",
+                    arp.steps_per_beat, arp.gate
+                ),
+            );
+        }
+    }
+    if let Some(tuning) = &config.tuning {
+        if tuning.scale.pitches.is_empty() {
+            problems.config(
+                "tuning.scale.pitches",
+                format!("Scale \"{}\" has no pitches", tuning.scale.description),
+            );
+        }
+    }
+    if let Some(scale) = &config.scale {
+        if scale.root > 11 {
+            problems.config(
+                "scale.root",
+                format!(
+                    "Scale root {} is not a pitch class from 0 to 11",
+                    scale.root
+                ),
+            );
+        }
+    }
+    if config
+        .quantize
+        .as_ref()
+        .is_some_and(|quantize| quantize.grid_ticks == 0)
+    {
+        problems.config(
+            "quantize.grid_ticks",
+            "Quantize grid needs to be at least one clock tick".to_owned(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        ArpeggiatorConfig, BendDirection, CcConfig, MonoConfig, NotePool, NoteRepeatConfig,
+        PitchBendConfig, PolyphonyConfig, Preset, ProgramChangeConfig, QuantizeConfig,
+        RapidTriggerConfig, ScaleConfig, SoftHoldConfig, SustainConfig, VibratoConfig,
+    };
+    use crate::tuning::{KeyboardMapping, ScalaScale, Tuning};
+
+    const KEY: HIDCodes = HIDCodes::A;
+
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.key_configs.insert(KEY, KeyConfig::default());
+        config
+    }
+
+    fn with_key(update: impl FnOnce(&mut KeyConfig)) -> Config {
+        let mut config = valid_config();
+        update(config.key_configs.get_mut(&KEY).unwrap());
+        config
+    }
+
+    /// Asserts that validation reports exactly one problem, for the given key and field
+    fn assert_problem(config: &Config, key: Option<HIDCodes>, field: &str) {
+        let errors = config.validate().expect_err("config should be invalid");
+        let found: Vec<_> = errors
+            .iter()
+            .map(|error| (error.key.clone(), error.field.as_str()))
+            .collect();
+        assert_eq!(found, [(key, field)], "{:?}", errors);
+    }
+
+    fn assert_key_problem(update: impl FnOnce(&mut KeyConfig), field: &str) {
+        assert_problem(&with_key(update), Some(KEY), field);
+    }
+
+    #[test]
+    fn defaults_with_a_key_are_valid() {
+        assert_eq!(valid_config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = with_key(|key_config| {
+            key_config.channel = 16;
+            key_config.note_id = 200;
+            key_config.shift_amount = 0;
+        });
+        assert_eq!(config.validate().unwrap_err().len(), 2);
+        assert!(ensure_valid(&config).is_err());
+        assert!(ensure_valid(&valid_config()).is_ok());
+    }
+
+    #[test]
+    fn no_key_plays_a_note() {
+        assert_problem(&Config::default(), None, "key_configs");
+    }
+
+    #[test]
+    fn unknown_default_preset() {
+        let mut config = valid_config();
+        config.default_preset = Some("Missing".to_owned());
+        assert_problem(&config, None, "default_preset");
+    }
+
+    #[test]
+    fn key_channel_out_of_range() {
+        assert_key_problem(|key_config| key_config.channel = 16, "key_configs.channel");
+    }
+
+    #[test]
+    fn preset_fields_are_prefixed() {
+        let mut config = valid_config();
+        let key_config = KeyConfig {
+            channel: 16,
+            ..KeyConfig::default()
+        };
+        let preset = Preset {
+            key_configs: [(KEY, key_config)].into_iter().collect(),
+        };
+        config.presets.insert("Lead".to_owned(), preset);
+        assert_problem(&config, Some(KEY), "preset.Lead.key_configs.channel");
+    }
+
+    #[test]
+    fn note_above_127() {
+        assert_key_problem(
+            |key_config| {
+                key_config.note_id = 200;
+                key_config.shift_amount = 0;
+            },
+            "key_configs.note_id",
+        );
+        assert_key_problem(
+            |key_config| key_config.second_note = Some((200, 0.9)),
+            "key_configs.second_note",
+        );
+        assert_key_problem(
+            |key_config| {
+                key_config.soft_hold = Some(SoftHoldConfig {
+                    max_depth: 0.5,
+                    hold_ms: 100,
+                    note: 200,
+                    velocity: 0.5,
+                })
+            },
+            "key_configs.soft_hold.note",
+        );
+        assert_key_problem(
+            |key_config| {
+                key_config.note_pool = Some(NotePool {
+                    notes: vec![60, 200],
+                    no_repeat: false,
+                    seed: None,
+                })
+            },
+            "key_configs.note_pool.notes",
+        );
+    }
+
+    #[test]
+    fn actuation_point_outside_0_to_1() {
+        assert_key_problem(
+            |key_config| key_config.actuation_point = -0.1,
+            "key_configs.actuation_point",
+        );
+    }
+
+    #[test]
+    fn threshold_at_or_below_actuation_point() {
+        assert_key_problem(
+            |key_config| {
+                key_config.actuation_point = 0.5;
+                key_config.threshold = 0.5;
+            },
+            "key_configs.threshold",
+        );
+        assert_key_problem(
+            |key_config| key_config.threshold = 1.1,
+            "key_configs.threshold",
+        );
+    }
+
+    #[test]
+    fn velocity_scale_not_positive() {
+        assert_key_problem(
+            |key_config| key_config.velocity_scale = 0.0,
+            "key_configs.velocity_scale",
+        );
+        assert_key_problem(
+            |key_config| key_config.velocity_scale = f32::NAN,
+            "key_configs.velocity_scale",
+        );
+    }
+
+    #[test]
+    fn velocity_window_inverted_or_above_127() {
+        assert_key_problem(
+            |key_config| {
+                key_config.velocity_min = 100;
+                key_config.velocity_max = 50;
+            },
+            "key_configs.velocity_min",
+        );
+        assert_key_problem(
+            |key_config| key_config.velocity_max = 128,
+            "key_configs.velocity_min",
+        );
+    }
+
+    #[test]
+    fn fixed_velocity_outside_1_to_127() {
+        assert_key_problem(
+            |key_config| key_config.fixed_velocity = Some(0),
+            "key_configs.fixed_velocity",
+        );
+        assert_key_problem(
+            |key_config| key_config.fixed_velocity = Some(128),
+            "key_configs.fixed_velocity",
+        );
+    }
+
+    #[test]
+    fn fixed_release_velocity_above_127() {
+        assert_key_problem(
+            |key_config| key_config.fixed_release_velocity = Some(128),
+            "key_configs.fixed_release_velocity",
+        );
+    }
+
+    #[test]
+    fn release_velocity_scale_not_positive() {
+        assert_key_problem(
+            |key_config| key_config.release_velocity_scale = Some(-1.0),
+            "key_configs.release_velocity_scale",
+        );
+    }
+
+    #[test]
+    fn negative_humanize_velocity() {
+        assert_key_problem(
+            |key_config| key_config.humanize_velocity = -0.1,
+            "key_configs.humanize_velocity",
+        );
+    }
+
+    #[test]
+    fn note_repeat_tempo_not_positive() {
+        assert_key_problem(
+            |key_config| {
+                key_config.note_repeat = Some(NoteRepeatConfig {
+                    bpm: Some(0.0),
+                    ..NoteRepeatConfig::default()
+                })
+            },
+            "key_configs.note_repeat.bpm",
+        );
+    }
+
+    #[test]
+    fn contact_time_range_inverted() {
+        assert_key_problem(
+            |key_config| {
+                key_config.velocity_estimation = VelocityEstimation::ContactTime {
+                    fastest_ms: 50.0,
+                    slowest_ms: 10.0,
+                }
+            },
+            "key_configs.velocity_estimation",
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_sensitivity_outside_0_to_1() {
+        assert_key_problem(
+            |key_config| {
+                key_config.rapid_trigger = Some(RapidTriggerConfig {
+                    press_sensitivity: 1.0,
+                    ..RapidTriggerConfig::default()
+                })
+            },
+            "key_configs.rapid_trigger",
+        );
+    }
+
+    #[test]
+    fn calibration_window_inverted() {
+        assert_key_problem(
+            |key_config| {
+                key_config.calibration_min = 0.6;
+                key_config.calibration_max = 0.4;
+            },
+            "key_configs.calibration_min",
+        );
+    }
+
+    #[test]
+    fn release_threshold_at_or_above_threshold() {
+        assert_key_problem(
+            |key_config| key_config.release_threshold = Some(0.8),
+            "key_configs.release_threshold",
+        );
+    }
+
+    #[test]
+    fn negative_velocity_curve_steepness() {
+        assert_key_problem(
+            |key_config| key_config.velocity_curve = Some(VelocityCurve::Exponential(-1.0)),
+            "key_configs.velocity_curve",
+        );
+    }
+
+    #[test]
+    fn negative_vibrato() {
+        assert_key_problem(
+            |key_config| {
+                key_config.vibrato = Some(VibratoConfig {
+                    depth: -1.0,
+                    ..VibratoConfig::default()
+                })
+            },
+            "key_configs.vibrato",
+        );
+    }
+
+    #[test]
+    fn mapping_channels_out_of_range() {
+        let mut config = valid_config();
+        config.cc_mappings.insert(
+            HIDCodes::B,
+            CcConfig {
+                controller: 1,
+                channel: 16,
+                high_resolution: false,
+            },
+        );
+        assert_problem(&config, Some(HIDCodes::B), "cc_mappings.channel");
+
+        let mut config = valid_config();
+        config.pitch_bend_keys.insert(
+            HIDCodes::B,
+            PitchBendConfig {
+                channel: 16,
+                direction: BendDirection::Up,
+            },
+        );
+        assert_problem(&config, Some(HIDCodes::B), "pitch_bend_keys.channel");
+
+        let mut config = valid_config();
+        config.program_change_keys.insert(
+            HIDCodes::B,
+            ProgramChangeConfig {
+                program: 0,
+                channel: 16,
+                bank: None,
+                threshold: 0.5,
+            },
+        );
+        assert_problem(&config, Some(HIDCodes::B), "program_change_keys.channel");
+    }
+
+    #[test]
+    fn config_channels_out_of_range() {
+        let mut config = valid_config();
+        config.mono_channels.insert(16, MonoConfig::default());
+        assert_problem(&config, None, "mono_channels");
+
+        let mut config = valid_config();
+        config.polyphony.insert(16, PolyphonyConfig::default());
+        assert_problem(&config, None, "polyphony");
+
+        let mut config = valid_config();
+        config.default_channel = Some(16);
+        assert_problem(&config, None, "default_channel");
+
+        let mut config = valid_config();
+        config.sustain = Some(SustainConfig {
+            keys: vec![HIDCodes::Space],
+            channel: 16,
+            threshold: 0.5,
+            hysteresis: 0.05,
+        });
+        assert_problem(&config, None, "sustain.channel");
+
+        let mut config = valid_config();
+        config.channel_setup.insert(16, ChannelSetup::default());
+        assert_problem(&config, None, "channel_setup");
+    }
+
+    #[test]
+    fn no_voices() {
+        let mut config = valid_config();
+        config.polyphony.insert(
+            0,
+            PolyphonyConfig {
+                max_voices: 0,
+                ..PolyphonyConfig::default()
+            },
+        );
+        assert_problem(&config, None, "polyphony.max_voices");
+    }
+
+    #[test]
+    fn channel_setup_value_above_127() {
+        let mut config = valid_config();
+        config.channel_setup.insert(
+            0,
+            ChannelSetup {
+                cc_defaults: vec![(7, 128)],
+                ..ChannelSetup::default()
+            },
+        );
+        assert_problem(&config, None, "channel_setup");
+    }
+
+    #[test]
+    fn transpose_beyond_limit() {
+        let mut config = valid_config();
+        config.transpose = TRANSPOSE_MAX + 1;
+        config.shift_out_of_range = ShiftOutOfRange::Clamp;
+        assert_problem(&config, None, "transpose");
+    }
+
+    #[test]
+    fn shift_moves_every_note_out_of_range() {
+        let mut config = with_key(|key_config| {
+            key_config.note_id = MIDI_NOTE_MAX;
+            key_config.shift_amount = 1;
+        });
+        assert_problem(&config, None, "key_configs.shift_amount");
+
+        config.shift_out_of_range = ShiftOutOfRange::Fold;
+        assert_eq!(config.validate(), Ok(()));
+
+        // A single note that stays in range is enough
+        config.shift_out_of_range = ShiftOutOfRange::Drop;
+        config.key_configs.insert(HIDCodes::B, KeyConfig::default());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn toggle_key_also_mapped() {
+        let mut config = valid_config();
+        config.toggle_keys.push(KEY);
+        assert_problem(&config, Some(KEY), "toggle_keys");
+
+        let mut config = valid_config();
+        config.toggle_keys.push(HIDCodes::B);
+        config.presets.insert(
+            "Lead".to_owned(),
+            Preset {
+                key_configs: [(HIDCodes::B, KeyConfig::default())].into_iter().collect(),
+            },
+        );
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors[0].key, Some(HIDCodes::B));
+        assert_eq!(errors[0].field, "toggle_keys");
+        assert!(errors[0].message.contains("preset.Lead.key_configs"));
+    }
+
+    #[test]
+    fn sysex_framing_and_data_bytes() {
+        let mut config = valid_config();
+        config.on_enable_sysex = Some(vec![0xF0, 0x7E]);
+        assert_problem(&config, None, "on_enable_sysex");
+
+        let mut config = valid_config();
+        config.on_disable_sysex = Some(vec![0xF0, 0x80, 0xF7]);
+        assert_problem(&config, None, "on_disable_sysex");
+    }
+
+    #[test]
+    fn clock_tempo_not_positive() {
+        let mut config = valid_config();
+        config.clock_bpm = Some(0.0);
+        assert_problem(&config, None, "clock_bpm");
+    }
+
+    #[test]
+    fn high_resolution_cc_without_lsb() {
+        let mut config = valid_config();
+        config.cc_mappings.insert(
+            HIDCodes::B,
+            CcConfig {
+                controller: CC_LSB_OFFSET,
+                channel: 0,
+                high_resolution: true,
+            },
+        );
+        assert_problem(&config, Some(HIDCodes::B), "cc_mappings.high_resolution");
+    }
+
+    #[test]
+    fn smoothing_coefficient_outside_0_to_1() {
+        let mut config = valid_config();
+        config.input_smoothing = Some(InputSmoothing::Exponential(0.0));
+        assert_problem(&config, None, "input_smoothing");
+    }
+
+    #[test]
+    fn arpeggiator_tempo_not_positive() {
+        let mut config = valid_config();
+        config.arpeggiator = Some(ArpeggiatorConfig {
+            bpm: Some(-1.0),
+            ..ArpeggiatorConfig::default()
+        });
+        assert_problem(&config, None, "arpeggiator.bpm");
+    }
+
+    #[test]
+    fn arpeggiator_steps_or_gate_invalid() {
+        let mut config = valid_config();
+        config.arpeggiator = Some(ArpeggiatorConfig {
+            steps_per_beat: 0,
+            ..ArpeggiatorConfig::default()
+        });
+        assert_problem(&config, None, "arpeggiator");
+
+        let mut config = valid_config();
+        config.arpeggiator = Some(ArpeggiatorConfig {
+            gate: 1.5,
+            ..ArpeggiatorConfig::default()
+        });
+        assert_problem(&config, None, "arpeggiator");
+    }
+
+    #[test]
+    fn tuning_without_pitches() {
+        let mut config = valid_config();
+        config.tuning = Some(Tuning {
+            scale: ScalaScale {
+                description: "Empty".to_owned(),
+                pitches: vec![],
+            },
+            mapping: KeyboardMapping::default(),
+        });
+        assert_problem(&config, None, "tuning.scale.pitches");
+    }
+
+    #[test]
+    fn scale_root_above_11() {
+        let mut config = valid_config();
+        config.scale = Some(ScaleConfig {
+            root: 12,
+            ..ScaleConfig::default()
+        });
+        assert_problem(&config, None, "scale.root");
+    }
+
+    #[test]
+    fn quantize_grid_of_zero() {
+        let mut config = valid_config();
+        config.quantize = Some(QuantizeConfig {
+            grid_ticks: 0,
+            ..QuantizeConfig::default()
+        });
+        assert_problem(&config, None, "quantize.grid_ticks");
+    }
+}
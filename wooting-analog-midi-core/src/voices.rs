@@ -0,0 +1,217 @@
+use crate::config::{Config, PolyphonyConfig, StealPolicy};
+use crate::note::NoteSink;
+use crate::{Channel, NoteID};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+
+#[derive(Debug, Clone, Copy)]
+struct SoundingNote {
+    note_id: NoteID,
+    velocity: f32,
+}
+
+/// Notes sounding on each channel with a polyphony limit
+#[derive(Debug, Default)]
+pub(crate) struct VoiceLimiter {
+    // Ordered from oldest to newest
+    sounding: FxHashMap<Channel, Vec<SoundingNote>>,
+}
+
+impl VoiceLimiter {
+    pub(crate) fn clear(&mut self) {
+        self.sounding.clear();
+    }
+}
+
+/// Ends a sounding note before a note on would exceed the polyphony of its channel. The later
+/// note off of a stolen note is dropped, so the receiver never sees it twice.
+pub(crate) struct VoiceLimitSink<'a, S: NoteSink> {
+    inner: &'a mut S,
+    limiter: &'a mut VoiceLimiter,
+    polyphony: &'a FxHashMap<Channel, PolyphonyConfig>,
+}
+
+impl<'a, S: NoteSink> VoiceLimitSink<'a, S> {
+    pub(crate) fn new(inner: &'a mut S, limiter: &'a mut VoiceLimiter, config: &'a Config) -> Self {
+        Self {
+            inner,
+            limiter,
+            polyphony: &config.polyphony,
+        }
+    }
+
+    /// Makes room for a note on a channel with a polyphony limit
+    fn steal(&mut self, note: SoundingNote, channel: Channel) -> Result<()> {
+        let Some(polyphony) = self.polyphony.get(&channel) else {
+            return Ok(());
+        };
+        let sounding = self.limiter.sounding.entry(channel).or_default();
+        if let Some(index) = sounding.iter().position(|s| s.note_id == note.note_id) {
+            sounding.remove(index);
+        }
+        while !sounding.is_empty() && sounding.len() >= polyphony.max_voices as usize {
+            let index = match polyphony.steal {
+                StealPolicy::Oldest => 0,
+                // The first of equally quiet notes is the oldest one
+                StealPolicy::Quietest => sounding
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.velocity.total_cmp(&b.velocity))
+                    .map_or(0, |(index, _)| index),
+            };
+            let stolen = sounding.remove(index);
+            self.inner.note_off(stolen.note_id, 0.0, channel)?;
+        }
+        sounding.push(note);
+        Ok(())
+    }
+
+    /// Whether a note sends messages, stolen notes of limited channels do not
+    fn is_sounding(&self, note_id: NoteID, channel: Channel) -> bool {
+        if !self.polyphony.contains_key(&channel) {
+            return true;
+        }
+        self.limiter
+            .sounding
+            .get(&channel)
+            .is_some_and(|sounding| sounding.iter().any(|s| s.note_id == note_id))
+    }
+}
+
+impl<S: NoteSink> NoteSink for VoiceLimitSink<'_, S> {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.steal(SoundingNote { note_id, velocity }, channel)?;
+        self.inner.note_on(note_id, velocity, channel)
+    }
+
+    fn note_on_high_resolution(
+        &mut self,
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.steal(SoundingNote { note_id, velocity }, channel)?;
+        self.inner
+            .note_on_high_resolution(note_id, velocity, channel)
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        if !self.is_sounding(note_id, channel) {
+            return Ok(());
+        }
+        if let Some(sounding) = self.limiter.sounding.get_mut(&channel) {
+            sounding.retain(|s| s.note_id != note_id);
+        }
+        self.inner.note_off(note_id, velocity, channel)
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        if !self.is_sounding(note_id, channel) {
+            return Ok(());
+        }
+        self.inner.polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8, channel: Channel) -> Result<()> {
+        self.inner.control_change(controller, value, channel)
+    }
+
+    fn program_change(&mut self, program: u8, channel: Channel) -> Result<()> {
+        self.inner.program_change(program, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.inner.channel_pressure(pressure, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.inner.pitch_bend(value, channel)
+    }
+
+    fn system_realtime(&mut self, status: u8) -> Result<()> {
+        self.inner.system_realtime(status)
+    }
+
+    fn sysex(&mut self, message: &[u8]) -> Result<()> {
+        self.inner.sysex(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays notes with velocities on channel 0, negative velocities release the note
+    fn play(steal: StealPolicy, notes: &[(NoteID, f32)]) -> Vec<(u8, u8)> {
+        let mut config = Config::default();
+        let polyphony = PolyphonyConfig {
+            max_voices: 2,
+            steal,
+        };
+        config.polyphony.insert(0, polyphony);
+        let mut limiter = VoiceLimiter::default();
+        let mut output: Vec<Vec<u8>> = Vec::new();
+        let mut sink = VoiceLimitSink::new(&mut output, &mut limiter, &config);
+        for &(note_id, velocity) in notes {
+            if velocity < 0.0 {
+                sink.note_off(note_id, 0.0, 0).unwrap();
+            } else {
+                sink.note_on(note_id, velocity, 0).unwrap();
+            }
+        }
+        output
+            .iter()
+            .map(|message| (message[0], message[1]))
+            .collect()
+    }
+
+    #[test]
+    fn oldest_note_is_stolen() {
+        let notes = [(60, 0.5), (62, 0.2), (64, 1.0)];
+        assert_eq!(
+            play(StealPolicy::Oldest, &notes),
+            [(0x90, 60), (0x90, 62), (0x80, 60), (0x90, 64)]
+        );
+    }
+
+    #[test]
+    fn quietest_note_is_stolen() {
+        let notes = [(60, 0.5), (62, 0.2), (64, 1.0)];
+        assert_eq!(
+            play(StealPolicy::Quietest, &notes),
+            [(0x90, 60), (0x90, 62), (0x80, 62), (0x90, 64)]
+        );
+    }
+
+    #[test]
+    fn stolen_notes_are_not_released_twice() {
+        let notes = [(60, 0.5), (62, 0.5), (64, 0.5), (60, -1.0), (62, -1.0)];
+        assert_eq!(
+            play(StealPolicy::Oldest, &notes),
+            [(0x90, 60), (0x90, 62), (0x80, 60), (0x90, 64), (0x80, 62)]
+        );
+    }
+
+    #[test]
+    fn released_notes_free_their_voice() {
+        let notes = [(60, 0.5), (62, 0.5), (60, -1.0), (64, 0.5)];
+        assert_eq!(
+            play(StealPolicy::Oldest, &notes),
+            [(0x90, 60), (0x90, 62), (0x80, 60), (0x90, 64)]
+        );
+    }
+
+    #[test]
+    fn restruck_notes_keep_one_voice() {
+        let notes = [(60, 0.5), (60, 0.5), (62, 0.5)];
+        assert_eq!(
+            play(StealPolicy::Oldest, &notes),
+            [(0x90, 60), (0x90, 60), (0x90, 62)]
+        );
+    }
+}